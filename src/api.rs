@@ -1,16 +1,25 @@
-use std::net::SocketAddr;
-
+use futures::{Stream, StreamExt};
 use ollama_rs::{
-    generation::chat::{request::ChatMessageRequest, ChatMessage},
+    generation::{
+        chat::{request::ChatMessageRequest, ChatMessage},
+        images::Image,
+        options::GenerationOptions,
+        parameters::{KeepAlive, TimeUnit},
+    },
     Ollama,
 };
+use serde::{Deserialize, Serialize};
 
-pub use ollama_rs::generation::chat::ChatMessageResponse;
+pub use ollama_rs::generation::chat::{ChatMessageFinalResponseData, ChatMessageResponse};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OllamaConfig {
     pub host: String,
     pub port: u16,
+    /// sent as a bearer token, for servers behind an authenticating proxy
+    pub api_key: Option<String>,
+    /// answer with canned models and replies instead of reaching the server
+    pub demo: bool,
 }
 
 pub const DEFAULT_PORT: u16 = 11434;
@@ -20,19 +29,75 @@ impl OllamaConfig {
         Self {
             host: "localhost".to_string(),
             port,
+            api_key: None,
+            demo: false,
         }
     }
 
+    /// Connect to the server, resolving the host name if it is not an IP address
     pub async fn tcp_connect(&self) -> std::io::Result<tokio::net::TcpStream> {
-        let addr: SocketAddr = format!("{}:{}", self.host, self.port).parse().unwrap();
-        tokio::net::TcpStream::connect(addr).await
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        tokio::net::TcpStream::connect((host, self.port))
+            .await
+            .map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("cannot connect to {}:{}: {}", self.host, self.port, e),
+                )
+            })
+    }
+
+    pub fn address(&self) -> String {
+        format!("http://{}:{}", url_host(&self.host), self.port)
     }
 
-    pub fn instance(&self) -> Ollama {
-        Ollama::new(format!("http://{}", self.host), self.port)
+    pub fn instance(&self) -> Backend {
+        if self.demo {
+            return Backend::Demo;
+        }
+        let host = format!("http://{}", url_host(&self.host));
+        let client = self
+            .api_key
+            .as_deref()
+            .and_then(authenticated_client)
+            .unwrap_or_default();
+        Backend::Ollama(
+            Ollama::new_with_client(host, self.port, client.clone()),
+            client,
+        )
+    }
+}
+
+/// The host as written in URLs, with IPv6 addresses between brackets
+pub fn url_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
     }
 }
 
+/// Where the requests are sent
+#[derive(Clone, Debug)]
+pub enum Backend {
+    /// the server, with the client of `Ollama` for the endpoints it doesn't wrap
+    Ollama(Ollama, reqwest::Client),
+    /// fixed models and slowly streamed canned replies, to work on the interface (or take
+    /// screenshots) without a server
+    Demo,
+}
+
+/// A client sending `Authorization: Bearer <api_key>` with every request
+fn authenticated_client(api_key: &str) -> Option<reqwest::Client> {
+    let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", api_key)).ok()?;
+    value.set_sensitive(true);
+    let headers = reqwest::header::HeaderMap::from_iter([(reqwest::header::AUTHORIZATION, value)]);
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .ok()
+}
+
 pub struct ChatMessageResponseStream(pub ollama_rs::generation::chat::ChatMessageResponseStream);
 
 impl std::fmt::Debug for ChatMessageResponseStream {
@@ -47,6 +112,36 @@ impl Clone for ChatMessageResponseStream {
     }
 }
 
+// most responses already received when the stream is polled are handled at once
+const STREAM_BATCH: usize = 64;
+
+impl ChatMessageResponseStream {
+    /// The responses grouped by what is ready at the same time, so that a fast stream (or
+    /// several streams at once) doesn't cost an update of the interface per token. The
+    /// responses preceding a failed read are kept in their own batch
+    pub fn batched(self) -> impl Stream<Item = Result<Vec<ChatMessageResponse>, ()>> {
+        self.0.ready_chunks(STREAM_BATCH).flat_map(|batch| {
+            let mut items = Vec::new();
+            let mut responses = Vec::new();
+            for item in batch {
+                match item {
+                    Ok(response) => responses.push(response),
+                    Err(()) => {
+                        if !responses.is_empty() {
+                            items.push(Ok(std::mem::take(&mut responses)));
+                        }
+                        items.push(Err(()));
+                    }
+                }
+            }
+            if !responses.is_empty() {
+                items.push(Ok(responses));
+            }
+            futures::stream::iter(items)
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LocalModel(ollama_rs::models::LocalModel);
 
@@ -70,24 +165,483 @@ impl LocalModel {
     pub fn name(&self) -> &String {
         &self.0.name
     }
+
+    /// Size on disk, like "4.7 GB"
+    pub fn size(&self) -> String {
+        let size = self.0.size as f64;
+        if size >= 1e9 {
+            format!("{:.1} GB", size / 1e9)
+        } else {
+            format!("{:.0} MB", size / 1e6)
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_size(name: &str, size: u64) -> Self {
+        Self(ollama_rs::models::LocalModel {
+            name: name.to_string(),
+            modified_at: String::new(),
+            size,
+        })
+    }
+
+    /// Day the model was last pulled or created, as reported by the server
+    pub fn modified(&self) -> String {
+        match chrono::DateTime::parse_from_rfc3339(&self.0.modified_at) {
+            Ok(date) => date.format("%Y-%m-%d").to_string(),
+            Err(_) => self.0.modified_at.clone(),
+        }
+    }
+}
+
+pub struct ConnectionFailed(pub String);
+
+/// Sampling parameters of a generation, the defaults of the server being used when unset
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    /// size of the context window, in tokens; the server truncates the conversation beyond it
+    pub num_ctx: Option<u64>,
+    /// sequences ending the reply as soon as the model generates one of them
+    pub stop: Option<Vec<String>>,
+    /// how long the server keeps the model loaded after a reply, as accepted by
+    /// `parse_keep_alive`
+    pub keep_alive: Option<String>,
+}
+
+impl GenerationParams {
+    pub fn is_unset(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Take the parameters unset here from `defaults`
+    pub fn or(self, defaults: GenerationParams) -> Self {
+        Self {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            num_ctx: self.num_ctx.or(defaults.num_ctx),
+            stop: self.stop.or(defaults.stop),
+            keep_alive: self.keep_alive.or(defaults.keep_alive),
+        }
+    }
+
+    fn options(self) -> Option<GenerationOptions> {
+        if self.is_unset() {
+            return None;
+        }
+        let mut options = GenerationOptions::default();
+        if let Some(temperature) = self.temperature {
+            options = options.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            options = options.top_p(top_p);
+        }
+        if let Some(num_ctx) = self.num_ctx {
+            options = options.num_ctx(num_ctx);
+        }
+        if let Some(stop) = self.stop {
+            options = options.stop(stop);
+        }
+        Some(options)
+    }
+}
+
+/// How long the server keeps a model loaded: a number of seconds, or a number followed by
+/// `s`, `m` or `h`. `-1` keeps the model loaded and `0` unloads it after the reply
+pub fn parse_keep_alive(s: &str) -> Option<KeepAlive> {
+    let s = s.trim();
+    if s == "-1" {
+        return Some(KeepAlive::Indefinitely);
+    }
+    let (time, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        None => (s, TimeUnit::Seconds),
+        Some(idx) => {
+            let unit = match &s[idx..] {
+                "s" => TimeUnit::Seconds,
+                "m" => TimeUnit::Minutes,
+                "h" | "hr" => TimeUnit::Hours,
+                _ => return None,
+            };
+            (&s[..idx], unit)
+        }
+    };
+    match time.parse::<u64>().ok()? {
+        0 => Some(KeepAlive::UnloadOnCompletion),
+        time => Some(KeepAlive::Until { time, unit }),
+    }
+}
+
+/// Oldest server with everything used here, `keep_alive` being the last to arrive
+pub const MIN_SERVER_VERSION: &str = "0.1.23";
+
+// major, minor and patch of a version like "0.5.7" or "v0.6.0-rc1"
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let version = version.trim().trim_start_matches('v');
+    let end = version
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(version.len());
+    let mut numbers = version[..end].split('.').map(|n| n.parse::<u32>().ok());
+    let major = numbers.next()??;
+    let minor = numbers.next().unwrap_or(Some(0))?;
+    let patch = numbers.next().unwrap_or(Some(0))?;
+    Some((major, minor, patch))
+}
+
+/// The version is older than `MIN_SERVER_VERSION`. Development builds, reporting 0.0.0, and
+/// versions that cannot be parsed are given the benefit of the doubt
+pub fn is_outdated(version: &str) -> bool {
+    match (parse_version(version), parse_version(MIN_SERVER_VERSION)) {
+        (Some((0, 0, 0)), _) => false,
+        (Some(version), Some(min)) => version < min,
+        _ => false,
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionResponse {
+    version: String,
 }
 
-pub struct ConnectionFailed;
+/// Version of the server, `None` when it cannot tell
+pub async fn get_version(api: &Backend) -> Option<String> {
+    let (api, client) = match api {
+        Backend::Ollama(api, client) => (api, client),
+        Backend::Demo => return Some(demo::VERSION.to_string()),
+    };
+    let response = client
+        .get(format!("{}api/version", api.url_str()))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let body = match response {
+        Ok(response) => response.text().await.ok()?,
+        Err(e) => {
+            tracing::warn!("cannot get the version of the server: {}", e);
+            return None;
+        }
+    };
+    serde_json::from_str::<VersionResponse>(&body)
+        .ok()
+        .map(|v| v.version)
+}
 
-pub async fn get_model_lists(api: &Ollama) -> Result<Vec<LocalModel>, ConnectionFailed> {
+pub async fn get_model_lists(api: &Backend) -> Result<Vec<LocalModel>, ConnectionFailed> {
+    let api = match api {
+        Backend::Ollama(api, _) => api,
+        Backend::Demo => return Ok(demo::models()),
+    };
     api.list_local_models()
         .await
         .map(|v| v.into_iter().map(LocalModel).collect())
-        .map_err(|_| ConnectionFailed)
+        .map_err(|e| ConnectionFailed(e.to_string()))
+}
+
+/// What the user sends in a turn, with the images attached to it (base64 encoded)
+#[derive(Clone, Debug, Default)]
+pub struct Prompt {
+    pub text: String,
+    pub images: Vec<String>,
+}
+
+impl Prompt {
+    fn into_message(self) -> ChatMessage {
+        let message = ChatMessage::user(self.text);
+        if self.images.is_empty() {
+            message
+        } else {
+            message.with_images(self.images.into_iter().map(Image::from_base64).collect())
+        }
+    }
 }
 
-pub async fn chat_stream(api: Ollama, model: String, prompt: String) -> ChatMessageResponseStream {
-    let stream = api
-        .send_chat_messages_stream(ChatMessageRequest::new(
-            model,
-            vec![ChatMessage::user(prompt)],
-        ))
+/// A finished turn of a chat, sent back to the model as context
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub query: Prompt,
+    pub reply: String,
+}
+
+fn conversation(system: Option<String>, history: Vec<Exchange>) -> Vec<ChatMessage> {
+    system
+        .map(ChatMessage::system)
+        .into_iter()
+        .chain(history.into_iter().flat_map(|exchange| {
+            [
+                exchange.query.into_message(),
+                ChatMessage::assistant(exchange.reply),
+            ]
+        }))
+        .collect()
+}
+
+pub async fn chat_stream(
+    api: Backend,
+    model: String,
+    system: Option<String>,
+    history: Vec<Exchange>,
+    prompt: Prompt,
+    params: GenerationParams,
+) -> Result<ChatMessageResponseStream, String> {
+    let mut messages = conversation(system, history);
+    messages.push(prompt.into_message());
+    send_stream(api, model, messages, params).await
+}
+
+async fn send_stream(
+    api: Backend,
+    model: String,
+    messages: Vec<ChatMessage>,
+    mut params: GenerationParams,
+) -> Result<ChatMessageResponseStream, String> {
+    if model.is_empty() {
+        return Err("no model selected for this chat".to_string());
+    }
+    let (api, client) = match api {
+        Backend::Ollama(api, client) => (api, client),
+        Backend::Demo => return Ok(demo::chat_stream(model, &messages)),
+    };
+    let keep_alive = params.keep_alive.take().and_then(|s| parse_keep_alive(&s));
+    let mut request = ChatMessageRequest::new(model.clone(), messages);
+    if let Some(options) = params.options() {
+        request = request.options(options);
+    }
+    let failed = |e: String| format!("cannot chat with model {}: {}", model, e);
+    let response = client
+        .post(format!("{}api/chat", api.url_str()))
+        .body(chat_body(&request, keep_alive.as_ref()).to_string())
+        .send()
         .await
-        .unwrap();
-    ChatMessageResponseStream(stream)
+        .map_err(|e| failed(e.to_string()))?;
+    if !response.status().is_success() {
+        let e = response.text().await.unwrap_or_else(|e| e.to_string());
+        return Err(failed(e));
+    }
+    Ok(ChatMessageResponseStream(Box::pin(json_lines(
+        response.bytes_stream(),
+    ))))
+}
+
+/// The body of a streamed chat request. `ChatMessageRequest` has no keep alive, and the server
+/// only honors the one sent along the request it answers, as finishing a request resets how
+/// long the model stays loaded
+fn chat_body(request: &ChatMessageRequest, keep_alive: Option<&KeepAlive>) -> serde_json::Value {
+    let mut body = serde_json::to_value(request).unwrap_or_default();
+    body["stream"] = true.into();
+    if let Some(keep_alive) = keep_alive {
+        body["keep_alive"] = serde_json::to_value(keep_alive).unwrap_or_default();
+    }
+    body
+}
+
+/// The responses of a stream, one JSON object per line, which the chunks received may split
+fn json_lines<B: AsRef<[u8]>>(
+    chunks: impl Stream<Item = reqwest::Result<B>> + Send + 'static,
+) -> impl Stream<Item = Result<ChatMessageResponse, ()>> + Send + 'static {
+    chunks
+        .scan(Vec::new(), |pending: &mut Vec<u8>, chunk| {
+            let items = match chunk {
+                Ok(bytes) => {
+                    pending.extend_from_slice(bytes.as_ref());
+                    let complete = pending
+                        .iter()
+                        .rposition(|b| *b == b'\n')
+                        .map_or(0, |i| i + 1);
+                    let lines = pending.drain(..complete).collect::<Vec<_>>();
+                    lines
+                        .split(|b| *b == b'\n')
+                        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+                        .map(|line| {
+                            serde_json::from_slice(line).map_err(|e| {
+                                tracing::warn!("cannot read a response: {}", e);
+                            })
+                        })
+                        .collect()
+                }
+                Err(e) => {
+                    tracing::warn!("cannot read the stream: {}", e);
+                    vec![Err(())]
+                }
+            };
+            futures::future::ready(Some(futures::stream::iter(items)))
+        })
+        .flatten()
+}
+
+const CONTINUE_PROMPT: &str =
+    "Continue your previous answer exactly where it stopped, without repeating anything.";
+
+/// Ask the model to carry on a reply that was cut short
+pub async fn chat_continue(
+    api: Backend,
+    model: String,
+    system: Option<String>,
+    history: Vec<Exchange>,
+    prompt: Prompt,
+    partial: String,
+    params: GenerationParams,
+) -> Result<ChatMessageResponseStream, String> {
+    let mut messages = conversation(system, history);
+    messages.extend([
+        prompt.into_message(),
+        ChatMessage::assistant(partial),
+        ChatMessage::user(CONTINUE_PROMPT.to_string()),
+    ]);
+    send_stream(api, model, messages, params).await
+}
+
+/// Send a single prompt and wait for the whole reply
+pub async fn chat_once(api: Backend, model: String, prompt: String) -> Option<String> {
+    let api = match api {
+        Backend::Ollama(api, _) => api,
+        Backend::Demo => return Some(demo::TITLE.to_string()),
+    };
+    let request = ChatMessageRequest::new(model, vec![ChatMessage::user(prompt)]);
+    match api.send_chat_messages(request).await {
+        Ok(response) => Some(response.message.content),
+        Err(e) => {
+            tracing::warn!("chat once failed: {}", e);
+            None
+        }
+    }
+}
+
+/// The canned content of the demo backend
+mod demo {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use ollama_rs::generation::chat::{
+        ChatMessage, ChatMessageFinalResponseData, ChatMessageResponse, MessageRole,
+    };
+
+    use super::{ChatMessageResponseStream, LocalModel};
+
+    pub const TITLE: &str = "Demo chat";
+    pub const VERSION: &str = "0.5.7";
+
+    // pause between two pieces of a reply, slow enough to see it being streamed
+    const STREAM_DELAY: Duration = Duration::from_millis(40);
+
+    const MODELS: &[(&str, u64)] = &[
+        ("llama3.2:latest", 2_019_393_189),
+        ("qwen2.5-coder:7b", 4_683_087_332),
+        ("deepseek-r1:8b", 4_920_753_328),
+    ];
+
+    const REPLIES: &[&str] = &[
+        "Here is a small function computing the factorial of a number:\n\n\
+         ```rust\n\
+         fn factorial(n: u64) -> u64 {\n    (1..=n).product()\n}\n\
+         ```\n\n\
+         It multiplies every number from 1 to `n`, the empty range giving 1 for `factorial(0)`.",
+        "<think>\nThe user wants a comparison, a table reads best.\n</think>\n\n\
+         Both are fine choices, depending on what matters most:\n\n\
+         | | Tabs | Spaces |\n\
+         |---|---|---|\n\
+         | Width | set by the reader | fixed |\n\
+         | Alignment | harder | easy |\n\n\
+         Most style guides pick **spaces**, and the formatter of your language settles it anyway.",
+        "A few things to try:\n\n\
+         1. Restart the server\n\
+         2. Check the logs for errors\n\
+         3. Pull the model again with `ollama pull llama3.2`\n\n\
+         > If nothing helps, the model may be too large for the memory of the machine.",
+    ];
+
+    pub fn models() -> Vec<LocalModel> {
+        MODELS
+            .iter()
+            .map(|(name, size)| {
+                LocalModel(ollama_rs::models::LocalModel {
+                    name: name.to_string(),
+                    modified_at: "2025-01-15T10:30:00+00:00".to_string(),
+                    size: *size,
+                })
+            })
+            .collect()
+    }
+
+    fn response(model: &str, content: &str) -> ChatMessageResponse {
+        ChatMessageResponse {
+            model: model.to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            message: ChatMessage::new(MessageRole::Assistant, content.to_string()),
+            done: false,
+            final_data: None,
+        }
+    }
+
+    /// One of the canned replies, in turn as the chat goes on, streamed a word at a time
+    pub fn chat_stream(model: String, messages: &[ChatMessage]) -> ChatMessageResponseStream {
+        let asked = messages
+            .iter()
+            .filter(|m| matches!(m.role, MessageRole::User))
+            .count();
+        let reply = REPLIES[asked.saturating_sub(1) % REPLIES.len()];
+        let pieces = reply.split_inclusive(' ').collect::<Vec<_>>();
+        let count = pieces.len();
+        let mut last = response(&model, "");
+        last.done = true;
+        last.final_data = Some(ChatMessageFinalResponseData {
+            total_duration: STREAM_DELAY.as_nanos() as u64 * count as u64,
+            prompt_eval_count: 0,
+            prompt_eval_duration: 0,
+            eval_count: count as u16,
+            eval_duration: STREAM_DELAY.as_nanos() as u64 * count as u64,
+        });
+        let stream = futures::stream::iter(pieces)
+            .then(move |piece| {
+                let response = response(&model, piece);
+                async move {
+                    tokio::time::sleep(STREAM_DELAY).await;
+                    Ok(response)
+                }
+            })
+            .chain(futures::stream::once(async move { Ok(last) }));
+        ChatMessageResponseStream(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_sent_with_the_chat() {
+        let request = ChatMessageRequest::new("llama3.2".to_string(), vec![]);
+        let keep_alive = parse_keep_alive("30m");
+        let body = chat_body(&request, keep_alive.as_ref());
+        assert_eq!(body["keep_alive"], "30m");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["model"], "llama3.2");
+        assert_eq!(
+            chat_body(&request, parse_keep_alive("-1").as_ref())["keep_alive"],
+            -1
+        );
+        assert!(chat_body(&request, None).get("keep_alive").is_none());
+    }
+
+    #[test]
+    fn json_lines_across_chunks() {
+        let line = |content: &str| {
+            format!(
+                "{{\"model\":\"m\",\"created_at\":\"now\",\"message\":{{\"role\":\"assistant\",\"content\":\"{}\"}},\"done\":false}}\n",
+                content
+            )
+        };
+        let stream = format!("{}{}", line("a"), line("b"));
+        let (first, second) = stream.split_at(30);
+        let chunks = futures::stream::iter([
+            Ok::<_, reqwest::Error>(first.to_string()),
+            Ok(second.to_string()),
+        ]);
+        let responses = futures::executor::block_on(json_lines(chunks).collect::<Vec<_>>());
+        let contents = responses
+            .into_iter()
+            .map(|response| response.unwrap().message.content)
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["a", "b"]);
+    }
 }
@@ -1,30 +1,134 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    ops::Range,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
+use base64::prelude::{Engine, BASE64_STANDARD};
 use chrono::{DateTime, Local};
 use iced::{
+    advanced::widget::{self as iced_widget, operation},
+    highlighter,
     widget::{
-        column, container, horizontal_rule, horizontal_space, row, scrollable, text, text_editor,
-        Container,
+        button, checkbox, column, container, horizontal_rule, horizontal_space, image, pick_list,
+        rich_text, row, scrollable, span, stack, text, text_editor, text_input, tooltip, Container,
     },
-    Alignment, Element, Length, Padding,
+    Alignment, Element, Length, Padding, Rectangle, Task, Vector,
 };
 use ulid::Ulid;
 
 use crate::{
     api,
-    helper::button_icon,
-    history::{Party, SavedChat},
+    helper::{banner, button_icon, button_icon_small, button_icon_text, icon_to_text, role_icon},
+    history::{strip_reasoning, Party, Query, Reply, ReplyInfo, SavedChat},
+    indicator::Indicator,
+    settings::{ChatTemplate, PinnedPrompt, Settings},
+    stats::estimate_tokens,
     Message,
 };
 
+// part of the context size from which the conversation is shown as close to be truncated
+const CONTEXT_WARNING: f64 = 0.8;
+
 pub struct Chat {
     pub previous: SavedChat<ChatOutput>,
     pub state: ChatState,
+    // index of the queries for which the effective sent prompt is shown
+    show_sent: HashSet<usize>,
+    // when the last generation finished, to fade out the generating border
+    finished_at: Option<Instant>,
+    // model selected in the menubar, offered for the next turn
+    suggested_model: Option<String>,
+    // why the last generation could not start
+    pub error: Option<String>,
+    system_editor: text_editor::Content,
+    show_system: bool,
+    // whether the view sticks to the end of the chat, until the user scrolls up
+    follow: bool,
+    // position of the view once scrolled up, restored when the tab is shown again
+    offset: scrollable::AbsoluteOffset,
+    // images to send with the next prompt
+    attachments: Vec<Attachment>,
+    // why the last image could not be attached
+    attach_error: Option<String>,
+    // estimated tokens of the system prompt and finished turns, counted again when they change
+    context_tokens: usize,
+    // estimated tokens of the prompt being written, counted again when it is edited
+    prompt_tokens: usize,
+    // the last exchange, taken back to edit its query
+    editing: Option<EditedExchange>,
+    // the search within the chat, while its bar is open
+    find: Option<Find>,
+}
+
+// restored as it was if the edit is cancelled
+struct EditedExchange {
+    query: Query,
+    reply: Reply<ChatOutput>,
+    draft: text_editor::Content,
+    attachments: Vec<Attachment>,
+}
+
+struct Find {
+    query: String,
+    // the matches of the whole chat, searched again when the query or the parties change
+    matches: Vec<FindMatch>,
+    // the match shown, among `matches`
+    current: usize,
+}
+
+impl Find {
+    fn current_match(&self) -> Option<&FindMatch> {
+        self.matches.get(self.current)
+    }
+}
+
+// a match of the search: index of the party, chunk of the reply holding it, and byte range in
+// the query or the chunk
+type FindMatch = (usize, Option<usize>, Range<usize>);
+
+// space kept above the match scrolled to
+const FIND_MARGIN: f32 = 40.0;
+
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp"];
+const THUMBNAIL_SIZE: f32 = 64.0;
+
+/// An image attached to the prompt being written
+pub struct Attachment {
+    // base64 encoded, as sent to the model
+    data: String,
+    thumbnail: image::Handle,
+}
+
+impl Attachment {
+    fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            data: BASE64_STANDARD.encode(&bytes),
+            thumbnail: image::Handle::from_bytes(bytes),
+        }
+    }
+
+    fn from_base64(data: String) -> Option<Self> {
+        let bytes = BASE64_STANDARD.decode(&data).ok()?;
+        Some(Self {
+            data,
+            thumbnail: image::Handle::from_bytes(bytes),
+        })
+    }
 }
 
+// distance to the end under which the chat is considered scrolled to the bottom
+const FOLLOW_THRESHOLD: f32 = 30.0;
+
+const GLOW_PERIOD: Duration = Duration::from_millis(1500);
+const GLOW_FADE: Duration = Duration::from_millis(600);
+
 pub enum ChatState {
     Prompting(iced::widget::text_editor::Content),
-    Generating(ChatGenerating),
+    Generating(Box<ChatGenerating>),
 }
 
 impl Default for ChatState {
@@ -34,85 +138,621 @@ impl Default for ChatState {
 }
 
 pub struct ChatGenerating {
-    prompt: String,
+    prompt: Query,
     start: SystemTime,
     output: ChatOutput,
+    // metrics sent by the server with the last chunk of the reply
+    final_data: Option<api::ChatMessageFinalResponseData>,
+    // when continuing a reply: what is known of its previous generation
+    continued: Option<ReplyInfo>,
+    // when regenerating a reply: the reply being replaced, until the new one starts streaming
+    replaced: Option<Reply<ChatOutput>>,
+    // when continuing or regenerating: the prompt being edited, given back once finished
+    draft: Option<text_editor::Content>,
 }
 
 impl ChatGenerating {
-    fn new(prompt: String) -> Self {
+    fn new(prompt: Query) -> Self {
         Self {
             prompt,
             start: SystemTime::now(),
             output: ChatOutput::new(),
+            final_data: None,
+            continued: None,
+            replaced: None,
+            draft: None,
         }
     }
 }
 
 impl Chat {
+    // the chat as saved, with its prompt being written, and everything else as when opened
+    fn with_state(previous: SavedChat<ChatOutput>, state: ChatState) -> Self {
+        let system_editor =
+            text_editor::Content::with_text(previous.system.as_deref().unwrap_or_default());
+        let mut chat = Self {
+            previous,
+            state,
+            show_sent: HashSet::new(),
+            finished_at: None,
+            suggested_model: None,
+            error: None,
+            system_editor,
+            show_system: false,
+            follow: true,
+            offset: scrollable::AbsoluteOffset::default(),
+            attachments: vec![],
+            attach_error: None,
+            context_tokens: 0,
+            prompt_tokens: 0,
+            editing: None,
+            find: None,
+        };
+        chat.count_context_tokens();
+        chat.count_prompt_tokens();
+        chat
+    }
+
     pub fn new(model: api::LocalModel) -> Self {
-        Self {
-            previous: SavedChat {
+        Self::with_state(
+            SavedChat {
                 ulid: Ulid::new(),
                 model: model.name().clone(),
                 content: vec![],
+                template: None,
+                system: None,
+                title: None,
+                template_name: None,
+                params: api::GenerationParams::default(),
+                pinned: false,
+                tags: vec![],
+            },
+            ChatState::default(),
+        )
+    }
+
+    pub fn from_template(model: String, template: &ChatTemplate) -> Self {
+        Self::with_state(
+            SavedChat {
+                ulid: Ulid::new(),
+                model,
+                content: vec![],
+                template: (!template.wrap.trim().is_empty()).then(|| template.wrap.clone()),
+                system: (!template.system.is_empty()).then(|| template.system.clone()),
+                title: None,
+                template_name: Some(template.name.clone()),
+                params: template.params.clone(),
+                pinned: false,
+                tags: vec![],
             },
-            state: ChatState::default(),
+            ChatState::Prompting(text_editor::Content::with_text(&template.prompt)),
+        )
+    }
+
+    pub fn ulid(&self) -> Ulid {
+        self.previous.ulid
+    }
+
+    fn scroll_id(&self) -> scrollable::Id {
+        scrollable::Id::new(format!("chat-{}", self.ulid()))
+    }
+
+    /// Stop following the end of the chat when scrolled up, and follow it again once back at the bottom
+    pub fn scrolled(&mut self, viewport: scrollable::Viewport) -> Task<Message> {
+        if self.follow {
+            // anchored at the bottom, the offset is the distance to the end
+            if viewport.absolute_offset().y > FOLLOW_THRESHOLD {
+                self.follow = false;
+                self.offset = viewport.absolute_offset_reversed();
+                // keep the same position once anchored at the top
+                return scrollable::scroll_to(self.scroll_id(), self.offset);
+            }
+        } else if viewport.absolute_offset_reversed().y < FOLLOW_THRESHOLD {
+            return self.follow_latest();
+        } else {
+            self.offset = viewport.absolute_offset();
         }
+        Task::none()
     }
 
-    pub fn description(&self) -> String {
-        if self.previous.content.is_empty() {
-            String::new()
+    /// Put the view back where it was, as the tabs share the state of their scrollable
+    pub fn restore_scroll(&self) -> Task<Message> {
+        if self.follow {
+            // anchored at the bottom, the start is the end of the chat
+            scrollable::snap_to(self.scroll_id(), scrollable::RelativeOffset::START)
         } else {
-            match &self.previous.content[0] {
-                Party::Query(p) => p.chars().take(40).collect::<String>(),
-                Party::Reply(_) => String::new(),
+            scrollable::scroll_to(self.scroll_id(), self.offset)
+        }
+    }
+
+    /// Scroll to the end of the chat and keep following it
+    pub fn follow_latest(&mut self) -> Task<Message> {
+        self.follow = true;
+        // anchored at the bottom, the start is the end of the chat
+        scrollable::snap_to(self.scroll_id(), scrollable::RelativeOffset::START)
+    }
+
+    fn find_id(&self) -> text_input::Id {
+        text_input::Id::new(format!("find-{}", self.ulid()))
+    }
+
+    /// Open the search bar, or focus it if already open
+    pub fn open_find(&mut self) -> Task<Message> {
+        if self.find.is_none() {
+            self.find = Some(Find {
+                query: String::new(),
+                matches: vec![],
+                current: 0,
+            });
+        }
+        text_input::focus(self.find_id())
+    }
+
+    pub fn close_find(&mut self) {
+        self.find = None;
+    }
+
+    /// Matches of `query` in the queries and in the chunks of the replies, in reading order.
+    /// The reasoning, collapsed once the reply starts, is not searched
+    fn find_matches(&self, query: &str) -> Vec<FindMatch> {
+        self.previous
+            .content
+            .iter()
+            .enumerate()
+            .flat_map(|(i, party)| match party {
+                Party::Query(q) => find_matches(&q.text, query)
+                    .into_iter()
+                    .map(|range| (i, None, range))
+                    .collect::<Vec<_>>(),
+                Party::Reply(reply) => reply
+                    .content
+                    .output
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(c, chunk)| {
+                        find_matches(&chunk.raw_content, query)
+                            .into_iter()
+                            .map(move |range| (i, Some(c), range))
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    // the parties changed, so do the matches of an open search
+    fn find_again(&mut self) {
+        if let Some(query) = self.find.as_ref().map(|find| find.query.clone()) {
+            let matches = self.find_matches(&query);
+            if let Some(find) = &mut self.find {
+                find.current = find.current.min(matches.len().saturating_sub(1));
+                find.matches = matches;
             }
         }
     }
 
-    pub fn ulid(&self) -> Ulid {
-        self.previous.ulid
+    /// Search for `query`, showing its first match
+    pub fn find(&mut self, query: String) -> Task<Message> {
+        let matches = self.find_matches(&query);
+        if let Some(find) = &mut self.find {
+            find.query = query;
+            find.matches = matches;
+            find.current = 0;
+        }
+        self.scroll_to_match()
+    }
+
+    /// Show the next (or previous) match, wrapping around at the ends
+    pub fn find_step(&mut self, forward: bool) -> Task<Message> {
+        let Some(find) = self.find.as_mut().filter(|find| !find.matches.is_empty()) else {
+            return Task::none();
+        };
+        let count = find.matches.len();
+        find.current = if forward {
+            (find.current + 1) % count
+        } else {
+            (find.current + count - 1) % count
+        };
+        self.scroll_to_match()
+    }
+
+    // the container of the current match, the query or the chunk of the reply holding it
+    fn match_id(&self) -> container::Id {
+        container::Id::new(format!("match-{}", self.ulid()))
+    }
+
+    // scrolls to the container of the current match, once laid out
+    fn scroll_to_match(&mut self) -> Task<Message> {
+        if self.find.as_ref().and_then(Find::current_match).is_none() {
+            return Task::none();
+        }
+        self.follow = false;
+        let scroll_id = self.scroll_id();
+        iced_widget::operate(MatchOffset {
+            scrollable: scroll_id.clone().into(),
+            target: self.match_id().into(),
+            content: None,
+            offset: None,
+        })
+        .then(move |offset| {
+            scrollable::scroll_to(
+                scroll_id.clone(),
+                scrollable::AbsoluteOffset {
+                    x: 0.0,
+                    y: (offset - FIND_MARGIN).max(0.0),
+                },
+            )
+        })
     }
 
     pub fn model(&self) -> String {
         self.previous.model.clone()
     }
 
+    pub fn clear_attach_error(&mut self) {
+        self.attach_error = None;
+    }
+
+    pub fn attach(&mut self, image: Result<Vec<u8>, String>) {
+        match image {
+            Ok(bytes) => {
+                self.attachments.push(Attachment::new(bytes));
+                self.attach_error = None;
+            }
+            Err(e) => self.attach_error = Some(e),
+        }
+    }
+
+    pub fn remove_attachment(&mut self, index: usize) {
+        if index < self.attachments.len() {
+            self.attachments.remove(index);
+        }
+    }
+
+    pub fn set_model(&mut self, model: String) {
+        self.previous.model = model;
+        self.suggested_model = None;
+    }
+
+    pub fn suggest_model(&mut self, model: String) {
+        self.suggested_model = (model != self.previous.model).then_some(model);
+    }
+
+    /// Switch to the suggested model if `accept`, and forget the suggestion either way
+    pub fn resolve_suggested_model(&mut self, accept: bool) {
+        if let Some(model) = self.suggested_model.take() {
+            if accept {
+                self.previous.model = model;
+            }
+        }
+    }
+
+    /// Estimate again the tokens of the system prompt and the conversation so far
+    fn count_context_tokens(&mut self) {
+        let system = self.previous.system.as_deref().map_or(0, estimate_tokens);
+        let content = self
+            .previous
+            .content
+            .iter()
+            .map(|party| match party {
+                Party::Query(query) => estimate_tokens(query.sent()),
+                Party::Reply(reply) => estimate_tokens(&strip_reasoning(&reply.content.raw())),
+            })
+            .sum::<usize>();
+        self.context_tokens = system + content;
+    }
+
+    /// Estimate again the tokens of the prompt being written
+    fn count_prompt_tokens(&mut self) {
+        if let ChatState::Prompting(content) = &self.state {
+            self.prompt_tokens = estimate_tokens(&content.text());
+        }
+    }
+
+    /// The finished turns, as context for the next one (reasoning blocks are not sent back)
+    pub fn history(&self) -> Vec<api::Exchange> {
+        self.previous
+            .content
+            .chunks(2)
+            .filter_map(|pair| match pair {
+                [Party::Query(query), Party::Reply(reply)] => Some(api::Exchange {
+                    query: query.prompt(),
+                    reply: strip_reasoning(&reply.content.raw()),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn system(&self) -> Option<String> {
+        self.previous.system.clone()
+    }
+
+    /// The parameters of the chat, the unset ones taken from `defaults`
+    pub fn generation_params(&self, defaults: api::GenerationParams) -> api::GenerationParams {
+        self.previous.params.clone().or(defaults)
+    }
+
+    pub fn template_name(&self) -> Option<&str> {
+        self.previous.template_name.as_deref()
+    }
+
+    /// Set the system prompt, the prompt wrapping and the parameters of `template` again,
+    /// as when the chat was created from it
+    pub fn apply_template(&mut self, template: &ChatTemplate) {
+        self.previous.system = (!template.system.is_empty()).then(|| template.system.clone());
+        self.system_editor = text_editor::Content::with_text(&template.system);
+        self.previous.template = (!template.wrap.trim().is_empty()).then(|| template.wrap.clone());
+        self.previous.params = template.params.clone();
+        self.previous.template_name = Some(template.name.clone());
+        self.count_context_tokens();
+    }
+
+    pub fn toggle_show_system(&mut self) {
+        self.show_system = !self.show_system;
+    }
+
+    pub fn edit_system(&mut self, action: text_editor::Action) {
+        let is_edit = action.is_edit();
+        self.system_editor.perform(action);
+        if is_edit {
+            let system = self.system_editor.text();
+            let system = system.trim();
+            self.previous.system = (!system.is_empty()).then(|| system.to_string());
+            self.count_context_tokens();
+        }
+    }
+
     pub fn from_saved(chat: SavedChat<String>) -> Self {
-        let previous = chat.to_chat_output();
-        Self {
-            previous,
-            state: ChatState::default(),
+        Self::with_state(chat.into_chat_output(), ChatState::default())
+    }
+
+    /// The first exchange of a chat that has no title yet
+    pub fn untitled_exchange(&self) -> Option<(String, String)> {
+        match (
+            self.previous.title.as_ref(),
+            self.previous.content.as_slice(),
+        ) {
+            (None, [Party::Query(query), Party::Reply(reply)]) => {
+                Some((query.text.clone(), reply.content.raw()))
+            }
+            _ => None,
         }
     }
 
+    pub fn has_title(&self) -> bool {
+        self.previous.title.is_some()
+    }
+
+    pub fn set_title(&mut self, title: Option<String>) {
+        self.previous.title = title;
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.previous.pinned = pinned;
+    }
+
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.previous.tags = tags;
+    }
+
     pub fn to_saved(&self) -> SavedChat<String> {
-        self.previous.clone().flatten_output()
+        let mut saved = self.previous.clone();
+        // the exchange being edited is only replaced once the new query is sent
+        if let Some(editing) = &self.editing {
+            saved.content.extend([
+                Party::Query(editing.query.clone()),
+                Party::Reply(editing.reply.clone()),
+            ]);
+        }
+        saved.flatten_output()
     }
 
     pub fn name(&self) -> String {
+        let description = self.previous.description();
+        if !description.is_empty() {
+            return description;
+        }
         let time = self.previous.ulid.datetime();
-        let date: DateTime<Local> = time.clone().into();
+        let date: DateTime<Local> = time.into();
 
         format!("Chat {}", date.format("%Y-%m-%d %H:%M:%S"))
     }
 
-    pub fn set_generating(&mut self) -> String {
+    /// Apply the chat template (if any) to the user input
+    fn wrap_prompt(&self, prompt: &str) -> String {
+        match &self.previous.template {
+            None => prompt.to_string(),
+            Some(template) if template.contains("{prompt}") => template.replace("{prompt}", prompt),
+            Some(template) => format!("{}\n\n{}", template, prompt),
+        }
+    }
+
+    /// The text selected in the prompt editor
+    pub fn prompt_selection(&self) -> Option<String> {
+        match &self.state {
+            ChatState::Prompting(content) => content.selection(),
+            ChatState::Generating(_) => None,
+        }
+    }
+
+    /// The prompt being written, if any
+    pub fn draft(&self) -> Option<String> {
+        let draft = match &self.state {
+            ChatState::Prompting(content) => content,
+            ChatState::Generating(generating) => generating.draft.as_ref()?,
+        };
+        let text = draft.text();
+        (!text.trim().is_empty()).then_some(text)
+    }
+
+    pub fn set_draft(&mut self, draft: &str) {
+        if let ChatState::Prompting(content) = &mut self.state {
+            *content = text_editor::Content::with_text(draft);
+        }
+        self.count_prompt_tokens();
+    }
+
+    pub fn edit_prompt(&mut self, action: text_editor::Action) {
+        let is_edit = action.is_edit();
+        if let ChatState::Prompting(content) = &mut self.state {
+            content.perform(action);
+        }
+        if is_edit {
+            self.count_prompt_tokens();
+        }
+    }
+
+    pub fn set_generating(&mut self) -> api::Prompt {
+        self.error = None;
         match &mut self.state {
             ChatState::Prompting(prompt) => {
                 let prompt = prompt.text();
-                self.state = ChatState::Generating(ChatGenerating::new(prompt.clone()));
+                let sent = self.wrap_prompt(&prompt);
+                let images = self
+                    .attachments
+                    .drain(..)
+                    .map(|attachment| attachment.data)
+                    .collect();
+                self.attach_error = None;
+                let query = Query::new(prompt, sent, images);
+                let prompt = query.prompt();
+                self.state = ChatState::Generating(Box::new(ChatGenerating::new(query)));
                 prompt
             }
             ChatState::Generating(_) => {
                 tracing::error!("set generating in already generating mode");
-                String::new()
+                api::Prompt::default()
             }
         }
     }
 
+    /// The prompt being written as it would be sent, without sending it
+    pub fn pending_query(&self) -> Option<Query> {
+        let ChatState::Prompting(content) = &self.state else {
+            return None;
+        };
+        let prompt = content.text();
+        if prompt.trim().is_empty() {
+            return None;
+        }
+        let sent = self.wrap_prompt(&prompt);
+        let images = self.attachments.iter().map(|a| a.data.clone()).collect();
+        Some(Query::new(prompt, sent, images))
+    }
+
+    /// The chat without the exchange being edited, as it would be continued
+    pub fn saved_before_prompt(&self) -> SavedChat<String> {
+        self.previous.clone().flatten_output()
+    }
+
+    /// Remove the last query and its reply, along with the prompt being edited
+    fn take_last_exchange(&mut self) -> Option<(Query, Reply<ChatOutput>, text_editor::Content)> {
+        if !matches!(self.state, ChatState::Prompting(_)) {
+            tracing::error!("cannot take the last exchange in generating mode");
+            return None;
+        }
+        if self.editing.is_some() {
+            return None;
+        }
+        let (Some(Party::Query(_)), Some(Party::Reply(_))) = (
+            self.previous.content.iter().rev().nth(1),
+            self.previous.content.last(),
+        ) else {
+            return None;
+        };
+        let Some(Party::Reply(reply)) = self.previous.content.pop() else {
+            unreachable!()
+        };
+        let Some(Party::Query(prompt)) = self.previous.content.pop() else {
+            unreachable!()
+        };
+        let ChatState::Prompting(draft) = std::mem::take(&mut self.state) else {
+            unreachable!()
+        };
+        self.error = None;
+        self.find_again();
+        Some((prompt, reply, draft))
+    }
+
+    /// Put the last query back in the editor, to send it again once changed
+    pub fn edit_last_query(&mut self) {
+        if self.editing.is_some() {
+            return;
+        }
+        let Some((query, reply, draft)) = self.take_last_exchange() else {
+            return;
+        };
+        let attachments = std::mem::replace(
+            &mut self.attachments,
+            query
+                .images
+                .iter()
+                .cloned()
+                .filter_map(Attachment::from_base64)
+                .collect(),
+        );
+        self.state = ChatState::Prompting(text_editor::Content::with_text(&query.text));
+        self.editing = Some(EditedExchange {
+            query,
+            reply,
+            draft,
+            attachments,
+        });
+        self.count_context_tokens();
+        self.count_prompt_tokens();
+    }
+
+    /// Give back the last exchange as it was before editing its query
+    pub fn cancel_edit(&mut self) {
+        let Some(editing) = self.editing.take() else {
+            return;
+        };
+        if !matches!(self.state, ChatState::Prompting(_)) {
+            return;
+        }
+        self.previous
+            .content
+            .extend([Party::Query(editing.query), Party::Reply(editing.reply)]);
+        self.state = ChatState::Prompting(editing.draft);
+        self.attachments = editing.attachments;
+        self.count_context_tokens();
+        self.count_prompt_tokens();
+        self.find_again();
+    }
+
+    /// Resume generating the last reply, returning the query it answers and its content so far
+    pub fn set_continuing(&mut self) -> Option<(api::Prompt, String)> {
+        let (prompt, reply, draft) = self.take_last_exchange()?;
+        let sent = prompt.prompt();
+        let partial = reply.content.raw();
+        self.state = ChatState::Generating(Box::new(ChatGenerating {
+            output: reply.content,
+            continued: Some(reply.info),
+            draft: Some(draft),
+            ..ChatGenerating::new(prompt)
+        }));
+        Some((sent, partial))
+    }
+
+    /// Generate the last reply again, returning the query it answers
+    pub fn set_regenerating(&mut self) -> Option<api::Prompt> {
+        let (prompt, reply, draft) = self.take_last_exchange()?;
+        let sent = prompt.prompt();
+        self.state = ChatState::Generating(Box::new(ChatGenerating {
+            replaced: Some(reply),
+            draft: Some(draft),
+            ..ChatGenerating::new(prompt)
+        }));
+        Some(sent)
+    }
+
+    /// Forget the reply being regenerated, now that the new one is streaming
+    pub fn discard_replaced(&mut self) {
+        if let ChatState::Generating(generating) = &mut self.state {
+            generating.replaced = None;
+        }
+    }
+
     pub fn set_finish(&mut self) {
         let mut prev_state = ChatState::default();
         std::mem::swap(&mut prev_state, &mut self.state);
@@ -123,100 +763,598 @@ impl Chat {
                 self.state = ChatState::Prompting(content);
             }
             ChatState::Generating(generating) => {
+                // the edited exchange is replaced by the new one
+                self.editing = None;
                 self.previous.content.push(Party::Query(generating.prompt));
-                self.previous.content.push(Party::Reply(generating.output));
+                let duration = SystemTime::now()
+                    .duration_since(generating.start)
+                    .unwrap_or(Duration::ZERO);
+                if let Some(draft) = generating.draft {
+                    self.state = ChatState::Prompting(draft);
+                }
+                let mut output = generating.output;
+                output.finalize();
+                let mut info = generating.continued.unwrap_or_default();
+                info.model = Some(self.previous.model.clone());
+                let add = |a: Option<u64>, b: u64| Some(a.unwrap_or(0) + b);
+                info.duration_ms = add(info.duration_ms, duration.as_millis() as u64);
+                if let Some(data) = generating.final_data {
+                    info.prompt_tokens = Some(data.prompt_eval_count as u64);
+                    info.tokens = add(info.tokens, data.eval_count as u64);
+                    info.eval_ms = add(info.eval_ms, data.eval_duration / 1_000_000);
+                }
+                self.previous
+                    .content
+                    .push(Party::Reply(Reply::new(output, info)));
+                self.finished_at = Some(Instant::now());
+                self.count_context_tokens();
+                self.count_prompt_tokens();
+                self.find_again();
             }
         }
     }
 
-    pub fn view(&self) -> Container<Message> {
-        let previous_chunks = self.previous.content.iter().map(|p| match p {
-            Party::Query(q) => Self::view_prompt(q).into(),
-            Party::Reply(o) => Self::view_output(o).into(),
-        });
+    /// Go back to prompting after a generation that could not start, keeping the prompt
+    pub fn set_failed(&mut self, error: String) {
+        if let ChatState::Generating(_) = self.state {
+            let ChatState::Generating(generating) = std::mem::take(&mut self.state) else {
+                unreachable!()
+            };
+            let generating = *generating;
+            let reply = match (generating.continued, generating.replaced) {
+                (Some(info), _) => Some(Reply::new(generating.output, info)),
+                (None, replaced) => replaced,
+            };
+            match (reply, generating.draft) {
+                (Some(reply), Some(draft)) => {
+                    self.previous.content.push(Party::Query(generating.prompt));
+                    self.previous.content.push(Party::Reply(reply));
+                    self.state = ChatState::Prompting(draft);
+                }
+                _ => {
+                    self.state = ChatState::Prompting(text_editor::Content::with_text(
+                        &generating.prompt.text,
+                    ));
+                    self.attachments = generating
+                        .prompt
+                        .images
+                        .into_iter()
+                        .filter_map(Attachment::from_base64)
+                        .collect();
+                }
+            }
+            self.count_context_tokens();
+            self.count_prompt_tokens();
+            self.find_again();
+        }
+        self.error = Some(error);
+    }
+
+    /// Intensity of the generating border, pulsing while generating and fading out after
+    pub fn glow(&self) -> f32 {
+        match &self.state {
+            ChatState::Generating(generating) => {
+                let elapsed = SystemTime::now()
+                    .duration_since(generating.start)
+                    .unwrap_or(Duration::ZERO);
+                let phase = elapsed.as_secs_f32() / GLOW_PERIOD.as_secs_f32();
+                0.65 + 0.35 * (phase * std::f32::consts::TAU).cos()
+            }
+            ChatState::Prompting(_) => match self.finished_at {
+                Some(finished) if finished.elapsed() < GLOW_FADE => {
+                    1.0 - finished.elapsed().as_secs_f32() / GLOW_FADE.as_secs_f32()
+                }
+                _ => 0.0,
+            },
+        }
+    }
+
+    pub fn is_generating(&self) -> bool {
+        matches!(self.state, ChatState::Generating(_))
+    }
+
+    pub fn is_animating(&self) -> bool {
+        self.glow() > 0.0
+    }
+
+    pub fn toggle_show_sent(&mut self, index: usize) {
+        if !self.show_sent.remove(&index) {
+            self.show_sent.insert(index);
+        }
+    }
+
+    pub fn view<'a>(
+        &'a self,
+        settings: &'a Settings,
+        models: &'a [api::LocalModel],
+    ) -> Container<'a, Message> {
+        let glow = self.glow();
+        let matches = self.find.as_ref().map_or(&[][..], |find| &find.matches);
+        let current = self.find.as_ref().and_then(Find::current_match);
+        // the matches within a query, flagged when it is the current one
+        let found = |index: usize| {
+            matches
+                .iter()
+                .filter(|(i, _, _)| *i == index)
+                .map(|m| (m.2.clone(), Some(m) == current))
+                .collect::<Vec<_>>()
+        };
+        // the chunks of a reply holding matches, the one of the current match with its id
+        let found_chunks = |index: usize| {
+            matches
+                .iter()
+                .filter(|(i, _, _)| *i == index)
+                .filter_map(|m| Some((m.1?, (Some(m) == current).then(|| self.match_id()))))
+                .collect::<Vec<_>>()
+        };
+        let previous_chunks = self
+            .previous
+            .content
+            .iter()
+            .enumerate()
+            .map(|(i, p)| match p {
+                Party::Query(q) => {
+                    let prompt = self.view_prompt(i, q, &found(i));
+                    if current.is_some_and(|m| m.0 == i) {
+                        prompt.id(self.match_id()).into()
+                    } else {
+                        prompt.into()
+                    }
+                }
+                Party::Reply(o) => {
+                    let last = i + 1 == self.previous.content.len();
+                    let actions = (last
+                        && matches!(self.state, ChatState::Prompting(_))
+                        && self.editing.is_none())
+                    .then(|| {
+                        row![]
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::ArrowBarRight, "Continue")
+                                    .style(button::secondary)
+                                    .on_press(Message::ChatContinue(self.ulid())),
+                            )
+                            .push(
+                                button_icon_text(
+                                    iced_fonts::Bootstrap::ArrowClockwise,
+                                    "Regenerate",
+                                )
+                                .style(button::secondary)
+                                .on_press(Message::ChatRegenerate(self.ulid())),
+                            )
+                            .spacing(5.0)
+                    });
+                    let output = self.view_output(
+                        i,
+                        &o.content,
+                        o.info.model.as_deref(),
+                        found_chunks(i),
+                        settings,
+                    );
+                    column![]
+                        .push(output)
+                        .push_maybe(Self::view_reply_info(&o.info))
+                        .push_maybe(actions)
+                        .spacing(5.0)
+                        .into()
+                }
+            });
 
         let chunks: Box<dyn Iterator<Item = Element<'_, Message>> + '_> = match &self.state {
             ChatState::Prompting(content) => Box::new(
-                previous_chunks.chain(std::iter::once(Self::view_prompt_editor(&content).into())),
+                previous_chunks.chain(std::iter::once(
+                    self.view_prompt_editor(
+                        content,
+                        &settings.pinned_prompts,
+                        self.generation_params(settings.generation_params(&self.previous.model))
+                            .num_ctx,
+                    )
+                    .into(),
+                )),
             ),
             ChatState::Generating(chat_generating) => Box::new(
                 previous_chunks
                     .chain(std::iter::once(
-                        Self::view_prompt(&chat_generating.prompt).into(),
+                        self.view_prompt(self.previous.content.len(), &chat_generating.prompt, &[])
+                            .into(),
                     ))
-                    .chain(std::iter::once(
-                        Self::view_output(&chat_generating.output).into(),
-                    )),
+                    .chain(std::iter::once(if chat_generating.output.is_empty() {
+                        Self::view_pending()
+                    } else {
+                        self.view_output(
+                            self.previous.content.len() + 1,
+                            &chat_generating.output,
+                            Some(&self.previous.model),
+                            vec![],
+                            settings,
+                        )
+                    })),
             ),
         };
         let mut menu = row![]
             .spacing(5.0)
             .align_y(Alignment::Center)
-            .push(text(format!("using {}", self.model())));
+            .push(self.view_model(models))
+            .push_maybe(self.previous.template_name.as_ref().map(|name| {
+                row![]
+                    .push(text(format!("from template {}", name)).style(text::secondary))
+                    .push(tooltip(
+                        button_icon_small(iced_fonts::Bootstrap::ArrowCounterclockwise)
+                            .style(button::text)
+                            .on_press(Message::ChatTemplateReapply(self.ulid())),
+                        container(text("Re-apply the template").size(12.0))
+                            .padding(5)
+                            .style(container::rounded_box),
+                        tooltip::Position::Bottom,
+                    ))
+                    .align_y(Alignment::Center)
+            }))
+            .push(
+                button_icon(iced_fonts::Bootstrap::Search)
+                    .style(button::text)
+                    .on_press(Message::ChatFindOpen(self.ulid())),
+            );
 
         match &self.state {
-            ChatState::Prompting(_) => {}
+            ChatState::Prompting(_) => {
+                menu = menu.push(horizontal_space());
+                if let Some(model) = &self.suggested_model {
+                    menu = menu
+                        .push(text(format!("use {} for the next turn?", model)))
+                        .push(
+                            button(text("Switch"))
+                                .on_press(Message::ChatSuggestedModel(self.ulid(), true)),
+                        )
+                        .push(
+                            button(text("Keep"))
+                                .style(button::secondary)
+                                .on_press(Message::ChatSuggestedModel(self.ulid(), false)),
+                        );
+                }
+                menu = menu.push(
+                    button_icon_text(iced_fonts::Bootstrap::LayoutSplit, "Compare")
+                        .style(button::secondary)
+                        .on_press_maybe(
+                            self.pending_query()
+                                .map(|_| Message::CompareOpen(self.ulid())),
+                        ),
+                );
+                if !self.previous.content.is_empty() {
+                    menu = menu.push(
+                        button_icon_text(iced_fonts::Bootstrap::Download, "Export")
+                            .style(button::secondary)
+                            .on_press(Message::ExportChat(self.ulid())),
+                    );
+                }
+            }
             ChatState::Generating(generating) => {
                 let current = SystemTime::now();
                 let s = current
                     .duration_since(generating.start)
                     .unwrap_or(std::time::Duration::ZERO);
                 menu = menu.push(horizontal_space());
-                menu = menu.push(text(format!("generating {} seconds", s.as_secs())));
+                // redrawn on every frame, as the border of the chat glows while generating
+                menu = menu.push(text(format!("generating for {:.1}s", s.as_secs_f32())));
                 menu = menu.push(iced_aw::Spinner::new());
+                menu = menu.push(
+                    button_icon_text(iced_fonts::Bootstrap::StopFill, "Stop")
+                        .style(button::danger)
+                        .on_press(Message::ChatStop(self.ulid())),
+                );
             }
         };
         container(
             column![]
                 .push(
                     container(menu)
-                        .style(|theme| container::bordered_box(theme))
+                        .style(container::bordered_box)
                         .width(Length::Fill)
                         .padding(5.0), //.height(30.0),
                 )
-                .push(horizontal_rule(1.0))
-                .push(
-                    scrollable(
-                        container(column(chunks).spacing(15.0))
-                            .padding(Padding::default().left(10.0).right(20.0)),
-                    )
-                    .anchor_bottom(),
+                .push_maybe(
+                    self.find
+                        .as_ref()
+                        .map(|find| self.view_find(find, matches.len(), current.is_some())),
                 )
+                .push(self.view_system())
+                .push_maybe(
+                    self.error
+                        .as_ref()
+                        .map(|e| banner(e, Message::ChatErrorDismiss(self.ulid()))),
+                )
+                .push(horizontal_rule(1.0))
+                .push(self.view_scrollable(chunks))
                 .spacing(15.0),
         )
         .padding(Padding::from(5.0))
+        .style(move |theme: &iced::Theme| {
+            let mut color = theme.extended_palette().primary.base.color;
+            color.a = glow;
+            container::Style {
+                border: iced::border::rounded(5).color(color).width(2.0),
+                ..container::Style::default()
+            }
+        })
+    }
+
+    fn view_model<'a>(&self, models: &'a [api::LocalModel]) -> Element<'a, Message> {
+        if self.is_generating() || models.is_empty() {
+            return text(format!("using {}", self.model())).into();
+        }
+        let ulid = self.ulid();
+        let selected = models
+            .iter()
+            .find(|m| *m.name() == self.previous.model)
+            .cloned();
+        row![]
+            .push(text("using"))
+            .push(
+                pick_list(models, selected, move |model| {
+                    Message::ChatModelSelected(ulid, model)
+                })
+                // the model of the chat may not be installed anymore
+                .placeholder(self.model())
+                .text_size(14.0)
+                .padding(3.0),
+            )
+            .spacing(5.0)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_scrollable<'a>(
+        &'a self,
+        chunks: impl IntoIterator<Item = Element<'a, Message>>,
+    ) -> Element<'a, Message> {
+        let ulid = self.ulid();
+        let scroll = scrollable(
+            container(column(chunks).spacing(15.0))
+                .padding(Padding::default().left(10.0).right(20.0)),
+        )
+        .id(self.scroll_id())
+        .on_scroll(move |viewport| Message::ChatScrolled(ulid, viewport));
+        if self.follow {
+            return scroll.anchor_bottom().into();
+        }
+        stack![
+            scroll,
+            container(
+                button_icon_text(iced_fonts::Bootstrap::ArrowDown, "Jump to latest")
+                    .on_press(Message::ChatFollow(ulid))
+                    .style(button::secondary),
+            )
+            .align_x(Alignment::Center)
+            .align_y(iced::alignment::Vertical::Bottom)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(10.0)
+        ]
+        .into()
+    }
+
+    fn view_find<'a>(&self, find: &'a Find, count: usize, found: bool) -> Element<'a, Message> {
+        let ulid = self.ulid();
+        let status = if find.query.is_empty() {
+            String::new()
+        } else if found {
+            format!("{} / {}", find.current.min(count - 1) + 1, count)
+        } else {
+            "no match".to_string()
+        };
+        row![]
+            .push(
+                text_input("Find in chat", &find.query)
+                    .id(self.find_id())
+                    .on_input(move |query| Message::ChatFind(ulid, query))
+                    .on_submit(Message::ChatFindStep(ulid, true))
+                    .width(250.0),
+            )
+            .push(text(status).size(12.0).style(text::secondary))
+            .push(
+                button_icon(iced_fonts::Bootstrap::ChevronUp)
+                    .style(button::secondary)
+                    .on_press_maybe(found.then_some(Message::ChatFindStep(ulid, false))),
+            )
+            .push(
+                button_icon(iced_fonts::Bootstrap::ChevronDown)
+                    .style(button::secondary)
+                    .on_press_maybe(found.then_some(Message::ChatFindStep(ulid, true))),
+            )
+            .push(
+                button_icon(iced_fonts::Bootstrap::X)
+                    .style(button::text)
+                    .on_press(Message::ChatFindClose(ulid)),
+            )
+            .spacing(5.0)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_system(&self) -> Element<'_, Message> {
+        let ulid = self.ulid();
+        let (icon, label) = if self.show_system {
+            (iced_fonts::Bootstrap::ChevronDown, "System prompt")
+        } else if self.previous.system.is_some() {
+            (iced_fonts::Bootstrap::ChevronRight, "System prompt (set)")
+        } else {
+            (iced_fonts::Bootstrap::ChevronRight, "System prompt")
+        };
+        column![]
+            .push(
+                button_icon_text(icon, label)
+                    .style(button::text)
+                    .padding(0.0)
+                    .on_press(Message::ChatToggleSystem(ulid)),
+            )
+            .push_maybe(self.show_system.then(|| {
+                text_editor(&self.system_editor)
+                    .placeholder("Instructions given to the model for the whole chat...")
+                    .on_action(move |action| Message::ChatEditSystem(ulid, action))
+                    .height(100.0)
+            }))
+            .spacing(5.0)
+            .into()
     }
 
     fn view_prompt_editor<'a>(
+        &'a self,
         content: &'a iced::widget::text_editor::Content,
+        pinned_prompts: &'a [PinnedPrompt],
+        num_ctx: Option<u64>,
     ) -> Container<'a, Message> {
-        container(
+        let ulid = self.ulid();
+        let prompt = content.text();
+        let pinned = pinned_prompts.iter().map(|pinned| {
+            button(text(&pinned.label).size(12.0))
+                .style(button::secondary)
+                .on_press(Message::ChatInsertPinned(ulid, pinned.text.clone(), None))
+                .into()
+        });
+        let editor =
             row![]
                 .push(
-                    text_editor(&content)
+                    text_editor(content)
                         .placeholder("Type something here...")
-                        .on_action(Message::ChatEditPrompt)
-                        .key_binding(|key_press| match key_press.key.as_ref() {
+                        .on_action(move |action| Message::ChatEditPrompt(ulid, action))
+                        .key_binding(move |key_press| match key_press.key.as_ref() {
                             iced::keyboard::Key::Named(iced::keyboard::key::Named::Enter)
                                 if key_press.modifiers.command() =>
                             {
                                 Some(iced::widget::text_editor::Binding::Custom(
-                                    Message::ChatSend,
+                                    Message::ChatSend(ulid),
                                 ))
                             }
                             _ => text_editor::Binding::from_key_press(key_press),
                         }),
                 )
                 .push(
-                    button_icon(iced_fonts::Bootstrap::Send)
-                        .on_press_maybe((!content.text().is_empty()).then_some(Message::ChatSend)),
+                    column![]
+                        .push(button_icon(iced_fonts::Bootstrap::Send).on_press_maybe(
+                            (!prompt.is_empty()).then_some(Message::ChatSend(ulid)),
+                        ))
+                        .push(
+                            button_icon(iced_fonts::Bootstrap::Paperclip)
+                                .on_press(Message::ChatAttach(ulid))
+                                .style(button::secondary),
+                        )
+                        .spacing(5.0),
                 )
-                .spacing(5.0),
-        )
-    }
+                .spacing(5.0);
+        let attachments = self
+            .attachments
+            .iter()
+            .enumerate()
+            .map(|(index, attachment)| {
+                stack![
+                    image(attachment.thumbnail.clone())
+                        .width(THUMBNAIL_SIZE)
+                        .height(THUMBNAIL_SIZE),
+                    button_icon_small(iced_fonts::Bootstrap::X)
+                        .on_press(Message::ChatAttachRemove(ulid, index))
+                        .style(button::danger)
+                        .padding(1.0),
+                ]
+                .into()
+            });
+        let attach_error = self
+            .attach_error
+            .as_ref()
+            .map(|e| text(e).size(12.0).style(text::danger));
+        let prompt_tokens = self.prompt_tokens;
+        let context_tokens = self.context_tokens + prompt_tokens;
+        let mut counter = format!(
+            "{} characters · ~{} tokens",
+            prompt.chars().count(),
+            prompt_tokens
+        );
+        match num_ctx {
+            Some(num_ctx) => {
+                counter.push_str(&format!(" · context ~{} / {}", context_tokens, num_ctx))
+            }
+            None => counter.push_str(&format!(" · context ~{}", context_tokens)),
+        }
+        let near_limit = num_ctx
+            .is_some_and(|num_ctx| context_tokens as f64 >= num_ctx as f64 * CONTEXT_WARNING);
+        let counter = if near_limit {
+            text(format!(
+                "{} · close to the context size, the beginning of the chat may be dropped",
+                counter
+            ))
+            .size(12.0)
+            .style(text::danger)
+        } else {
+            text(counter).size(12.0).style(text::secondary)
+        };
+        container(
+            column![]
+                .push_maybe((!pinned_prompts.is_empty()).then(|| row(pinned).spacing(5.0).wrap()))
+                .push_maybe(
+                    (!self.attachments.is_empty()).then(|| row(attachments).spacing(5.0).wrap()),
+                )
+                .push_maybe(attach_error)
+                .push_maybe(self.editing.as_ref().map(|_| {
+                    row![]
+                        .push(text("Editing the last prompt").style(text::secondary))
+                        .push(
+                            button(text("Cancel"))
+                                .style(button::secondary)
+                                .on_press(Message::ChatEditCancel(ulid)),
+                        )
+                        .spacing(10.0)
+                        .align_y(Alignment::Center)
+                }))
+                .push(editor)
+                .push(counter)
+                .spacing(5.0),
+        )
+    }
 
-    fn view_prompt<'a>(prompt: &'a str) -> Container<'a, Message> {
-        container(container(text(prompt)).padding(Padding::default().left(5.0).right(5.0)))
+    fn view_prompt<'a>(
+        &'a self,
+        index: usize,
+        prompt: &'a Query,
+        found: &[(Range<usize>, bool)],
+    ) -> Container<'a, Message> {
+        let mut inner = if found.is_empty() {
+            column![].push(text(&prompt.text))
+        } else {
+            column![].push(rich_text(highlight_matches(&prompt.text, found)))
+        };
+        if !prompt.images.is_empty() {
+            inner = inner.push(
+                text(format!("{} image(s) attached", prompt.images.len()))
+                    .size(12.0)
+                    .font(iced::Font::MONOSPACE),
+            );
+        }
+        let editable = index + 2 == self.previous.content.len()
+            && matches!(self.state, ChatState::Prompting(_))
+            && self.editing.is_none();
+        if editable {
+            inner = inner.push(
+                button_icon_text(iced_fonts::Bootstrap::Pencil, "Edit")
+                    .padding(0.0)
+                    .style(button::text)
+                    .on_press(Message::ChatEditLast(self.ulid())),
+            );
+        }
+        if let Some(sent) = &prompt.sent {
+            let shown = self.show_sent.contains(&index);
+            let label = if shown {
+                "hide sent prompt"
+            } else {
+                "show sent prompt"
+            };
+            inner = inner.push(
+                button(text(label).size(12.0))
+                    .padding(0.0)
+                    .style(button::text)
+                    .on_press(Message::ChatToggleSentPrompt(self.ulid(), index)),
+            );
+            if shown {
+                inner = inner.push(text(sent).font(iced::Font::MONOSPACE).size(12.0));
+            }
+        }
+        let bubble = container(container(inner).padding(Padding::default().left(5.0).right(5.0)))
             .style(|theme: &iced::Theme| {
                 let palette = theme.extended_palette();
 
@@ -226,50 +1364,581 @@ impl Chat {
                     border: iced::border::rounded(10),
                     ..container::Style::default()
                 }
-            })
-            .center_x(Length::Fill)
-            .padding(
-                Padding::default()
-                    .top(5.0)
-                    .bottom(5.0)
-                    .left(30.0)
-                    .right(30.0),
+            });
+        // the user side is on the right, chat-app style
+        container(
+            row![]
+                .push(bubble)
+                .push(role_icon(iced_fonts::Bootstrap::PersonFill, |p| {
+                    p.primary.base
+                }))
+                .spacing(8.0),
+        )
+        .align_right(Length::Fill)
+        .padding(Padding::default().top(5.0).bottom(5.0).left(60.0))
+    }
+
+    fn view_output<'a>(
+        &self,
+        index: usize,
+        output: &'a ChatOutput,
+        model: Option<&'a str>,
+        found: Vec<(usize, Option<container::Id>)>,
+        settings: &Settings,
+    ) -> Element<'a, Message> {
+        let ulid = self.ulid();
+        let header = row![]
+            .push_maybe(model.map(|model| text(model).size(12.0).style(text::secondary)))
+            .push(horizontal_space())
+            .push(
+                button_icon_small(if output.is_source_shown() {
+                    iced_fonts::Bootstrap::MarkdownFill
+                } else {
+                    iced_fonts::Bootstrap::Markdown
+                })
+                .style(button::text)
+                .padding(1.0)
+                .on_press(Message::ChatToggleSource(self.ulid(), index)),
+            )
+            .push(
+                button_icon_small(iced_fonts::Bootstrap::Clipboard)
+                    .style(button::text)
+                    .padding(1.0)
+                    .on_press(Message::CopyReply(Arc::new(output.raw()))),
+            )
+            .align_y(Alignment::Center);
+        row![]
+            .push(Self::view_assistant_icon())
+            .push(
+                column![]
+                    .push(header)
+                    .push(output.view(
+                        Message::ChatToggleReasoning(self.ulid(), index),
+                        |chunk| Message::ChatToggleCode(self.ulid(), index, chunk),
+                        move |action| Message::ChatSourceAction(ulid, index, action),
+                        found,
+                        settings,
+                    ))
+                    .spacing(5.0),
             )
+            .spacing(8.0)
+            .padding(Padding::default().right(30.0))
+            .into()
+    }
+
+    fn view_assistant_icon<'a>() -> Container<'a, Message> {
+        role_icon(iced_fonts::Bootstrap::Robot, |p| p.secondary.base)
+    }
+
+    /// Shown in place of the reply until its first token arrives
+    fn view_pending<'a>() -> Element<'a, Message> {
+        row![]
+            .push(Self::view_assistant_icon())
+            .push(
+                Indicator::new()
+                    .circle_radius(8.0)
+                    .color(iced::Color::from_rgb8(50, 180, 30))
+                    .pulsing(true),
+            )
+            .push(text("thinking…").style(text::secondary))
+            .spacing(10.0)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_reply_info(info: &ReplyInfo) -> Option<Element<'_, Message>> {
+        let mut details = Vec::new();
+        if let Some(duration_ms) = info.duration_ms {
+            details.push(format!("{:.1}s", duration_ms as f32 / 1000.0));
+        }
+        if let Some(tokens) = info.tokens {
+            details.push(format!("{} tokens", tokens));
+        }
+        if let Some(speed) = info.tokens_per_second() {
+            details.push(format!("{:.1} tokens/s", speed));
+        }
+        if let Some(prompt_tokens) = info.prompt_tokens {
+            details.push(format!("{} prompt tokens", prompt_tokens));
+        }
+        (!details.is_empty()).then(|| {
+            text(details.join(" · "))
+                .size(12.0)
+                .style(text::secondary)
+                .into()
+        })
     }
 
-    fn view_output<'a>(output: &'a ChatOutput) -> Container<'a, Message> {
-        output.view()
+    /// Expand or collapse the reasoning of the reply at `index` (possibly the one being generated)
+    pub fn toggle_reasoning(&mut self, index: usize) {
+        match self.previous.content.get_mut(index) {
+            Some(Party::Reply(reply)) => reply.content.toggle_reasoning(),
+            Some(Party::Query(_)) => {}
+            None => {
+                if let ChatState::Generating(generating) = &mut self.state {
+                    generating.output.toggle_reasoning()
+                }
+            }
+        }
     }
 
-    pub fn add_content(&mut self, response: api::ChatMessageResponse) {
+    /// Show the markdown source of the reply at `index`, or render it again
+    pub fn toggle_source(&mut self, index: usize) {
+        match self.previous.content.get_mut(index) {
+            Some(Party::Reply(reply)) => reply.content.toggle_source(),
+            Some(Party::Query(_)) => {}
+            None => {
+                if let ChatState::Generating(generating) = &mut self.state {
+                    generating.output.toggle_source()
+                }
+            }
+        }
+    }
+
+    /// Select or copy in the markdown source of the reply at `index`
+    pub fn source_action(&mut self, index: usize, action: text_editor::Action) {
+        match self.previous.content.get_mut(index) {
+            Some(Party::Reply(reply)) => reply.content.source_action(action),
+            Some(Party::Query(_)) => {}
+            None => {
+                if let ChatState::Generating(generating) = &mut self.state {
+                    generating.output.source_action(action)
+                }
+            }
+        }
+    }
+
+    pub fn toggle_code(&mut self, index: usize, chunk: usize, collapse_default: bool) {
+        match self.previous.content.get_mut(index) {
+            Some(Party::Reply(reply)) => reply.content.toggle_code(chunk, collapse_default),
+            Some(Party::Query(_)) => {}
+            None => {
+                if let ChatState::Generating(generating) = &mut self.state {
+                    generating.output.toggle_code(chunk, collapse_default)
+                }
+            }
+        }
+    }
+
+    /// Append a batch of streamed responses, parsed as a whole. Returns true when a trailing
+    /// parse of the open paragraph must be scheduled with `refresh_preview`
+    pub fn add_content(&mut self, responses: Vec<api::ChatMessageResponse>) -> bool {
         match &mut self.state {
             ChatState::Prompting(_) => {
-                tracing::error!("chat message appended in prompt mode")
+                tracing::error!("chat message appended in prompt mode");
+                false
             }
             ChatState::Generating(generating) => {
-                generating.output.add_content(&response.message.content)
+                let mut content = String::new();
+                for response in responses {
+                    content.push_str(&response.message.content);
+                    if response.final_data.is_some() {
+                        generating.final_data = response.final_data;
+                    }
+                }
+                generating.output.add_content(&content)
+            }
+        }
+    }
+
+    pub fn refresh_preview(&mut self) {
+        if let ChatState::Generating(generating) = &mut self.state {
+            generating.output.refresh_preview();
+        }
+    }
+}
+
+// the text searched in a party of the chat
+// the text split in spans, with a background behind the matches
+fn highlight_matches<'a>(
+    s: &'a str,
+    found: &[(Range<usize>, bool)],
+) -> Vec<iced::widget::text::Span<'a, Message>> {
+    let mut spans = vec![];
+    let mut end = 0;
+    for (range, current) in found {
+        spans.push(span(&s[end..range.start]));
+        let background = if *current {
+            iced::Color::from_rgb8(0xff, 0x96, 0x32)
+        } else {
+            iced::Color::from_rgb8(0xff, 0xe0, 0x66)
+        };
+        spans.push(
+            span(&s[range.clone()])
+                .color(iced::Color::BLACK)
+                .background(background),
+        );
+        end = range.end;
+    }
+    spans.push(span(&s[end..]));
+    spans
+}
+
+// the offset in the chat scrollable of the container of the current match
+struct MatchOffset {
+    scrollable: iced_widget::Id,
+    target: iced_widget::Id,
+    content: Option<Rectangle>,
+    offset: Option<f32>,
+}
+
+impl iced_widget::Operation<f32> for MatchOffset {
+    fn scrollable(
+        &mut self,
+        _state: &mut dyn operation::Scrollable,
+        id: Option<&iced_widget::Id>,
+        _bounds: Rectangle,
+        content_bounds: Rectangle,
+        _translation: Vector,
+    ) {
+        if id == Some(&self.scrollable) {
+            self.content = Some(content_bounds);
+        }
+    }
+
+    fn container(
+        &mut self,
+        id: Option<&iced_widget::Id>,
+        bounds: Rectangle,
+        operate_on_children: &mut dyn FnMut(&mut dyn iced_widget::Operation<f32>),
+    ) {
+        if self.offset.is_some() {
+            return;
+        }
+        match self.content {
+            Some(content) if id == Some(&self.target) => self.offset = Some(bounds.y - content.y),
+            _ => operate_on_children(self),
+        }
+    }
+
+    fn finish(&self) -> operation::Outcome<f32> {
+        match self.offset {
+            Some(offset) => operation::Outcome::Some(offset),
+            None => operation::Outcome::None,
+        }
+    }
+}
+
+// outlines a chunk of a reply holding matches of the search, with the id scrolled to when it
+// holds the current one
+fn view_found<'a>(
+    element: impl Into<Element<'a, Message>>,
+    match_id: Option<container::Id>,
+) -> Element<'a, Message> {
+    let current = match_id.is_some();
+    let found = container(element)
+        .padding(3.0)
+        .style(move |theme: &iced::Theme| {
+            let palette = theme.extended_palette();
+            let (color, width) = if current {
+                (palette.primary.strong.color, 2.0)
+            } else {
+                (palette.primary.weak.color, 1.0)
+            };
+            container::Style {
+                border: iced::border::rounded(5).color(color).width(width),
+                ..container::Style::default()
+            }
+        });
+    match match_id {
+        Some(id) => found.id(id).into(),
+        None => found.into(),
+    }
+}
+
+/// Byte ranges of the case-insensitive occurrences of `needle` in `haystack`
+fn find_matches(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+    let needle = needle
+        .chars()
+        .flat_map(char::to_lowercase)
+        .collect::<Vec<_>>();
+    let mut matches = vec![];
+    if needle.is_empty() {
+        return matches;
+    }
+    let mut next = 0;
+    'start: for (start, _) in haystack.char_indices() {
+        if start < next {
+            continue;
+        }
+        let mut expected = needle.iter();
+        for (i, c) in haystack[start..].char_indices() {
+            for lower in c.to_lowercase() {
+                if expected.next() != Some(&lower) {
+                    continue 'start;
+                }
+            }
+            if expected.len() == 0 {
+                next = start + i + c.len_utf8();
+                matches.push(start..next);
+                continue 'start;
             }
         }
+        // the end is reached before the whole needle, so is it for any later start
+        break;
     }
+    matches
 }
 
 #[derive(Clone)]
 pub enum OutputMode {
     Text(Vec<iced::widget::markdown::Item>),
-    Code(String, Arc<iced::widget::text_editor::Content>),
+    Tasks(Vec<TaskItem>),
+    Table(Table),
+    Code(String, Rc<iced::widget::text_editor::Content>),
+    // the content of a `$$ ... $$` block
+    Math(String),
+}
+
+/// A line of a markdown list, with a checkbox state if it is a task (`- [ ]` / `- [x]`)
+#[derive(Clone)]
+pub struct TaskItem {
+    checked: Option<bool>,
+    indent: usize,
+    items: Vec<iced::widget::markdown::Item>,
+}
+
+fn task_marker(line: &str) -> Option<(bool, &str)> {
+    let rest = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("+ "))?;
+    if let Some(rest) = rest.strip_prefix("[ ]") {
+        Some((false, rest.trim_start()))
+    } else if let Some(rest) = rest
+        .strip_prefix("[x]")
+        .or_else(|| rest.strip_prefix("[X]"))
+    {
+        Some((true, rest.trim_start()))
+    } else {
+        None
+    }
+}
+
+fn is_list_item(line: &str) -> bool {
+    line.starts_with("- ")
+        || line.starts_with("* ")
+        || line.starts_with("+ ")
+        || line
+            .split_once(". ")
+            .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Split a paragraph made only of list items into task items, if at least one of
+/// them is a task
+fn parse_task_list(s: &str) -> Option<Vec<TaskItem>> {
+    let mut lines: Vec<(Option<bool>, usize, String)> = vec![];
+    for line in s.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        if let Some((checked, rest)) = task_marker(trimmed) {
+            lines.push((Some(checked), indent, rest.to_string()));
+        } else if is_list_item(trimmed) {
+            lines.push((None, indent, trimmed.to_string()));
+        } else if let Some((_, _, last)) = lines.last_mut() {
+            // continuation of the previous item
+            last.push(' ');
+            last.push_str(trimmed);
+        } else {
+            return None;
+        }
+    }
+    if !lines.iter().any(|(checked, _, _)| checked.is_some()) {
+        return None;
+    }
+    Some(
+        lines
+            .into_iter()
+            .map(|(checked, indent, content)| TaskItem {
+                checked,
+                indent,
+                items: iced::widget::markdown::parse(&content).collect(),
+            })
+            .collect(),
+    )
+}
+
+/// A markdown table, short rows being padded with empty cells
+#[derive(Clone)]
+pub struct Table {
+    align: Vec<iced::alignment::Horizontal>,
+    header: Vec<Vec<iced::widget::markdown::Item>>,
+    rows: Vec<Vec<Vec<iced::widget::markdown::Item>>>,
+}
+
+// the cells of a `| a | b |` line, the outer pipes being optional and `\|` escaping a pipe
+fn table_cells(line: &str) -> Option<Vec<String>> {
+    let line = line.trim();
+    if !line.contains('|') {
+        return None;
+    }
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(rest) if !rest.ends_with('\\') => rest,
+        _ => line,
+    };
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => cell.push(chars.next().unwrap()),
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            c => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    Some(cells)
+}
+
+// the alignment of every column, if the line is a `|---|:---:|` header separator
+fn table_separator(cells: &[String]) -> Option<Vec<iced::alignment::Horizontal>> {
+    cells
+        .iter()
+        .map(|cell| {
+            let left = cell.starts_with(':');
+            let right = cell.ends_with(':');
+            let dashes = cell.trim_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (left, right) {
+                (true, true) => iced::alignment::Horizontal::Center,
+                (false, true) => iced::alignment::Horizontal::Right,
+                _ => iced::alignment::Horizontal::Left,
+            })
+        })
+        .collect()
+}
+
+fn parse_table(s: &str) -> Option<Table> {
+    let mut lines = s.lines().filter(|line| !line.trim().is_empty());
+    let header = table_cells(lines.next()?)?;
+    let mut align = table_separator(&table_cells(lines.next()?)?)?;
+    let rows = lines.map(table_cells).collect::<Option<Vec<_>>>()?;
+    let columns = rows
+        .iter()
+        .map(Vec::len)
+        .chain([header.len(), align.len()])
+        .max()
+        .unwrap_or(0);
+    align.resize(columns, iced::alignment::Horizontal::Left);
+    let parse_row = |mut cells: Vec<String>| {
+        cells.resize(columns, String::new());
+        cells
+            .iter()
+            .map(|cell| iced::widget::markdown::parse(cell).collect())
+            .collect::<Vec<_>>()
+    };
+    Some(Table {
+        align,
+        header: parse_row(header),
+        rows: rows.into_iter().map(parse_row).collect(),
+    })
+}
+
+impl Table {
+    fn view_row<'a>(
+        &'a self,
+        cells: &'a [Vec<iced::widget::markdown::Item>],
+    ) -> Element<'a, Message> {
+        row(cells.iter().zip(&self.align).map(|(cell, align)| {
+            container(view_markdown(cell))
+                .width(Length::FillPortion(1))
+                .align_x(*align)
+                .padding(5.0)
+                .into()
+        }))
+        .into()
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let header = container(self.view_row(&self.header)).style(|theme: &iced::Theme| {
+            container::background(theme.extended_palette().background.weak.color)
+        });
+        container(
+            column![].push(header).extend(
+                self.rows
+                    .iter()
+                    .flat_map(|cells| [horizontal_rule(1.0).into(), self.view_row(cells)]),
+            ),
+        )
+        .style(container::bordered_box)
+        .into()
+    }
+}
+
+fn view_markdown(items: &[iced::widget::markdown::Item]) -> Element<'_, Message> {
+    iced::widget::markdown(
+        items,
+        iced::widget::markdown::Settings::default(),
+        iced::widget::markdown::Style::from_palette(iced::Theme::TokyoNightStorm.palette()),
+    )
+    .map(Message::LinkClicked)
 }
 
 #[derive(Clone)]
 pub struct ChatOutput {
     stream: MarkdownIncremental,
+    reasoning: Vec<Chunk>,
     output: Vec<Chunk>,
+    // set once the user expanded or collapsed the reasoning
+    show_reasoning: Option<bool>,
+    preview: Option<Preview>,
+    // the markdown source in a read-only editor, so that it can be selected and copied
+    source: Rc<text_editor::Content>,
+    // the source is shown instead of the rendered reply
+    show_source: bool,
+}
+
+/// The open paragraph of a streaming reply, parsed ahead of its end so that its inline
+/// formatting shows while it streams
+#[derive(Clone)]
+struct Preview {
+    // start of the unparsed tail that was parsed, the preview is stale once it moved
+    pos: usize,
+    // length of the tail that was parsed, the preview is behind once the tail grew
+    len: usize,
+    parsed_at: Instant,
+    items: Vec<iced::widget::markdown::Item>,
+    // a parse is scheduled at the end of the throttling window, for the tokens it skipped
+    trailing: bool,
 }
 
+/// The open paragraph is parsed again at most this often, instead of on every token
+pub const PREVIEW_INTERVAL: Duration = Duration::from_millis(100);
+
 impl ChatOutput {
     pub fn new() -> Self {
         Self {
             stream: MarkdownIncremental::new(),
+            reasoning: vec![],
             output: vec![],
+            show_reasoning: None,
+            preview: None,
+            source: Rc::new(text_editor::Content::new()),
+            show_source: false,
+        }
+    }
+
+    fn is_reasoning(&self) -> bool {
+        self.stream.context == MarkdownContext::Think
+    }
+
+    /// The reasoning is shown while the model is thinking, and collapsed once the answer starts
+    fn reasoning_shown(&self) -> bool {
+        self.show_reasoning.unwrap_or_else(|| self.is_reasoning())
+    }
+
+    pub fn toggle_reasoning(&mut self) {
+        self.show_reasoning = Some(!self.reasoning_shown());
+    }
+
+    pub fn toggle_code(&mut self, chunk: usize, collapse_default: bool) {
+        if let Some(chunk) = self.output.get_mut(chunk) {
+            chunk.toggle_collapsed(collapse_default);
         }
     }
 
@@ -277,41 +1946,234 @@ impl ChatOutput {
         self.stream.buf.clone()
     }
 
+    pub fn is_source_shown(&self) -> bool {
+        self.show_source
+    }
+
+    /// Switch between the rendered reply and its markdown source
+    pub fn toggle_source(&mut self) {
+        self.show_source = !self.show_source;
+    }
+
+    /// Apply an action of the source editor, except the edits since the source is read-only
+    pub fn source_action(&mut self, action: text_editor::Action) {
+        if action.is_edit() {
+            return;
+        }
+        if let Some(source) = Rc::get_mut(&mut self.source) {
+            source.perform(action);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stream.buf.is_empty()
+    }
+
     fn unparsed(&self) -> &str {
         &self.stream.buf[self.stream.pos..]
     }
 
-    pub fn view<'a>(&'a self) -> Container<'a, Message> {
-        let rem = std::iter::once(text(self.unparsed()).into());
-        container(column(self.output.iter().map(|c| c.view()).chain(rem)).spacing(20.0))
+    fn view_reasoning<'a>(
+        &'a self,
+        on_toggle: Message,
+        code_theme: highlighter::Theme,
+    ) -> Element<'a, Message> {
+        let shown = self.reasoning_shown();
+        let (icon, label) = if shown {
+            (iced_fonts::Bootstrap::ChevronDown, "Reasoning")
+        } else {
+            (iced_fonts::Bootstrap::ChevronRight, "Reasoning")
+        };
+        let toggle = button_icon_text(icon, label)
+            .style(button::text)
+            .padding(0.0)
+            .on_press(on_toggle);
+        let content = shown.then(|| {
+            let rem = self.is_reasoning().then(|| text(self.unparsed()).into());
+            container(
+                column(self.reasoning.iter().map(|c| c.view(code_theme)).chain(rem)).spacing(10.0),
+            )
+            .padding(Padding::default().left(10.0))
+            .style(|theme: &iced::Theme| container::Style {
+                text_color: Some(theme.extended_palette().background.strong.color),
+                ..container::Style::default()
+            })
+        });
+        column![]
+            .push(toggle)
+            .push_maybe(content)
+            .spacing(5.0)
+            .into()
+    }
+
+    pub fn view<'a>(
+        &'a self,
+        toggle_reasoning: Message,
+        toggle_code: impl Fn(usize) -> Message,
+        source_action: impl Fn(text_editor::Action) -> Message + 'a,
+        found: Vec<(usize, Option<container::Id>)>,
+        settings: &Settings,
+    ) -> Container<'a, Message> {
+        let code_theme = settings.code_highlighter();
+        // without rendering, the reply is shown as streamed, reasoning tags included
+        if self.show_source || !settings.render_markdown {
+            // the edits are dropped by source_action, keeping the source read-only
+            let source = text_editor(&self.source)
+                .on_action(source_action)
+                .font(iced::Font::MONOSPACE);
+            // the chunks are not shown, so the whole source is outlined
+            return if found.is_empty() {
+                container(source)
+            } else {
+                container(view_found(source, found.into_iter().find_map(|(_, id)| id)))
+            };
+        }
+        let reasoning = (!self.reasoning.is_empty() || self.is_reasoning())
+            .then(|| self.view_reasoning(toggle_reasoning, code_theme));
+        let rem = (!self.is_reasoning()).then(|| match &self.preview {
+            Some(preview) if preview.pos == self.stream.pos => view_markdown(&preview.items),
+            _ => text(self.unparsed()).into(),
+        });
+        container(
+            column(
+                reasoning
+                    .into_iter()
+                    .chain(self.output.iter().enumerate().map(|(i, c)| {
+                        let chunk =
+                            c.view_collapsible(code_theme, settings.collapse_code, toggle_code(i));
+                        let mut found = found.iter().filter(|(chunk, _)| *chunk == i).peekable();
+                        if found.peek().is_none() {
+                            chunk
+                        } else {
+                            view_found(chunk, found.find_map(|(_, id)| id.clone()))
+                        }
+                    }))
+                    .chain(rem),
+            )
+            .spacing(20.0),
+        )
     }
 
-    pub fn add_content(&mut self, message: &str) {
+    fn push(&mut self, content: Content) {
+        match content {
+            Content::Code(s) => self.output.push(Chunk::new_code(s)),
+            Content::Math(s) => self.output.push(Chunk::new_math(s)),
+            // the tags of reasoning blocks are usually surrounded by blank lines
+            Content::Normal(s) | Content::Think(s) if s.trim().is_empty() => {}
+            Content::Normal(s) => self.output.push(Chunk::new(s)),
+            Content::Think(s) => self.reasoning.push(Chunk::new(s)),
+        }
+    }
+
+    /// Append streamed text, returning true when a trailing parse of the open paragraph must be
+    /// scheduled with `refresh_preview` after `PREVIEW_INTERVAL`
+    pub fn add_content(&mut self, message: &str) -> bool {
         self.stream.add_content(message);
-        loop {
-            match self.stream.process_content() {
-                None => {
-                    break;
-                }
-                Some(Content::Code(s)) => self.output.push(Chunk::new_code(s)),
-                Some(Content::Normal(s)) => self.output.push(Chunk::new(s)),
+        while let Some(content) = self.stream.process_content() {
+            self.push(content);
+        }
+        if let Some(content) = self.stream.flush_long_tail(UNPARSED_SOFT_CAP) {
+            self.push(content);
+        }
+        let trailing = self.update_preview();
+        match Rc::get_mut(&mut self.source) {
+            // the tokens are appended at the end, instead of building the whole source again
+            Some(source) => {
+                source.perform(text_editor::Action::Move(text_editor::Motion::DocumentEnd));
+                source.perform(text_editor::Action::Edit(text_editor::Edit::Paste(
+                    Arc::new(message.to_string()),
+                )));
+            }
+            None => self.source = Rc::new(text_editor::Content::with_text(&self.stream.buf)),
+        }
+        trailing
+    }
+
+    // true when the parse was skipped by the throttling, and no trailing one is scheduled yet
+    fn update_preview(&mut self) -> bool {
+        if self.stream.context != MarkdownContext::Normal || self.unparsed().trim().is_empty() {
+            self.preview = None;
+            return false;
+        }
+        let len = self.unparsed().len();
+        match &mut self.preview {
+            Some(preview)
+                if preview.pos == self.stream.pos
+                    && preview.parsed_at.elapsed() < PREVIEW_INTERVAL =>
+            {
+                let schedule = preview.len != len && !preview.trailing;
+                preview.trailing |= schedule;
+                schedule
+            }
+            _ => {
+                self.parse_preview();
+                false
             }
         }
     }
+
+    fn parse_preview(&mut self) {
+        self.preview = Some(Preview {
+            pos: self.stream.pos,
+            len: self.unparsed().len(),
+            parsed_at: Instant::now(),
+            items: iced::widget::markdown::parse(self.unparsed()).collect(),
+            trailing: false,
+        });
+    }
+
+    /// The trailing parse, catching up with the tokens skipped while throttled
+    pub fn refresh_preview(&mut self) {
+        let behind = self.preview.as_ref().is_some_and(|preview| {
+            preview.pos == self.stream.pos && preview.len != self.unparsed().len()
+        });
+        if behind {
+            self.parse_preview();
+        } else if let Some(preview) = &mut self.preview {
+            preview.trailing = false;
+        }
+    }
+
+    /// Parse what is left once the reply ended, e.g. a code block without its closing fence
+    pub fn finalize(&mut self) {
+        if let Some(content) = self.stream.flush_tail() {
+            self.push(content);
+        }
+        self.preview = None;
+    }
 }
 
 #[derive(Clone)]
 pub struct Chunk {
     raw_content: Arc<String>,
     output_mode: OutputMode,
+    // set once the user collapsed or expanded the code block, the setting applies otherwise
+    collapsed: Option<bool>,
 }
 
 impl Chunk {
     pub fn new(raw_content: String) -> Self {
-        let items = iced::widget::markdown::parse(&raw_content).collect();
+        let output_mode = if let Some(tasks) = parse_task_list(&raw_content) {
+            OutputMode::Tasks(tasks)
+        } else if let Some(table) = parse_table(&raw_content) {
+            OutputMode::Table(table)
+        } else {
+            OutputMode::Text(
+                iced::widget::markdown::parse(&inline_math_as_code(&raw_content)).collect(),
+            )
+        };
         Self {
             raw_content: Arc::new(raw_content),
-            output_mode: OutputMode::Text(items),
+            output_mode,
+            collapsed: None,
+        }
+    }
+
+    pub fn new_math(raw_content: String) -> Self {
+        Self {
+            output_mode: OutputMode::Math(raw_content.trim().to_string()),
+            raw_content: Arc::new(raw_content),
+            collapsed: None,
         }
     }
 
@@ -320,71 +2182,287 @@ impl Chunk {
             Self {
                 raw_content: Arc::new(content.to_string()),
                 output_mode: OutputMode::Code(
-                    code_type.to_string(),
-                    Arc::new(iced::widget::text_editor::Content::with_text(content)),
+                    normalize_language(code_type),
+                    Rc::new(iced::widget::text_editor::Content::with_text(content)),
                 ),
+                collapsed: None,
             }
         } else {
             let content = iced::widget::text_editor::Content::with_text(&raw_content);
             Self {
                 raw_content: Arc::new(raw_content),
-                output_mode: OutputMode::Code(String::new(), Arc::new(content)),
+                output_mode: OutputMode::Code(String::new(), Rc::new(content)),
+                collapsed: None,
             }
         }
     }
 
-    pub fn view<'a>(&'a self) -> Element<'a, Message> {
-        match &self.output_mode {
-            OutputMode::Text(items) => iced::widget::markdown(
-                items,
-                iced::widget::markdown::Settings::default(),
-                iced::widget::markdown::Style::from_palette(iced::Theme::TokyoNightStorm.palette()),
+    fn is_collapsed(&self, collapse_default: bool) -> bool {
+        self.collapsed.unwrap_or(collapse_default)
+    }
+
+    pub fn toggle_collapsed(&mut self, collapse_default: bool) {
+        self.collapsed = Some(!self.is_collapsed(collapse_default));
+    }
+
+    /// Like [`Chunk::view`], with a header collapsing and expanding code blocks
+    pub fn view_collapsible<'a>(
+        &'a self,
+        code_theme: highlighter::Theme,
+        collapse_default: bool,
+        on_toggle: Message,
+    ) -> Element<'a, Message> {
+        let OutputMode::Code(code_type, content) = &self.output_mode else {
+            return self.view(code_theme);
+        };
+        let collapsed = self.is_collapsed(collapse_default);
+        let lines = content.line_count();
+        let label = format!(
+            "{} · {} line{}",
+            code_label(code_type),
+            lines,
+            if lines == 1 { "" } else { "s" }
+        );
+        let toggle = button(
+            row![]
+                .push(icon_to_text(if collapsed {
+                    iced_fonts::Bootstrap::ChevronRight
+                } else {
+                    iced_fonts::Bootstrap::ChevronDown
+                }))
+                .push(text(label).size(12.0).font(iced::Font::MONOSPACE))
+                .spacing(5.0)
+                .align_y(Alignment::Center),
+        )
+        .style(button::text)
+        .padding(0.0)
+        .on_press(on_toggle);
+        column![]
+            .push(self.view_code_header(toggle))
+            .push_maybe((!collapsed).then(|| Self::view_code(code_type, content, code_theme)))
+            .spacing(5.0)
+            .into()
+    }
+
+    // the label of the code block, with the button copying it on the right
+    fn view_code_header<'a>(&self, label: impl Into<Element<'a, Message>>) -> Element<'a, Message> {
+        row![]
+            .push(label)
+            .push(horizontal_space())
+            .push(
+                button_icon_small(iced_fonts::Bootstrap::Clipboard)
+                    .style(button::text)
+                    .padding(1.0)
+                    .on_press(Message::CopyClipboard(self.raw_content.clone())),
             )
-            .map(Message::LinkClicked)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_code<'a>(
+        code_type: &'a str,
+        content: &'a text_editor::Content,
+        code_theme: highlighter::Theme,
+    ) -> Element<'a, Message> {
+        iced::widget::TextEditor::new(content)
+            .style(move |theme, style| {
+                let mut style = iced::widget::text_editor::default(theme, style);
+                style.background = iced::Background::Color(if code_theme.is_dark() {
+                    iced::Color::from_rgb8(0, 0, 0)
+                } else {
+                    iced::Color::from_rgb8(0xff, 0xff, 0xff)
+                });
+                style
+            })
+            .highlight(code_type, code_theme)
+            .font(iced::Font::MONOSPACE)
+            .into()
+    }
+
+    pub fn view<'a>(&'a self, code_theme: highlighter::Theme) -> Element<'a, Message> {
+        match &self.output_mode {
+            OutputMode::Text(items) => view_markdown(items),
+            OutputMode::Tasks(tasks) => column(tasks.iter().map(|task| {
+                let line = row![].push(horizontal_space().width(task.indent as f32 * 8.0));
+                let line = match task.checked {
+                    // no on_toggle: the checkbox is read-only
+                    Some(checked) => line.push(checkbox("", checked)),
+                    None => line,
+                };
+                line.push(view_markdown(&task.items))
+                    .spacing(5.0)
+                    .align_y(Alignment::Center)
+                    .into()
+            }))
+            .spacing(2.0)
             .into(),
-            OutputMode::Code(_code_type, content) => row![]
-                .push(
-                    button_icon(iced_fonts::Bootstrap::Clipboard)
-                        .on_press(Message::CopyClipboard(self.raw_content.clone())),
-                )
+            OutputMode::Table(table) => table.view(),
+            // shown as written, set apart from the text
+            OutputMode::Math(math) => container(text(math).font(iced::Font::MONOSPACE))
+                .padding(10.0)
+                .center_x(Length::Fill)
+                .style(container::rounded_box)
+                .into(),
+            OutputMode::Code(code_type, content) => column![]
                 .push(
-                    iced::widget::TextEditor::new(content)
-                        .style(|theme, style| {
-                            let mut style = iced::widget::text_editor::default(theme, style);
-                            style.background =
-                                iced::Background::Color(iced::Color::from_rgb8(0, 0, 0));
-                            style
-                        })
-                        .highlight(_code_type, iced::highlighter::Theme::InspiredGitHub)
-                        .font(iced::Font::MONOSPACE),
+                    self.view_code_header(
+                        text(code_label(code_type))
+                            .size(12.0)
+                            .font(iced::Font::MONOSPACE),
+                    ),
                 )
-                .spacing(10.0)
+                .push(Self::view_code(code_type, content, code_theme))
+                .spacing(5.0)
                 .into(),
         }
     }
 }
 
+/// The language of a code block as named by the highlighter, from the info string after its
+/// opening fence. Languages unknown to the highlighter are kept as is, and shown as plain text
+fn normalize_language(info: &str) -> String {
+    // only the first word names the language, as in "python title=main.py" or "{.rust}"
+    let language = info
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .trim_start_matches('{')
+        .trim_start_matches('.')
+        .trim_end_matches(['}', ',', ':'])
+        .to_lowercase();
+    let alias = match language.as_str() {
+        "text" | "txt" | "plain" | "plaintext" => "",
+        "shell" | "sh" | "zsh" | "console" | "shell-session" => "bash",
+        "js" | "jsx" | "node" | "mjs" => "javascript",
+        "py" | "python3" | "py3" => "python",
+        "rs" => "rust",
+        "golang" => "go",
+        "yml" => "yaml",
+        "c++" | "cxx" | "hpp" => "cpp",
+        "c#" | "csharp" => "cs",
+        "rb" => "ruby",
+        "md" => "markdown",
+        "htm" | "xhtml" => "html",
+        "kt" => "kotlin",
+        "ps1" | "pwsh" => "powershell",
+        "tex" => "latex",
+        _ => return language,
+    };
+    alias.to_string()
+}
+
+/// Inline math (`$x^2$`) turned into code spans, so that it stands out from the text around.
+/// As in pandoc, a dollar opens math when followed by a non-space, and closes it when preceded
+/// by a non-space and not followed by a digit, which leaves prices alone
+fn inline_math_as_code(s: &str) -> Cow<'_, str> {
+    if !s.contains('$') {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(idx) = rest.find(['$', '`', '\\']) {
+        let (before, after) = rest.split_at(idx);
+        out.push_str(before);
+        let skip = match after.as_bytes()[0] {
+            // an escaped character, like `\$`, is kept as is
+            b'\\' => 1 + after[1..].chars().next().map_or(0, char::len_utf8),
+            // a code span is copied up to its end
+            b'`' => after[1..].find('`').map_or(after.len(), |end| end + 2),
+            _ => {
+                let delimiter = if after.starts_with("$$") { "$$" } else { "$" };
+                let body = &after[delimiter.len()..];
+                match math_end(body, delimiter) {
+                    Some(end) => {
+                        out.push('`');
+                        out.push_str(&body[..end]);
+                        out.push('`');
+                        rest = &body[end + delimiter.len()..];
+                        continue;
+                    }
+                    None => delimiter.len(),
+                }
+            }
+        };
+        out.push_str(&after[..skip]);
+        rest = &after[skip..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+// position of the dollar(s) closing the inline math starting `body`
+fn math_end(body: &str, delimiter: &str) -> Option<usize> {
+    if body.is_empty() || body.starts_with(char::is_whitespace) {
+        return None;
+    }
+    body.match_indices(delimiter)
+        .map(|(i, _)| i)
+        .find(|i| {
+            !body[..*i].ends_with(char::is_whitespace)
+                && !body[*i + delimiter.len()..].starts_with(|c: char| c.is_ascii_digit())
+        })
+        .filter(|i| *i > 0 && !body[..*i].contains('`'))
+}
+
+// the language of a code block, as given after its opening fence
+fn code_label(code_type: &str) -> &str {
+    if code_type.is_empty() {
+        "text"
+    } else {
+        code_type
+    }
+}
+
 #[derive(Clone)]
 pub struct MarkdownIncremental {
     context: MarkdownContext,
     buf: String,
     pos: usize,
+    // language of the code block being streamed, once part of it has been flushed
+    code_lang: Option<String>,
 }
 
+// past this size, the unparsed tail is flushed at a line boundary instead of waiting for the
+// end of the paragraph or code block, so that it doesn't get re-laid out as a whole on every token
+const UNPARSED_SOFT_CAP: usize = 2048;
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum MarkdownContext {
     Normal,
     Code,
+    /// inside a `$$ ... $$` math block
+    Math,
+    /// inside a `<think>...</think>` reasoning block
+    Think,
 }
 
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+const MATH_DELIMITER: &str = "$$";
+
 enum Content {
     Code(String),
+    Math(String),
     Normal(String),
+    Think(String),
 }
 
 enum ContentFound {
     NewParagraph(usize),
     CodeSyntax(usize),
+    MathStart(usize),
+    ThinkStart(usize),
+}
+
+impl ContentFound {
+    fn position(&self) -> usize {
+        match self {
+            ContentFound::NewParagraph(idx)
+            | ContentFound::CodeSyntax(idx)
+            | ContentFound::MathStart(idx)
+            | ContentFound::ThinkStart(idx) => *idx,
+        }
+    }
 }
 
 impl MarkdownIncremental {
@@ -393,6 +2471,7 @@ impl MarkdownIncremental {
             context: MarkdownContext::Normal,
             buf: String::new(),
             pos: 0,
+            code_lang: None,
         }
     }
 
@@ -403,47 +2482,592 @@ impl MarkdownIncremental {
     fn process_content(&mut self) -> Option<Content> {
         let remaining = &self.buf[self.pos..];
         match self.context {
-            MarkdownContext::Normal => match normal_next_chunk(remaining) {
-                None => None,
-                Some(ContentFound::NewParagraph(idx)) => {
-                    let s = &self.buf[self.pos..self.pos + idx];
-                    self.pos += idx + 2;
-                    Some(Content::Normal(s.to_string()))
+            MarkdownContext::Normal => {
+                match normal_next_chunk(remaining, self.buf[..self.pos].trim().is_empty()) {
+                    None => None,
+                    Some(ContentFound::NewParagraph(idx)) => {
+                        let s = &self.buf[self.pos..self.pos + idx];
+                        self.pos += idx + 2;
+                        Some(Content::Normal(s.to_string()))
+                    }
+                    Some(ContentFound::CodeSyntax(idx)) => {
+                        let s = &self.buf[self.pos..self.pos + idx];
+                        self.pos += idx + 3;
+                        self.context = MarkdownContext::Code;
+                        Some(Content::Normal(s.to_string()))
+                    }
+                    Some(ContentFound::MathStart(idx)) => {
+                        let s = &self.buf[self.pos..self.pos + idx];
+                        self.pos += idx + MATH_DELIMITER.len();
+                        self.context = MarkdownContext::Math;
+                        Some(Content::Normal(s.to_string()))
+                    }
+                    Some(ContentFound::ThinkStart(idx)) => {
+                        let s = &self.buf[self.pos..self.pos + idx];
+                        self.pos += idx + THINK_START.len();
+                        self.context = MarkdownContext::Think;
+                        Some(Content::Normal(s.to_string()))
+                    }
                 }
-                Some(ContentFound::CodeSyntax(idx)) => {
+            }
+            MarkdownContext::Math => match remaining.find(MATH_DELIMITER) {
+                None => None,
+                Some(idx) => {
                     let s = &self.buf[self.pos..self.pos + idx];
-                    self.pos += idx + 3;
-                    self.context = MarkdownContext::Code;
-                    Some(Content::Normal(s.to_string()))
+                    self.pos += idx + MATH_DELIMITER.len();
+                    self.context = MarkdownContext::Normal;
+                    Some(Content::Math(s.to_string()))
                 }
             },
+            MarkdownContext::Think => {
+                let end = remaining.find(THINK_END);
+                let paragraph = remaining.find("\n\n");
+                match (end, paragraph) {
+                    (Some(end), Some(paragraph)) if paragraph < end => {
+                        let s = &self.buf[self.pos..self.pos + paragraph];
+                        self.pos += paragraph + 2;
+                        Some(Content::Think(s.to_string()))
+                    }
+                    (Some(end), _) => {
+                        let s = &self.buf[self.pos..self.pos + end];
+                        self.pos += end + THINK_END.len();
+                        self.context = MarkdownContext::Normal;
+                        Some(Content::Think(s.to_string()))
+                    }
+                    (None, Some(paragraph)) => {
+                        let s = &self.buf[self.pos..self.pos + paragraph];
+                        self.pos += paragraph + 2;
+                        Some(Content::Think(s.to_string()))
+                    }
+                    (None, None) => None,
+                }
+            }
             MarkdownContext::Code => match remaining.find("```") {
                 None => None,
                 Some(idx) => {
                     let s = &self.buf[self.pos..self.pos + idx];
+                    let s = match self.code_lang.take() {
+                        None => s.to_string(),
+                        Some(lang) => format!("{}\n{}", lang, s),
+                    };
                     self.pos += idx + 3;
                     self.context = MarkdownContext::Normal;
-                    Some(Content::Code(s.to_string()))
+                    Some(Content::Code(s))
                 }
             },
         }
     }
+
+    /// Take the whole unparsed tail, in the current context. The context is kept, so that
+    /// the reply can still be continued
+    fn flush_tail(&mut self) -> Option<Content> {
+        let remaining = &self.buf[self.pos..];
+        if remaining.is_empty() {
+            return None;
+        }
+        let s = remaining.to_string();
+        self.pos = self.buf.len();
+        match self.context {
+            MarkdownContext::Normal => Some(Content::Normal(s)),
+            MarkdownContext::Math => Some(Content::Math(s)),
+            MarkdownContext::Think => Some(Content::Think(s)),
+            MarkdownContext::Code => match &self.code_lang {
+                Some(lang) => Some(Content::Code(format!("{}\n{}", lang, s))),
+                None => {
+                    let lang = s.split_once('\n').map(|(l, _)| l).unwrap_or("");
+                    self.code_lang = Some(lang.to_string());
+                    Some(Content::Code(s))
+                }
+            },
+        }
+    }
+
+    /// Cut an unparsed tail longer than `cap` at its last line (or word) boundary
+    fn flush_long_tail(&mut self, cap: usize) -> Option<Content> {
+        let remaining = &self.buf[self.pos..];
+        if remaining.len() <= cap {
+            return None;
+        }
+        match self.context {
+            MarkdownContext::Normal => {
+                let cut = markdown_cut(remaining)?;
+                let s = remaining[..cut].to_string();
+                self.pos += cut + 1;
+                Some(Content::Normal(s))
+            }
+            MarkdownContext::Think => {
+                let cut = markdown_cut(remaining)?;
+                let s = remaining[..cut].to_string();
+                self.pos += cut + 1;
+                Some(Content::Think(s))
+            }
+            MarkdownContext::Math => {
+                let cut = remaining.rfind('\n')?;
+                let s = remaining[..cut].to_string();
+                self.pos += cut + 1;
+                Some(Content::Math(s))
+            }
+            MarkdownContext::Code => {
+                let cut = remaining.rfind('\n')?;
+                let s = match &self.code_lang {
+                    Some(lang) => format!("{}\n{}", lang, &remaining[..cut]),
+                    None => {
+                        // first part of the block, starting with the language line
+                        let lang = remaining.split_once('\n').map(|(l, _)| l).unwrap_or("");
+                        self.code_lang = Some(lang.to_string());
+                        remaining[..cut].to_string()
+                    }
+                };
+                self.pos += cut + 1;
+                Some(Content::Code(s))
+            }
+        }
+    }
 }
 
-// find either a double newline or a triple backquote, whichever comes first
-fn normal_next_chunk(s: &str) -> Option<ContentFound> {
-    let z1 = s.find("```");
-    let z2 = s.find("\n\n");
-    match (z1, z2) {
-        (Some(z1), Some(z2)) => {
-            if z1 < z2 {
-                Some(ContentFound::CodeSyntax(z1))
-            } else {
-                Some(ContentFound::NewParagraph(z2))
+// where a long markdown tail can be cut: the last line break not followed by a line that may
+// continue a table, a list, a quote or an indented block, whose second half would no longer
+// parse as such. A tail without line breaks is cut at a word
+fn markdown_cut(s: &str) -> Option<usize> {
+    if !s.contains('\n') {
+        return s.rfind(' ');
+    }
+    s.match_indices('\n')
+        .map(|(idx, _)| idx)
+        .rev()
+        .find(|&idx| !may_continue_block(&s[idx + 1..]))
+}
+
+fn may_continue_block(rest: &str) -> bool {
+    let line = rest.split('\n').next().unwrap_or("");
+    let after_digits = line.trim_start_matches(|c: char| c.is_ascii_digit());
+    // an empty line may still be streaming
+    line.is_empty()
+        || line.starts_with([' ', '\t', '|', '-', '*', '+', '>', '='])
+        || (after_digits.len() < line.len()
+            && (after_digits.is_empty() || after_digits.starts_with(['.', ')'])))
+}
+
+// find either a double newline, a triple backquote, a math block or the start of a reasoning
+// block, whichever comes first. A reasoning block only opens the reply (`at_start` when nothing
+// but whitespace precedes `s`), a `<think>` further down is text mentioning the tag
+fn normal_next_chunk(s: &str, at_start: bool) -> Option<ContentFound> {
+    let think_start = at_start
+        .then(|| s.trim_start())
+        .filter(|rest| rest.starts_with(THINK_START))
+        .map(|rest| s.len() - rest.len());
+    [
+        s.find("```").map(ContentFound::CodeSyntax),
+        find_math_start(s).map(ContentFound::MathStart),
+        s.find("\n\n").map(ContentFound::NewParagraph),
+        think_start.map(ContentFound::ThinkStart),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(ContentFound::position)
+}
+
+// a math block starts with `$$` at the start of a line. Within a line, `$$x$$` is inline math
+// left to `inline_math_as_code`, and a lone `$$` (a price, a shell `echo $$`) stays text
+fn find_math_start(s: &str) -> Option<usize> {
+    s.match_indices(MATH_DELIMITER)
+        .map(|(idx, _)| idx)
+        .find(|&idx| {
+            let line_start = s[..idx].rfind('\n').map_or(0, |n| n + 1);
+            s[line_start..idx].trim().is_empty()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content: &str) -> api::ChatMessageResponse {
+        api::ChatMessageResponse {
+            model: "model".to_string(),
+            created_at: String::new(),
+            message: ollama_rs::generation::chat::ChatMessage::assistant(content.to_string()),
+            done: false,
+            final_data: None,
+        }
+    }
+
+    #[test]
+    fn stream_error_keeps_partial_reply() {
+        let template = ChatTemplate {
+            prompt: "Hello".to_string(),
+            ..ChatTemplate::default()
+        };
+        let mut chat = Chat::from_template("model".to_string(), &template);
+        chat.set_generating();
+        assert!(chat.is_generating());
+
+        let stream = api::ChatMessageResponseStream(Box::pin(futures::stream::iter([
+            Ok(response("Hello ")),
+            Ok(response("there")),
+            Err(()),
+            Ok(response(", never shown")),
+        ])));
+        // handled as the stream task of the application does, stopping at the first error
+        futures::executor::block_on(async {
+            let mut batches = std::pin::pin!(stream.batched());
+            while let Some(batch) = futures::StreamExt::next(&mut batches).await {
+                match batch {
+                    Ok(responses) => {
+                        chat.add_content(responses);
+                    }
+                    Err(()) => {
+                        chat.set_finish();
+                        chat.error = Some("interrupted".to_string());
+                        break;
+                    }
+                }
+            }
+        });
+
+        assert!(matches!(chat.state, ChatState::Prompting(_)));
+        assert_eq!(chat.error.as_deref(), Some("interrupted"));
+        let saved = chat.to_saved();
+        match saved.content.as_slice() {
+            [Party::Query(query), Party::Reply(reply)] => {
+                assert_eq!(query.text.trim_end(), "Hello");
+                assert_eq!(reply.content, "Hello there");
+            }
+            _ => panic!("expecting the query and its partial reply"),
+        }
+    }
+
+    // kind and source of every chunk of the reasoning and of the reply
+    fn chunks(output: &ChatOutput) -> Vec<(&'static str, String)> {
+        let kind = |chunk: &Chunk| match chunk.output_mode {
+            OutputMode::Text(_) => "text",
+            OutputMode::Tasks(_) => "tasks",
+            OutputMode::Table(_) => "table",
+            OutputMode::Code(_, _) => "code",
+            OutputMode::Math(_) => "math",
+        };
+        output
+            .reasoning
+            .iter()
+            .map(|chunk| ("reasoning", chunk.raw_content.to_string()))
+            .chain(
+                output
+                    .output
+                    .iter()
+                    .map(|chunk| (kind(chunk), chunk.raw_content.to_string())),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn streamed_in_random_chunks() {
+        let mut reply = "<think>\nFirst the plan.\n\nThen the answer.\n</think>\n".to_string();
+        for i in 0..200 {
+            reply.push_str(&format!(
+                "Paragraph {i} with **bold**, `code` and accents: é, ü, 日本.\n\n\
+                 - [ ] task {i}\n- [x] done\n\n\
+                 ```rust\nfn f{i}() -> u32 {{\n    {i}\n}}\n```\n\
+                 | a | b |\n|---|---|\n| {i} | x |\n\n\
+                 $$\nx^{i}\n$$\n"
+            ));
+        }
+
+        let mut once = ChatOutput::new();
+        once.add_content(&reply);
+        once.finalize();
+
+        // a small linear congruential generator, for chunks of 1 to 16 bytes
+        let mut seed = 0x2545_f491_u32;
+        let mut streamed = ChatOutput::new();
+        let mut rest = reply.as_str();
+        while !rest.is_empty() {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let mut len = ((seed >> 16) as usize % 16 + 1).min(rest.len());
+            while !rest.is_char_boundary(len) {
+                len += 1;
+            }
+            let (chunk, tail) = rest.split_at(len);
+            streamed.add_content(chunk);
+            rest = tail;
+        }
+        streamed.finalize();
+
+        assert_eq!(streamed.raw(), reply);
+        assert_eq!(chunks(&streamed), chunks(&once));
+        assert!(chunks(&once).len() > 1000);
+    }
+
+    #[test]
+    fn think_tag_mentioned_in_reply() {
+        let mut output = ChatOutput::new();
+        output.add_content("Models reason between `<think>` and `</think>`.\n\nDone.");
+        output.finalize();
+        assert_eq!(
+            chunks(&output),
+            [
+                (
+                    "text",
+                    "Models reason between `<think>` and `</think>`.".to_string()
+                ),
+                ("text", "Done.".to_string()),
+            ]
+        );
+
+        let mut output = ChatOutput::new();
+        output.add_content("\n<think>\nPlan.\n</think>\nAnswer with <think> in it.");
+        output.finalize();
+        assert_eq!(
+            chunks(&output),
+            [
+                ("reasoning", "\nPlan.\n".to_string()),
+                ("text", "\nAnswer with <think> in it.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn source_follows_stream_read_only() {
+        let mut output = ChatOutput::new();
+        output.add_content("# Title\n\nSome ");
+        output.toggle_source();
+        for token in ["streamed ", "text\n\n```rust\nfn main() {}\n", "```\n"] {
+            output.add_content(token);
+        }
+        output.source_action(text_editor::Action::SelectAll);
+        output.source_action(text_editor::Action::Edit(text_editor::Edit::Delete));
+        let source = &output.source;
+        assert_eq!(source.text().trim_end(), output.raw().trim_end());
+        assert_eq!(source.selection(), Some(output.raw()));
+    }
+
+    // a reply of 50KB without paragraph breaks keeps a short unparsed tail while streamed
+    #[test]
+    fn long_reply_tail_capped() {
+        let mut reply = String::new();
+        for i in 0.. {
+            if reply.len() >= 50 * 1024 {
+                break;
             }
+            reply.push_str(&format!(
+                "line {i} of a long reply, with *some* `inline` markup\n"
+            ));
+        }
+        let mut output = ChatOutput::new();
+        let mut longest_tail = 0;
+        for token in reply.as_bytes().chunks(4) {
+            output.add_content(std::str::from_utf8(token).unwrap());
+            longest_tail = longest_tail.max(output.unparsed().len());
+        }
+        assert!(longest_tail <= UNPARSED_SOFT_CAP + 4);
+        output.finalize();
+        assert_eq!(output.raw(), reply);
+    }
+
+    #[test]
+    fn long_table_kept_whole() {
+        let mut reply = "Results:\n\n| n | square |\n|---|---|\n".to_string();
+        for i in 0..400 {
+            reply.push_str(&format!("| {i} | {} |\n", i * i));
+        }
+        reply.push_str("\n1. first\n2. second\n");
+        assert!(reply.len() > 2 * UNPARSED_SOFT_CAP);
+        let mut output = ChatOutput::new();
+        for token in reply.as_bytes().chunks(4) {
+            output.add_content(std::str::from_utf8(token).unwrap());
+        }
+        output.finalize();
+        let kinds = chunks(&output)
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["text", "table", "text"]);
+    }
+
+    #[test]
+    fn preview_trailing_parse() {
+        let mut output = ChatOutput::new();
+        assert!(!output.add_content("Hello **wor"));
+        // throttled: a single trailing parse is asked for the skipped tokens
+        assert!(output.add_content("ld**, how"));
+        assert!(!output.add_content(" are you"));
+        let parsed = |output: &ChatOutput| output.preview.as_ref().map(|p| p.len);
+        assert_eq!(parsed(&output), Some("Hello **wor".len()));
+
+        output.refresh_preview();
+        assert_eq!(parsed(&output), Some(output.unparsed().len()));
+        // the next throttled token asks for a new trailing parse
+        assert!(output.add_content("?"));
+    }
+
+    #[test]
+    fn dollars_in_inline_code() {
+        let mut output = ChatOutput::new();
+        output.add_content("Run `echo $$` to get the PID.\n\n$$\nx^2\n$$\nDone, `$$` again.");
+        output.finalize();
+        let kinds = chunks(&output)
+            .into_iter()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, ["text", "math", "text"]);
+    }
+
+    #[test]
+    fn dollars_within_a_line() {
+        let mut output = ChatOutput::new();
+        output.add_content("The area is $$x^2$$ here.\n\nIt costs $$ a lot.\n\nStill text.");
+        output.finalize();
+        assert_eq!(
+            chunks(&output),
+            [
+                ("text", "The area is $$x^2$$ here.".to_string()),
+                ("text", "It costs $$ a lot.".to_string()),
+                ("text", "Still text.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn template_wraps_sent_prompt() {
+        let template = ChatTemplate {
+            wrap: "Translate: {prompt}".to_string(),
+            ..ChatTemplate::default()
+        };
+        let mut chat = Chat::from_template("model".to_string(), &template);
+        chat.set_draft("bonjour");
+        let query = chat.pending_query().expect("a prompt");
+        assert_eq!(query.text.trim_end(), "bonjour");
+        assert_eq!(query.sent.as_deref(), Some("Translate: bonjour\n"));
+        assert_eq!(
+            chat.to_saved().template.as_deref(),
+            Some("Translate: {prompt}")
+        );
+    }
+
+    #[test]
+    fn template_params_round_trip() {
+        let params = api::GenerationParams {
+            temperature: Some(0.2),
+            num_ctx: Some(8192),
+            ..api::GenerationParams::default()
+        };
+        let template = ChatTemplate {
+            name: "Precise".to_string(),
+            params: params.clone(),
+            ..ChatTemplate::default()
+        };
+        let chat = Chat::from_template("model".to_string(), &template);
+        let json = serde_json::to_string(&chat.to_saved()).unwrap();
+        let saved: SavedChat<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(saved.params, params);
+
+        // unset values fall back to the settings, set ones win over them
+        let defaults = api::GenerationParams {
+            temperature: Some(0.8),
+            top_p: Some(0.9),
+            ..api::GenerationParams::default()
+        };
+        let merged = Chat::from_saved(saved).generation_params(defaults);
+        assert_eq!(merged.temperature, Some(0.2));
+        assert_eq!(merged.top_p, Some(0.9));
+        assert_eq!(merged.num_ctx, Some(8192));
+    }
+
+    #[test]
+    fn token_counts_follow_changes() {
+        let template = ChatTemplate {
+            system: "You answer questions about the weather.".to_string(),
+            ..ChatTemplate::default()
+        };
+        let mut chat = Chat::from_template("model".to_string(), &template);
+        let system = chat.context_tokens;
+        assert!(system > 0);
+        assert_eq!(chat.prompt_tokens, 0);
+
+        chat.set_draft("Will it rain tomorrow in Paris?");
+        let prompt = chat.prompt_tokens;
+        assert!(prompt > 0);
+        chat.set_generating();
+        chat.add_content(vec![response("Probably, bring an umbrella.")]);
+        chat.set_finish();
+        assert!(chat.context_tokens > system + prompt);
+        assert_eq!(chat.prompt_tokens, 0);
+    }
+
+    #[test]
+    fn find_matches_chunks() {
+        let mut chat = Chat::from_template("model".to_string(), &ChatTemplate::default());
+        let _ = chat.open_find();
+        chat.set_draft("What is rust?");
+        chat.set_generating();
+        chat.add_content(vec![response(
+            "<think>\nRust, rust.\n</think>\nA language.\n\n```rust\nfn rust() {}\n```\n",
+        )]);
+        let _ = chat.find("RUST".to_string());
+        // the reply being generated is searched once finished
+        let found = |chat: &Chat| chat.find.as_ref().unwrap().matches.clone();
+        assert_eq!(found(&chat), []);
+        chat.set_finish();
+        // the reasoning is not searched, the code block is the second chunk of the reply
+        assert_eq!(found(&chat), [(0, None, 8..12), (1, Some(1), 3..7)]);
+        let _ = chat.find_step(false);
+        assert_eq!(chat.find.as_ref().unwrap().current, 1);
+        chat.edit_last_query();
+        assert_eq!(found(&chat), []);
+        chat.cancel_edit();
+        assert_eq!(found(&chat).len(), 2);
+    }
+
+    #[test]
+    fn template_reapplied() {
+        let mut template = ChatTemplate {
+            name: "Terse".to_string(),
+            system: "Be brief.".to_string(),
+            ..ChatTemplate::default()
+        };
+        let mut chat = Chat::from_template("model".to_string(), &template);
+        template.system = "Be very brief.".to_string();
+        template.wrap = "{prompt} (one line)".to_string();
+        template.params.temperature = Some(0.1);
+        chat.apply_template(&template);
+        let saved = chat.to_saved();
+        assert_eq!(saved.system.as_deref(), Some("Be very brief."));
+        assert_eq!(saved.template.as_deref(), Some("{prompt} (one line)"));
+        assert_eq!(saved.params.temperature, Some(0.1));
+    }
+
+    #[test]
+    fn language_tags() {
+        let cases = [
+            ("rust", "rust"),
+            ("JS", "javascript"),
+            ("sh", "bash"),
+            ("python title=main.py", "python"),
+            ("{.rust}", "rust"),
+            ("plaintext", ""),
+            ("", ""),
+            // unknown to the highlighter, kept as written
+            ("mermaid", "mermaid"),
+        ];
+        for (info, language) in cases {
+            assert_eq!(normalize_language(info), language, "for {:?}", info);
         }
-        (Some(z1), None) => Some(ContentFound::CodeSyntax(z1)),
-        (None, Some(z2)) => Some(ContentFound::NewParagraph(z2)),
-        (None, None) => None,
+    }
+
+    #[test]
+    fn task_list_mixed() {
+        let tasks = parse_task_list("- [ ] write\n- [x] test\n  * [X] nested\n- plain\n  more")
+            .expect("a task list");
+        let checked = tasks.iter().map(|t| t.checked).collect::<Vec<_>>();
+        assert_eq!(checked, [Some(false), Some(true), Some(true), None]);
+        let indents = tasks.iter().map(|t| t.indent).collect::<Vec<_>>();
+        assert_eq!(indents, [0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn task_list_fallback() {
+        // lists without any task, or text which isn't a list, are left to markdown
+        assert!(parse_task_list("- one\n- two\n1. three").is_none());
+        assert!(parse_task_list("some text\n- [ ] task").is_none());
+        assert!(parse_task_list("").is_none());
     }
 }
@@ -0,0 +1,143 @@
+// headless mode, printing the reply to a single prompt: `ThinkMate --prompt "..." [--model name]`
+// and `--demo`, answering with canned models and replies instead of the server
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use futures::StreamExt;
+
+use crate::{api, settings};
+
+pub struct CliArgs {
+    prompt: String,
+    model: Option<String>,
+}
+
+pub struct Args {
+    /// the arguments of the headless mode, or `None` to start the interface
+    pub headless: Option<CliArgs>,
+    pub demo: bool,
+}
+
+const USAGE: &str = "usage: ThinkMate [--demo] [--prompt <text|-> [--model <name>]]";
+
+pub fn parse_args(args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut prompt = None;
+    let mut model = None;
+    let mut demo = false;
+    // the interface ignores them, as the system may add its own like `-psn_…` on macOS
+    let mut unknown = None;
+    let mut args = args.skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "--prompt" | "--model" => args
+                .next()
+                .ok_or_else(|| format!("missing value for {}\n{}", arg, USAGE))?,
+            "--demo" => {
+                demo = true;
+                continue;
+            }
+            "--help" | "-h" => return Err(USAGE.to_string()),
+            _ => {
+                unknown.get_or_insert(arg);
+                continue;
+            }
+        };
+        if arg == "--prompt" {
+            prompt = Some(value);
+        } else {
+            model = Some(value);
+        }
+    }
+    let headless = match (prompt, model) {
+        (None, None) => None,
+        (None, Some(_)) => return Err(format!("--model needs a --prompt\n{}", USAGE)),
+        (Some(prompt), model) => Some(CliArgs { prompt, model }),
+    };
+    match unknown {
+        Some(arg) if headless.is_some() => Err(format!("unknown argument {}\n{}", arg, USAGE)),
+        _ => Ok(Args { headless, demo }),
+    }
+}
+
+/// Send the prompt to the server of the settings, and stream the reply to stdout
+pub fn run(config_dir: &Path, args: CliArgs, demo: bool) -> Result<(), String> {
+    let prompt = if args.prompt == "-" {
+        let mut prompt = String::new();
+        std::io::stdin()
+            .read_to_string(&mut prompt)
+            .map_err(|e| format!("cannot read the prompt: {}", e))?;
+        prompt
+    } else {
+        args.prompt
+    };
+    let mut settings = settings::read_settings(config_dir).unwrap_or_default();
+    settings.demo = demo;
+    let api = settings.ollama_config().instance();
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    runtime.block_on(async move {
+        let model = match args.model {
+            Some(model) => model,
+            None => api::get_model_lists(&api)
+                .await
+                .map_err(|api::ConnectionFailed(e)| format!("cannot reach ollama: {}", e))?
+                .first()
+                .map(|model| model.name().clone())
+                .ok_or("no model is available, use --model")?,
+        };
+        let prompt = api::Prompt {
+            text: prompt,
+            images: vec![],
+        };
+        let params = settings.generation_params(&model);
+        let mut stream = api::chat_stream(api, model, None, vec![], prompt, params)
+            .await?
+            .0;
+        let mut stdout = std::io::stdout();
+        while let Some(response) = stream.next().await {
+            let response =
+                response.map_err(|()| "the reply was interrupted by a failed read".to_string())?;
+            write!(stdout, "{}", response.message.content).map_err(|e| e.to_string())?;
+            stdout.flush().map_err(|e| e.to_string())?;
+        }
+        writeln!(stdout).map_err(|e| e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(
+            std::iter::once("ThinkMate")
+                .chain(args.iter().copied())
+                .map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn interface_args() {
+        let args = parse(&[]).unwrap();
+        assert!(args.headless.is_none());
+        assert!(!args.demo);
+        assert!(parse(&["--demo"]).unwrap().demo);
+        // added by macOS when started from the Finder
+        let args = parse(&["-psn_0_12345", "--demo"]).unwrap();
+        assert!(args.headless.is_none());
+        assert!(args.demo);
+    }
+
+    #[test]
+    fn headless_args() {
+        let args = parse(&["--prompt", "hi", "--model", "llama3.2"]).unwrap();
+        let headless = args.headless.unwrap();
+        assert_eq!(headless.prompt, "hi");
+        assert_eq!(headless.model.as_deref(), Some("llama3.2"));
+        assert!(parse(&["--prompt", "hi", "--verbose"]).is_err());
+        assert!(parse(&["--model", "llama3.2"]).is_err());
+        assert!(parse(&["--prompt"]).is_err());
+        assert!(parse(&["--help"]).is_err());
+    }
+}
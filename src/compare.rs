@@ -0,0 +1,289 @@
+use std::time::{Duration, SystemTime};
+
+use iced::{
+    widget::{button, column, container, pick_list, row, scrollable, text, text_editor},
+    Alignment, Element, Length,
+};
+
+use crate::{
+    api,
+    chat::ChatOutput,
+    helper::button_icon_text,
+    history::{strip_reasoning, Party, Query, Reply, ReplyInfo, SavedChat},
+    settings::Settings,
+    Message,
+};
+
+/// The prompt of a chat sent to two models side by side, to compare their replies.
+/// Nothing is saved unless a side is explicitly kept
+pub struct Comparison {
+    // the chat as it was before the prompt
+    chat: SavedChat<String>,
+    query: Query,
+    pub sides: [Side; 2],
+}
+
+pub struct Side {
+    pub model: Option<api::LocalModel>,
+    output: ChatOutput,
+    state: SideState,
+    start: SystemTime,
+    // metrics sent by the server with the last chunk of the reply
+    final_data: Option<api::ChatMessageFinalResponseData>,
+    // already kept as a new chat
+    saved: bool,
+}
+
+#[derive(Default)]
+enum SideState {
+    #[default]
+    Idle,
+    Generating,
+    Done(ReplyInfo),
+    Failed(String),
+}
+
+impl Side {
+    fn new(model: Option<api::LocalModel>) -> Self {
+        Self {
+            model,
+            output: ChatOutput::new(),
+            state: SideState::Idle,
+            start: SystemTime::now(),
+            final_data: None,
+            saved: false,
+        }
+    }
+
+    pub fn is_generating(&self) -> bool {
+        matches!(self.state, SideState::Generating)
+    }
+
+    fn view_status(&self) -> Element<'_, Message> {
+        match &self.state {
+            SideState::Idle => text("").into(),
+            SideState::Generating => iced_aw::Spinner::new().into(),
+            SideState::Done(info) => {
+                let seconds = info.duration_ms.unwrap_or(0) as f32 / 1000.0;
+                let status = match info.tokens_per_second() {
+                    Some(speed) => format!("{:.1} seconds, {:.1} tokens/s", seconds, speed),
+                    None => format!("{:.1} seconds", seconds),
+                };
+                text(status).size(12.0).style(text::secondary).into()
+            }
+            SideState::Failed(e) => text(e).size(12.0).style(text::danger).into(),
+        }
+    }
+}
+
+impl Comparison {
+    /// Compare the replies to `query`, sent after the content of `chat`. The model of the
+    /// chat is offered on the first side
+    pub fn new(chat: SavedChat<String>, query: Query, models: &[api::LocalModel]) -> Self {
+        let model = models.iter().find(|m| *m.name() == chat.model).cloned();
+        Self {
+            chat,
+            query,
+            sides: [Side::new(model), Side::new(None)],
+        }
+    }
+
+    pub fn is_generating(&self) -> bool {
+        self.sides.iter().any(Side::is_generating)
+    }
+
+    pub fn select_model(&mut self, side: usize, model: api::LocalModel) {
+        if let Some(side) = self.sides.get_mut(side).filter(|s| !s.is_generating()) {
+            *side = Side::new(Some(model));
+        }
+    }
+
+    fn history(&self) -> Vec<api::Exchange> {
+        self.chat
+            .content
+            .chunks(2)
+            .filter_map(|pair| match pair {
+                [Party::Query(query), Party::Reply(reply)] => Some(api::Exchange {
+                    query: query.prompt(),
+                    reply: strip_reasoning(&reply.content),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reset a side for a new generation, returning its model, the system prompt, the
+    /// history and the prompt to send
+    pub fn start(
+        &mut self,
+        side: usize,
+    ) -> Option<(String, Option<String>, Vec<api::Exchange>, api::Prompt)> {
+        let history = self.history();
+        let system = self.chat.system.clone();
+        let prompt = self.query.prompt();
+        let side = self.sides.get_mut(side)?;
+        let model = side.model.clone()?;
+        *side = Side::new(Some(model.clone()));
+        side.state = SideState::Generating;
+        Some((model.name().clone(), system, history, prompt))
+    }
+
+    /// The parameters of the compared chat, the unset ones taken from `defaults`
+    pub fn generation_params(&self, defaults: api::GenerationParams) -> api::GenerationParams {
+        self.chat.params.clone().or(defaults)
+    }
+
+    /// The side still waiting for its stream, if it is generating
+    pub fn generating_side(&mut self, side: usize) -> Option<&mut Side> {
+        self.sides.get_mut(side).filter(|s| s.is_generating())
+    }
+
+    /// Append streamed responses to a side, returning true when a trailing parse of its open
+    /// paragraph must be scheduled with `refresh_preview`
+    pub fn add_content(&mut self, side: usize, responses: Vec<api::ChatMessageResponse>) -> bool {
+        let Some(side) = self.generating_side(side) else {
+            return false;
+        };
+        let mut content = String::new();
+        for response in responses {
+            content.push_str(&response.message.content);
+            if response.final_data.is_some() {
+                side.final_data = response.final_data;
+            }
+        }
+        side.output.add_content(&content)
+    }
+
+    pub fn refresh_preview(&mut self, side: usize) {
+        if let Some(side) = self.generating_side(side) {
+            side.output.refresh_preview();
+        }
+    }
+
+    pub fn finish(&mut self, side: usize) {
+        if let Some(side) = self.generating_side(side) {
+            side.output.finalize();
+            let duration = SystemTime::now()
+                .duration_since(side.start)
+                .unwrap_or(Duration::ZERO);
+            let mut info = ReplyInfo {
+                duration_ms: Some(duration.as_millis() as u64),
+                model: side.model.as_ref().map(|m| m.name().clone()),
+                ..ReplyInfo::default()
+            };
+            if let Some(data) = side.final_data.take() {
+                info.prompt_tokens = Some(data.prompt_eval_count as u64);
+                info.tokens = Some(data.eval_count as u64);
+                info.eval_ms = Some(data.eval_duration / 1_000_000);
+            }
+            side.state = SideState::Done(info);
+        }
+    }
+
+    pub fn fail(&mut self, side: usize, error: String) {
+        if let Some(side) = self.generating_side(side) {
+            side.output.finalize();
+            side.state = SideState::Failed(error);
+        }
+    }
+
+    pub fn toggle_reasoning(&mut self, side: usize) {
+        if let Some(side) = self.sides.get_mut(side) {
+            side.output.toggle_reasoning();
+        }
+    }
+
+    pub fn toggle_code(&mut self, side: usize, chunk: usize, collapse_default: bool) {
+        if let Some(side) = self.sides.get_mut(side) {
+            side.output.toggle_code(chunk, collapse_default);
+        }
+    }
+
+    pub fn source_action(&mut self, side: usize, action: text_editor::Action) {
+        if let Some(side) = self.sides.get_mut(side) {
+            side.output.source_action(action);
+        }
+    }
+
+    /// The chat continued with the reply of a side, as a new chat
+    pub fn keep(&mut self, side: usize) -> Option<SavedChat<String>> {
+        let side = self.sides.get_mut(side)?;
+        let SideState::Done(info) = &side.state else {
+            return None;
+        };
+        let mut chat = self.chat.fork();
+        chat.model = side.model.as_ref()?.name().clone();
+        chat.content.push(Party::Query(self.query.clone()));
+        chat.content
+            .push(Party::Reply(Reply::new(side.output.raw(), info.clone())));
+        side.saved = true;
+        Some(chat)
+    }
+
+    fn view_side<'a>(
+        &'a self,
+        index: usize,
+        models: &'a [api::LocalModel],
+        settings: &Settings,
+    ) -> Element<'a, Message> {
+        let side = &self.sides[index];
+        let kept = matches!(side.state, SideState::Done(_)) && !side.saved;
+        let header = row![]
+            .push(
+                pick_list(models, side.model.as_ref(), move |model| {
+                    Message::CompareModelSelected(index, model)
+                })
+                .placeholder("Select Model"),
+            )
+            .push(side.view_status())
+            .push(iced::widget::horizontal_space())
+            .push(
+                button_icon_text(iced_fonts::Bootstrap::Save, "Save this side")
+                    .style(button::secondary)
+                    .on_press_maybe(kept.then_some(Message::CompareSave(index))),
+            )
+            .spacing(10)
+            .align_y(Alignment::Center);
+        let output = side.output.view(
+            Message::CompareToggleReasoning(index),
+            move |chunk| Message::CompareToggleCode(index, chunk),
+            move |action| Message::CompareSourceAction(index, action),
+            vec![],
+            settings,
+        );
+        column![]
+            .push(header)
+            .push(scrollable(output.padding(5)).height(Length::Fill))
+            .spacing(10)
+            .width(Length::FillPortion(1))
+            .into()
+    }
+
+    pub fn view<'a>(
+        &'a self,
+        models: &'a [api::LocalModel],
+        settings: &Settings,
+    ) -> Element<'a, Message> {
+        let ready = !self.is_generating() && self.sides.iter().all(|s| s.model.is_some());
+        column![]
+            .push(
+                container(text(&self.query.text))
+                    .padding(5)
+                    .width(Length::Fill)
+                    .style(container::bordered_box),
+            )
+            .push(
+                button_icon_text(iced_fonts::Bootstrap::PlayFill, "Compare")
+                    .on_press_maybe(ready.then_some(Message::CompareRun)),
+            )
+            .push(
+                row![]
+                    .push(self.view_side(0, models, settings))
+                    .push(iced::widget::vertical_rule(1))
+                    .push(self.view_side(1, models, settings))
+                    .spacing(10),
+            )
+            .spacing(10)
+            .into()
+    }
+}
@@ -47,6 +47,72 @@ pub fn dialog<'a, M: 'a + Clone>(
             .center_y(Length::Fill)
             .center_x(Length::Fill)
     );
-    let inner = container(dialog_content).style(|t| container::bordered_box(t));
+    let inner = container(dialog_content).style(container::bordered_box);
     container(inner).padding(Padding::from([40, 60]))
 }
+
+pub fn banner<'a, M: 'a + Clone>(message: &'a str, on_close: M) -> Container<'a, M> {
+    banner_with(
+        iced_fonts::Bootstrap::ExclamationTriangleFill,
+        message,
+        on_close,
+        |palette| palette.danger.weak,
+    )
+}
+
+pub fn notice<'a, M: 'a + Clone>(message: &'a str, on_close: M) -> Container<'a, M> {
+    banner_with(
+        iced_fonts::Bootstrap::InfoCircleFill,
+        message,
+        on_close,
+        |palette| palette.primary.weak,
+    )
+}
+
+fn banner_with<'a, M: 'a + Clone>(
+    icon: iced_fonts::Bootstrap,
+    message: &'a str,
+    on_close: M,
+    pair: fn(&iced::theme::palette::Extended) -> iced::theme::palette::Pair,
+) -> Container<'a, M> {
+    container(
+        row![
+            icon_to_text(icon),
+            text(message).width(Length::Fill),
+            button_icon_small(iced_fonts::Bootstrap::X)
+                .style(button::danger)
+                .on_press(on_close)
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center),
+    )
+    .padding(5)
+    .width(Length::Fill)
+    .style(move |theme: &iced::Theme| {
+        let pair = pair(theme.extended_palette());
+        container::Style {
+            background: Some(iced::Background::Color(pair.color)),
+            text_color: Some(pair.text),
+            ..container::Style::default()
+        }
+    })
+}
+
+/// A round badge with an icon, telling who wrote a message
+pub fn role_icon<'a, M: 'a>(
+    icon: iced_fonts::Bootstrap,
+    pair: fn(&iced::theme::palette::Extended) -> iced::theme::palette::Pair,
+) -> Container<'a, M> {
+    container(icon_to_text(icon).size(14.0))
+        .center_x(26.0)
+        .center_y(26.0)
+        .style(move |theme: &iced::Theme| {
+            let pair = pair(theme.extended_palette());
+            container::Style {
+                background: Some(iced::Background::Color(pair.color)),
+                text_color: Some(pair.text),
+                border: iced::border::rounded(13),
+                ..container::Style::default()
+            }
+        })
+}
@@ -1,83 +1,604 @@
-use std::path::{Path, PathBuf};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
 
+use chrono::{DateTime, Local, NaiveDateTime};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
 use ulid::Ulid;
 
-use crate::chat::ChatOutput;
+use crate::{api, chat::ChatOutput, persist};
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SavedChat<T> {
     pub ulid: Ulid,
     pub model: String,
     pub content: Vec<Party<T>>,
+    /// Template wrapping every prompt of this chat, `{prompt}` being replaced by the user input
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Title generated by the title model, the first query being used otherwise
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Name of the template the chat was created from
+    #[serde(default)]
+    pub template_name: Option<String>,
+    /// Generation parameters of the chat, over the ones of the settings
+    #[serde(default, skip_serializing_if = "api::GenerationParams::is_unset")]
+    pub params: api::GenerationParams,
+    /// shown at the top of the sidebar
+    #[serde(default)]
+    pub pinned: bool,
+    /// labels grouping chats together, to filter the sidebar
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Party<T> {
-    Query(String),
-    Reply(T),
+    Query(Query),
+    Reply(Reply<T>),
+}
+
+/// A model turn: the reply content and what is known about its generation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(
+    from = "ReplySerialized<T>",
+    bound(deserialize = "T: Deserialize<'de>")
+)]
+pub struct Reply<T> {
+    pub content: T,
+    pub info: ReplyInfo,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplyInfo {
+    /// time between sending the prompt and the end of the reply
+    pub duration_ms: Option<u64>,
+    /// number of tokens of the prompt, as reported by the server
+    pub prompt_tokens: Option<u64>,
+    /// number of tokens generated, as reported by the server
+    pub tokens: Option<u64>,
+    /// time spent by the server generating the tokens
+    pub eval_ms: Option<u64>,
+    /// model which generated the reply
+    pub model: Option<String>,
+}
+
+impl ReplyInfo {
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        match (self.tokens, self.eval_ms) {
+            (Some(tokens), Some(eval_ms)) if eval_ms > 0 => {
+                Some(tokens as f64 * 1000.0 / eval_ms as f64)
+            }
+            _ => None,
+        }
+    }
+}
+
+// older histories stored the reply content only
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ReplySerialized<T> {
+    Content(T),
+    Full {
+        content: T,
+        #[serde(default)]
+        info: ReplyInfo,
+    },
+}
+
+impl<T> From<ReplySerialized<T>> for Reply<T> {
+    fn from(r: ReplySerialized<T>) -> Self {
+        match r {
+            ReplySerialized::Content(content) => Reply {
+                content,
+                info: ReplyInfo::default(),
+            },
+            ReplySerialized::Full { content, info } => Reply { content, info },
+        }
+    }
+}
+
+impl<T> Reply<T> {
+    pub fn new(content: T, info: ReplyInfo) -> Self {
+        Self { content, info }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Reply<U> {
+        Reply {
+            content: f(self.content),
+            info: self.info,
+        }
+    }
+}
+
+/// A user turn: what was typed, and what was effectively sent to the model
+/// when it differs (e.g. after applying a template)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "QuerySerialized")]
+pub struct Query {
+    pub text: String,
+    pub sent: Option<String>,
+    /// images attached to the query, base64 encoded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+// older histories stored the query as a plain string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QuerySerialized {
+    Text(String),
+    Full {
+        text: String,
+        #[serde(default)]
+        sent: Option<String>,
+        #[serde(default)]
+        images: Vec<String>,
+    },
+}
+
+impl From<QuerySerialized> for Query {
+    fn from(q: QuerySerialized) -> Self {
+        match q {
+            QuerySerialized::Text(text) => Query {
+                text,
+                sent: None,
+                images: vec![],
+            },
+            QuerySerialized::Full { text, sent, images } => Query { text, sent, images },
+        }
+    }
+}
+
+impl Query {
+    pub fn new(text: String, sent: String, images: Vec<String>) -> Self {
+        let sent = (sent != text).then_some(sent);
+        Self { text, sent, images }
+    }
+
+    /// The prompt as received by the model
+    pub fn sent(&self) -> &str {
+        self.sent.as_deref().unwrap_or(&self.text)
+    }
+
+    pub fn prompt(&self) -> api::Prompt {
+        api::Prompt {
+            text: self.sent().to_string(),
+            images: self.images.clone(),
+        }
+    }
 }
 
 const HISTORY_FILE_NAME: &str = "history.json";
 
 pub fn read_history(path: &Path) -> Vec<SavedChat<String>> {
     let path = path.to_path_buf().join(HISTORY_FILE_NAME);
+    persist::recover(&path, |content| {
+        serde_json::from_slice::<Vec<SavedChat<String>>>(content).is_ok()
+    });
 
-    let Ok(file) = std::fs::File::open(&path) else {
+    let Ok(content) = std::fs::read(&path) else {
         return vec![];
     };
 
-    let Ok(v) = serde_json::from_reader(&file) else {
-        return vec![];
+    let mut v = match serde_json::from_slice::<Vec<SavedChat<String>>>(&content) {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::error!("cannot read {}: {}", path.display(), e);
+            // the next write replaces the file, keep the original around
+            backup(&path);
+            parse_lenient(&content)
+        }
     };
 
+    // replies saved before the model was recorded come from the model of the chat
+    for chat in &mut v {
+        for party in &mut chat.content {
+            if let Party::Reply(reply) = party {
+                reply.info.model.get_or_insert_with(|| chat.model.clone());
+            }
+        }
+    }
+
     v
 }
 
+/// Parse the chats one by one, skipping the ones that are invalid
+fn parse_lenient(content: &[u8]) -> Vec<SavedChat<String>> {
+    let Ok(values) = serde_json::from_slice::<Vec<serde_json::Value>>(content) else {
+        tracing::error!("history is not a list of chats, starting with an empty history");
+        return vec![];
+    };
+    let total = values.len();
+    let chats = values
+        .into_iter()
+        .filter_map(|value| serde_json::from_value::<SavedChat<String>>(value).ok())
+        .collect::<Vec<_>>();
+    tracing::warn!(
+        "dropped {} invalid chats out of {} from the history",
+        total - chats.len(),
+        total
+    );
+    chats
+}
+
+fn backup(path: &Path) {
+    let date = Local::now().format("%Y%m%d-%H%M%S");
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.bak", date));
+    let backup_path = path.with_file_name(name);
+    match std::fs::copy(path, &backup_path) {
+        Ok(_) => tracing::info!("history backed up to {}", backup_path.display()),
+        Err(e) => tracing::error!("cannot back up history to {}: {}", backup_path.display(), e),
+    }
+}
+
 pub fn serialize_history(chats: &[SavedChat<String>]) -> String {
     serde_json::to_string_pretty(chats).unwrap()
 }
 
 pub async fn write_history(path: PathBuf, chats: String) -> std::io::Result<()> {
-    let path = path.join(HISTORY_FILE_NAME);
-    let tmp_path = path.clone().with_extension(".json.tmp");
+    persist::write_atomic(path.join(HISTORY_FILE_NAME), chats.as_bytes()).await
+}
+
+/// Remove the `<think>...</think>` reasoning block opening a reply. A tag further down is
+/// part of the answer, and an unterminated block is kept rather than dropping what follows it
+pub fn strip_reasoning(s: &str) -> String {
+    let answer = s
+        .trim_start()
+        .strip_prefix("<think>")
+        .and_then(|reasoning| reasoning.split_once("</think>"));
+    match answer {
+        Some((_, answer)) => answer.trim_start().to_string(),
+        None => s.to_string(),
+    }
+}
 
-    let mut file = tokio::fs::File::create(&tmp_path).await?;
-    file.write_all(chats.as_bytes()).await?;
-    std::fs::rename(tmp_path, path)?;
-    Ok(())
+const TITLE_WORDS: usize = 5;
+
+/// Prompt asking the title model to name a chat from its first exchange
+pub fn title_prompt(query: &str, reply: &str) -> String {
+    format!(
+        "Give a title of at most {} words to the following conversation. \
+         Answer with the title only, without quotes or punctuation.\n\n\
+         User: {}\n\nAssistant: {}",
+        TITLE_WORDS,
+        query,
+        strip_reasoning(reply)
+    )
+}
+
+/// Keep the first line of the title model answer, without quotes and extra words
+pub fn clean_title(answer: &str) -> Option<String> {
+    let answer = strip_reasoning(answer);
+    let line = answer.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let title = line
+        .trim_matches(|c: char| c == '"' || c == '\'' || c == '*' || c == '#')
+        .split_whitespace()
+        .take(TITLE_WORDS)
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+/// A markdown document of the chat, with the user turns quoted
+pub fn to_markdown(chat: &SavedChat<String>, exclude_reasoning: bool) -> String {
+    let date: DateTime<Local> = chat.ulid.datetime().into();
+    let title = chat.description();
+    let mut out = format!(
+        "# {}\n\n- Model: {}\n- Date: {}\n",
+        if title.is_empty() { "Chat" } else { &title },
+        chat.model,
+        date.format("%Y-%m-%d %H:%M:%S")
+    );
+    if let Some(system) = &chat.system {
+        // its next lines are indented, continuing the item
+        out.push_str("- System prompt:");
+        for (i, line) in system.lines().enumerate() {
+            match (i, line.is_empty()) {
+                (_, true) => out.push('\n'),
+                (0, false) => out.push_str(&format!(" {}\n", line)),
+                (_, false) => out.push_str(&format!("  {}\n", line)),
+            }
+        }
+    }
+    for party in &chat.content {
+        match party {
+            Party::Query(query) => {
+                out.push_str("\n## User\n\n");
+                for line in query.text.lines() {
+                    if line.is_empty() {
+                        out.push_str(">\n");
+                    } else {
+                        out.push_str(&format!("> {}\n", line));
+                    }
+                }
+            }
+            Party::Reply(reply) => {
+                let content = if exclude_reasoning {
+                    strip_reasoning(&reply.content)
+                } else {
+                    reply.content.clone()
+                };
+                out.push_str("\n## Assistant\n\n");
+                for line in content.trim_end().lines() {
+                    out.push_str(&escape_role_heading(line));
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Read back a chat exported with [`to_markdown`], or a single [`SavedChat`] in JSON
+pub fn parse_import(content: &str) -> Result<SavedChat<String>, String> {
+    if content.trim_start().starts_with('{') {
+        let mut chat: SavedChat<String> =
+            serde_json::from_str(content).map_err(|e| format!("invalid chat JSON: {}", e))?;
+        for party in &mut chat.content {
+            if let Party::Reply(reply) = party {
+                reply.info.model.get_or_insert_with(|| chat.model.clone());
+            }
+        }
+        return Ok(chat);
+    }
+    from_markdown(content)
+}
+
+fn from_markdown(content: &str) -> Result<SavedChat<String>, String> {
+    let mut lines = content.lines().peekable();
+    let title = lines
+        .next()
+        .and_then(|line| line.strip_prefix("# "))
+        .ok_or("not an exported chat: missing the title line")?
+        .trim();
+    let mut model = None;
+    let mut date = None;
+    let mut system = None;
+    while let Some(line) = lines.next_if(|line| !line.starts_with("## ")) {
+        if let Some(value) = line.strip_prefix("- Model: ") {
+            model = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("- Date: ") {
+            date = Some(value.trim());
+        } else if let Some(value) = line.strip_prefix("- System prompt:") {
+            let mut prompt = vec![value.strip_prefix(' ').unwrap_or(value)];
+            while let Some(line) = lines.next_if(|line| line.is_empty() || line.starts_with("  ")) {
+                prompt.push(line.strip_prefix("  ").unwrap_or(line));
+            }
+            system = Some(prompt.join("\n").trim_end().to_string());
+        }
+    }
+    let model = model.ok_or("not an exported chat: missing the model")?;
+
+    let mut content = Vec::new();
+    while let Some(heading) = lines.next() {
+        let body =
+            std::iter::from_fn(|| lines.next_if(|line| !is_role_heading(line))).collect::<Vec<_>>();
+        match heading {
+            "## User" => {
+                let text = body
+                    .iter()
+                    .filter_map(|line| line.strip_prefix('>'))
+                    .map(|line| line.strip_prefix(' ').unwrap_or(line))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                content.push(Party::Query(Query::new(text.clone(), text, vec![])));
+            }
+            "## Assistant" => {
+                let text = body
+                    .iter()
+                    .map(|line| unescape_role_heading(line))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+                    .trim()
+                    .to_string();
+                let info = ReplyInfo {
+                    model: Some(model.clone()),
+                    ..ReplyInfo::default()
+                };
+                content.push(Party::Reply(Reply::new(text, info)));
+            }
+            _ => return Err(format!("unexpected line: {}", heading)),
+        }
+    }
+    if content.is_empty() {
+        return Err("the chat has no message".to_string());
+    }
+
+    // the creation time is kept, so that the chat is listed at its place
+    let ulid = date
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").ok())
+        .and_then(|date| date.and_local_timezone(Local).single())
+        .map(|date| Ulid::from_datetime(date.into()))
+        .unwrap_or_else(Ulid::new);
+    let mut chat = SavedChat {
+        ulid,
+        model,
+        content,
+        template: None,
+        system,
+        title: None,
+        template_name: None,
+        params: api::GenerationParams::default(),
+        pinned: false,
+        tags: vec![],
+    };
+    // the export falls back to the beginning of the first query, or "Chat", without a title
+    if title != "Chat" && title != chat.description() {
+        chat.title = Some(title.to_string());
+    }
+    Ok(chat)
+}
+
+fn is_role_heading(line: &str) -> bool {
+    line == "## User" || line == "## Assistant"
+}
+
+// a reply line reading like a role heading gets a backslash, so that markdown shows it as text
+// and the import doesn't take it for a heading. Lines already escaped get one more
+fn escape_role_heading(line: &str) -> Cow<'_, str> {
+    if is_role_heading(line.trim_start_matches('\\')) {
+        Cow::Owned(format!("\\{}", line))
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+fn unescape_role_heading(line: &str) -> &str {
+    match line.strip_prefix('\\') {
+        Some(rest) if is_role_heading(rest.trim_start_matches('\\')) => rest,
+        _ => line,
+    }
+}
+
+/// A file name for the export of the chat, made from its date and title
+pub fn export_file_name(chat: &SavedChat<String>) -> String {
+    let date: DateTime<Local> = chat.ulid.datetime().into();
+    let title = chat
+        .description()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>();
+    let title = title
+        .split('-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if title.is_empty() {
+        format!("chat-{}.md", date.format("%Y%m%d-%H%M%S"))
+    } else {
+        format!("chat-{}-{}.md", date.format("%Y%m%d-%H%M%S"), title)
+    }
+}
+
+/// A chat matching a full-text search, with the text around the first match
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub ulid: Ulid,
+    pub description: String,
+    pub snippet: String,
+}
+
+const SNIPPET_CONTEXT: usize = 30;
+
+/// The text around the first case-insensitive match of `query`, which must be lowercase
+fn snippet_around(text: &str, query: &str) -> Option<String> {
+    if query.is_empty() {
+        return None;
+    }
+    // lowercasing can change byte lengths outside of ascii, so every lowered byte keeps the
+    // offset in `text` of the character it comes from
+    let mut lowered = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+    for (i, c) in text.char_indices() {
+        lowered.extend(c.to_lowercase());
+        origins.resize(lowered.len(), i);
+    }
+    let found = lowered.find(query)?;
+    let pos = origins[found];
+    let last = origins[found + query.len() - 1];
+    let match_end = last + text[last..].chars().next().map_or(0, char::len_utf8);
+    let start = text[..pos]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(0, |(i, _)| i);
+    let end = text[match_end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(text.len(), |(i, _)| match_end + i);
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.push_str(&text[start..end].replace('\n', " "));
+    if end < text.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+/// Case-insensitive search of the query in the title and every message of every chat
+pub fn search(chats: &[SavedChat<String>], query: &str) -> Vec<SearchHit> {
+    let query = query.to_lowercase();
+    chats
+        .iter()
+        .rev()
+        .filter_map(|chat| {
+            let description = chat.description();
+            std::iter::once(description.as_str())
+                .chain(chat.content.iter().map(|party| match party {
+                    Party::Query(q) => q.text.as_str(),
+                    Party::Reply(r) => r.content.as_str(),
+                }))
+                .find_map(|text| snippet_around(text, &query))
+                .map(|snippet| SearchHit {
+                    ulid: chat.ulid,
+                    description: description.clone(),
+                    snippet,
+                })
+        })
+        .collect()
+}
+
+impl<T> SavedChat<T> {
+    /// The title of the chat, or the beginning of its first query
+    pub fn description(&self) -> String {
+        if let Some(title) = &self.title {
+            title.clone()
+        } else if self.content.is_empty() {
+            String::new()
+        } else {
+            match &self.content[0] {
+                Party::Query(p) => p.text.chars().take(40).collect::<String>(),
+                Party::Reply(_) => String::new(),
+            }
+        }
+    }
 }
 
 impl SavedChat<String> {
-    pub fn to_chat_output(self) -> SavedChat<ChatOutput> {
+    /// A copy of the chat under a new id, to continue the conversation differently
+    pub fn fork(&self) -> SavedChat<String> {
+        SavedChat {
+            ulid: Ulid::new(),
+            title: self.title.as_ref().map(|title| format!("{} (fork)", title)),
+            pinned: false,
+            ..self.clone()
+        }
+    }
+
+    pub fn into_chat_output(self) -> SavedChat<ChatOutput> {
         let content = self
             .content
             .into_iter()
             .map(|p| match p {
                 Party::Query(q) => Party::Query(q),
-                Party::Reply(s) => {
+                Party::Reply(r) => Party::Reply(r.map(|s| {
                     let mut chat_output = ChatOutput::new();
                     chat_output.add_content(&s);
-                    Party::Reply(chat_output)
-                }
+                    chat_output.finalize();
+                    chat_output
+                })),
             })
             .collect::<Vec<_>>();
         SavedChat {
             ulid: self.ulid,
             model: self.model,
             content,
-        }
-    }
-
-    pub fn description(&self) -> String {
-        if self.content.is_empty() {
-            String::new()
-        } else {
-            match &self.content[0] {
-                Party::Query(p) => p.chars().take(40).collect::<String>(),
-                Party::Reply(_) => String::new(),
-            }
+            template: self.template,
+            system: self.system,
+            title: self.title,
+            template_name: self.template_name,
+            params: self.params,
+            pinned: self.pinned,
+            tags: self.tags,
         }
     }
 }
@@ -89,13 +610,99 @@ impl SavedChat<ChatOutput> {
             .into_iter()
             .map(|p| match p {
                 Party::Query(q) => Party::Query(q),
-                Party::Reply(s) => Party::Reply(s.raw()),
+                Party::Reply(r) => Party::Reply(r.map(|o| o.raw())),
             })
             .collect::<Vec<_>>();
         SavedChat {
             ulid: self.ulid,
             model: self.model,
             content,
+            template: self.template,
+            system: self.system,
+            title: self.title,
+            template_name: self.template_name,
+            params: self.params,
+            pinned: self.pinned,
+            tags: self.tags,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_round_trip() {
+        let reply = "A transcript reads like this:\n\n## User\n\n> hi\n\n## Assistant\n\\## User";
+        let chat = SavedChat {
+            ulid: Ulid::new(),
+            model: "llama3.2".to_string(),
+            content: vec![
+                Party::Query(Query::new(
+                    "How are chats exported?".to_string(),
+                    "How are chats exported?".to_string(),
+                    vec![],
+                )),
+                Party::Reply(Reply::new(
+                    reply.to_string(),
+                    ReplyInfo {
+                        model: Some("llama3.2".to_string()),
+                        ..ReplyInfo::default()
+                    },
+                )),
+            ],
+            template: None,
+            system: Some("Be brief.\n\n  Answer in English.".to_string()),
+            title: Some("Export".to_string()),
+            template_name: None,
+            params: api::GenerationParams::default(),
+            pinned: false,
+            tags: vec![],
+        };
+        let imported = parse_import(&to_markdown(&chat, false)).unwrap();
+        assert_eq!(imported.system, chat.system);
+        assert_eq!(imported.content.len(), 2);
+        match &imported.content[1] {
+            Party::Reply(imported) => assert_eq!(imported.content, reply),
+            _ => panic!("expecting the reply"),
+        }
+    }
+
+    #[test]
+    fn reasoning_stripped_at_start_only() {
+        assert_eq!(
+            strip_reasoning("<think>\nPlan.\n</think>\n\nAnswer."),
+            "Answer."
+        );
+        assert_eq!(
+            strip_reasoning("Use `<think>` and `</think>` around it."),
+            "Use `<think>` and `</think>` around it."
+        );
+        assert_eq!(
+            strip_reasoning("<think>a</think>Tags like <think>b</think> stay."),
+            "Tags like <think>b</think> stay."
+        );
+        assert_eq!(
+            strip_reasoning("<think>\nStill thinking"),
+            "<think>\nStill thinking"
+        );
+    }
+
+    #[test]
+    fn snippet_with_unicode_case() {
+        // lowercasing `İ` takes more bytes, shifting the positions of the lowered text
+        assert_eq!(snippet_around("ſİ", "i").as_deref(), Some("ſİ"));
+        assert_eq!(
+            snippet_around("İstanbul is NOT Constantinople", "not").as_deref(),
+            Some("İstanbul is NOT Constantinople")
+        );
+        let long = format!("{}Needle{}", "é".repeat(40), "ü".repeat(40));
+        let snippet = snippet_around(&long, "needle").unwrap();
+        assert_eq!(
+            snippet,
+            format!("…{}Needle{}…", "é".repeat(31), "ü".repeat(30))
+        );
+        assert_eq!(snippet_around("nothing here", "needle"), None);
+    }
+}
@@ -2,19 +2,45 @@ use iced::{
     advanced::{
         layout::{Limits, Node},
         renderer,
-        widget::Tree,
+        widget::{tree, Tree},
         Clipboard, Layout, Shell, Widget,
     },
     event::Status,
     mouse::Cursor,
+    time::{Duration, Instant},
     window, Border, Color, Element, Event, Length, Rectangle, Shadow, Size, Vector,
 };
 
+const PULSE_PERIOD: Duration = Duration::from_millis(1200);
+
 pub struct Indicator {
     width: Length,
     height: Length,
     circle_radius: f32,
     color: Color,
+    pulsing: bool,
+}
+
+// time of the first frame and of the current one, to animate the pulse
+struct State {
+    start: Instant,
+    now: Instant,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self { start: now, now }
+    }
+}
+
+impl State {
+    /// Between 0 and 1, following the pulse
+    fn pulse(&self) -> f32 {
+        let elapsed = self.now.saturating_duration_since(self.start);
+        let phase = elapsed.as_secs_f32() / PULSE_PERIOD.as_secs_f32();
+        0.5 + 0.5 * (phase * std::f32::consts::TAU).cos()
+    }
 }
 
 impl Default for Indicator {
@@ -24,6 +50,7 @@ impl Default for Indicator {
             height: Length::Fixed(20.0),
             circle_radius: 2.0,
             color: Color::WHITE,
+            pulsing: false,
         }
     }
 }
@@ -57,6 +84,13 @@ impl Indicator {
         self.color = color;
         self
     }
+
+    /// Make the circle grow and fade back and forth, instead of being drawn steady
+    #[must_use]
+    pub fn pulsing(mut self, pulsing: bool) -> Self {
+        self.pulsing = pulsing;
+        self
+    }
 }
 
 fn is_visible(bounds: &Rectangle) -> bool {
@@ -94,6 +128,14 @@ impl<Message, Theme, Renderer> Widget<Message, Theme, Renderer> for Indicator
 where
     Renderer: renderer::Renderer,
 {
+    fn tag(&self) -> tree::Tag {
+        tree::Tag::of::<State>()
+    }
+
+    fn state(&self) -> tree::State {
+        tree::State::new(State::default())
+    }
+
     fn size(&self) -> Size<Length> {
         Size::new(self.width, self.height)
     }
@@ -108,7 +150,7 @@ where
 
     fn draw(
         &self,
-        _state: &Tree,
+        state: &Tree,
         renderer: &mut Renderer,
         _theme: &Theme,
         _style: &renderer::Style,
@@ -134,24 +176,41 @@ where
             center.y + distance_from_center - self.circle_radius,
         );
 
-        fill_circle(renderer, position, self.circle_radius, self.color);
+        if self.pulsing {
+            let pulse = state.state.downcast_ref::<State>().pulse();
+            let radius = self.circle_radius * (0.6 + 0.4 * pulse);
+            // keep the circle centered on the same point while it shrinks
+            let shift = self.circle_radius - radius;
+            let position = position + Vector::new(shift, shift);
+            let color = Color {
+                a: self.color.a * (0.4 + 0.6 * pulse),
+                ..self.color
+            };
+            fill_circle(renderer, position, radius, color);
+        } else {
+            fill_circle(renderer, position, self.circle_radius, self.color);
+        }
     }
 
     fn on_event(
         &mut self,
-        _state: &mut Tree,
+        state: &mut Tree,
         event: Event,
         layout: Layout<'_>,
         _cursor: Cursor,
         _renderer: &Renderer,
         _clipboard: &mut dyn Clipboard,
-        _shell: &mut Shell<'_, Message>,
+        shell: &mut Shell<'_, Message>,
         _viewport: &Rectangle,
     ) -> Status {
         let bounds = layout.bounds();
 
-        if let Event::Window(window::Event::RedrawRequested(_)) = event {
+        if let Event::Window(window::Event::RedrawRequested(now)) = event {
             if is_visible(&bounds) {
+                if self.pulsing {
+                    state.state.downcast_mut::<State>().now = now;
+                    shell.request_redraw(window::RedrawRequest::NextFrame);
+                }
                 return Status::Captured;
             }
         }
@@ -1,64 +1,187 @@
 use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
     time::Duration,
 };
 
-use helper::{button_icon, button_icon_small, button_icon_text, dialog};
+use helper::{banner, button_icon, button_icon_small, button_icon_text, dialog, notice};
 use history::{read_history, serialize_history, write_history, SavedChat};
 use iced::{
     font::{Family, Weight},
     widget::{
-        button, column, combo_box, container, horizontal_rule, horizontal_space, row, text,
-        vertical_space, Container,
+        button, column, combo_box, container, horizontal_rule, horizontal_space, mouse_area,
+        pick_list, row, scrollable, stack, text, tooltip, vertical_rule, vertical_space, Container,
     },
-    Alignment, Color, Element, Font, Length, Padding, Size, Subscription, Task, Theme,
+    window, Alignment, Color, Element, Font, Length, Padding, Size, Subscription, Task, Theme,
 };
 use indicator::Indicator;
 
 use iced::futures::channel::mpsc;
 use iced::futures::{SinkExt, Stream, StreamExt};
 use iced::stream;
-use sidebar::Sidebar;
+use sidebar::{Renaming, Sidebar, Tagging};
 use std::sync::Arc;
 use ulid::Ulid;
 use url::Url;
 
 mod api;
 mod chat;
+mod cli;
+mod compare;
 mod helper;
 mod history;
 mod indicator;
+mod persist;
+mod session;
 mod settings;
 mod sidebar;
+mod stats;
 mod utils;
 
-use chat::{Chat, ChatState};
+use chat::Chat;
 
 #[derive(Clone, Debug)]
 pub enum Message {
     SettingsClicked,
     SettingsChanged(settings::MessageSettings),
     SettingsClosed,
+    StatsClicked,
+    StatsClosed,
     ModelSelected(api::LocalModel),
     WorkerReady(mpsc::Sender<WorkerInput>),
+    Polling,
     Connected,
     ModelsChanged(Vec<api::LocalModel>),
-    Disconnected,
+    Disconnected(String),
+    // version reported by the server once connected, `None` when it cannot tell
+    ServerVersion(Option<String>),
+    VersionWarningDismiss,
+    ConnectionErrorDismiss,
+    MonitorPauseToggle,
+    Reconnect,
     NewChat(api::LocalModel),
+    NewChatFromTemplate(settings::ChatTemplate),
     SidebarVisibilityToggle,
+    SidebarResizeStart,
+    // position of the cursor dragging the edge of the sidebar, in the given window
+    SidebarResize(window::Id, f32),
+    SidebarResizeEnd,
     ChatClosed(Ulid),
+    ChatCloseConfirm,
+    ChatCloseCancel,
+    // close the tabs of the tab bar but this one
+    ChatCloseOthers(Ulid),
+    ChatCloseAll,
+    // move the tab right when true, left otherwise
+    ChatMove(Ulid, bool),
     ChatSelected(Ulid),
-    ChatEditPrompt(iced::widget::text_editor::Action),
-    ChatSend,
+    ChatEditPrompt(Ulid, iced::widget::text_editor::Action),
+    // text of a pinned prompt, and the clipboard once read if the text refers to it
+    ChatInsertPinned(Ulid, String, Option<String>),
+    ChatSend(Ulid),
+    ChatContinue(Ulid),
+    ChatRegenerate(Ulid),
+    ChatDetach(Ulid),
+    ChatRename(Ulid, bool),
+    ChatRenameEdit(String),
+    ChatRenameSubmit,
+    ChatRenameCancel,
+    WindowClosed(window::Id),
+    ModelFavoriteToggle(String),
+    ModelDefaultToggle(String),
+    WindowResized(window::Id, Size),
+    WindowMoved(window::Id, iced::Point),
+    ChatToggleSentPrompt(Ulid, usize),
+    ChatToggleSystem(Ulid),
+    ChatToggleReasoning(Ulid, usize),
+    // chat, index of the reply and index of the code block among the chunks of the reply
+    ChatToggleCode(Ulid, usize, usize),
+    // chat and index of the reply
+    ChatToggleSource(Ulid, usize),
+    ChatSourceAction(Ulid, usize, iced::widget::text_editor::Action),
+    ChatEditSystem(Ulid, iced::widget::text_editor::Action),
+    ChatSuggestedModel(Ulid, bool),
+    ChatEditLast(Ulid),
+    ChatEditCancel(Ulid),
+    ChatModelSelected(Ulid, api::LocalModel),
     ChatStreamStart(Ulid, api::ChatMessageResponseStream),
-    ChatStream(Ulid, api::ChatMessageResponse),
+    ChatStream(Ulid, Vec<api::ChatMessageResponse>),
+    // parse the open paragraph of the reply again, once its throttling window ended
+    ChatPreviewRefresh(Ulid),
     ChatStreamFinished(Ulid),
+    ChatStop(Ulid),
+    StopAll,
+    ChatStreamError(Ulid, String),
+    ChatFailed(Ulid, String),
+    ChatErrorDismiss(Ulid),
+    ChatScrolled(Ulid, scrollable::Viewport),
+    ChatAttach(Ulid),
+    ChatAttachLoaded(Ulid, Result<Vec<u8>, String>),
+    ChatAttachRemove(Ulid, usize),
+    ChatFollow(Ulid),
+    ChatFindOpen(Ulid),
+    // set the system prompt, wrapping and parameters of the template of the chat again
+    ChatTemplateReapply(Ulid),
+    ChatFind(Ulid, String),
+    // next match when true, previous one otherwise
+    ChatFindStep(Ulid, bool),
+    ChatFindClose(Ulid),
+    ChatTitle(Ulid, Option<String>),
+    CompareOpen(Ulid),
+    CompareClose,
+    CompareModelSelected(usize, api::LocalModel),
+    CompareRun,
+    // the messages of a stream carry the generation of the run that started it
+    CompareStreamStart(u64, usize, api::ChatMessageResponseStream),
+    CompareStream(u64, usize, Vec<api::ChatMessageResponse>),
+    ComparePreviewRefresh(u64, usize),
+    CompareStreamFinished(u64, usize),
+    CompareFailed(u64, usize, String),
+    CompareToggleReasoning(usize),
+    CompareToggleCode(usize, usize),
+    CompareSourceAction(usize, iced::widget::text_editor::Action),
+    CompareSave(usize),
     CopyClipboard(Arc<String>),
+    CopyReply(Arc<String>),
+    OpenConfigDir,
     ConfigWritingResult(Result<(), String>),
+    HistoryWrite(u64),
     HistoryWritingResult(Result<(), String>),
+    SessionWritingResult(Result<(), String>),
+    WriteErrorDismiss,
+    WriteErrorExpired(u64),
     HistorySelected(Ulid),
     HistoryDelete(Ulid),
+    HistoryPinToggle(Ulid),
+    HistoryTagEdit(Ulid),
+    HistoryTagInput(String),
+    HistoryTagSubmit,
+    HistoryTagRemove(Ulid, String),
+    HistoryTagFilter(Option<String>),
+    HistoryDeleteConfirm,
+    HistoryDeleteCancel,
+    HistoryDeleteUndo,
+    // generation of the deletion whose undo expired, or dismissed when `None`
+    HistoryDeleteUndoExpired(Option<u64>),
+    HistoryClear,
+    HistoryClearConfirm,
+    HistoryClearCancel,
+    ExportChat(Ulid),
+    HistoryFork(Ulid),
+    HistoryImport,
+    HistoryImportLoaded(Result<SavedChat<String>, String>),
+    CopyChat(Ulid),
+    ExportResult(Result<PathBuf, String>),
+    NoticeDismiss,
+    SidebarSearch(String),
+    SidebarSearchRun(u64),
+    DraftSave(u64),
+    SidebarSearchResults(u64, Vec<history::SearchHit>),
     LinkClicked(Url),
+    Shortcut(Shortcut),
+    AnimationTick,
+    ClockTick,
 }
 
 fn main() -> iced::Result {
@@ -68,22 +191,60 @@ fn main() -> iced::Result {
 
     let project_dir = directories::ProjectDirs::from("io", "coretype", "ThinkMate").unwrap();
 
-    let app = iced::application(ThinkMate::title, ThinkMate::update, ThinkMate::view)
+    let args = match cli::parse_args(std::env::args()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2)
+        }
+    };
+    // the demo starts from the default settings and an empty history, in a directory of its own
+    // so that the ones of the user are left untouched
+    let config_dir = if args.demo {
+        std::env::temp_dir().join(format!("ThinkMate-demo-{}", std::process::id()))
+    } else {
+        project_dir.config_dir().to_path_buf()
+    };
+    if let Some(headless) = args.headless {
+        let code = match cli::run(&config_dir, headless, args.demo) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+        std::process::exit(code)
+    }
+    let demo = args.demo;
+
+    let app = iced::daemon(ThinkMate::title, ThinkMate::update, ThinkMate::view)
         .theme(ThinkMate::theme)
+        .scale_factor(ThinkMate::scale_factor)
         .font(iced_fonts::BOOTSTRAP_FONT_BYTES)
         .font(iced_fonts::REQUIRED_FONT_BYTES)
         .default_font(font)
-        .centered()
-        .window_size(Size {
-            width: 1280.0,
-            height: 1024.0,
-        })
         .antialiasing(true)
         .subscription(ThinkMate::subscription);
-    app.run_with(move || ThinkMate::new(project_dir.config_dir()))
+    let app_config_dir = config_dir.clone();
+    let result = app.run_with(move || ThinkMate::new(&app_config_dir, demo));
+    if demo {
+        std::fs::remove_dir_all(&config_dir).unwrap_or(());
+    }
+    result
 }
 
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(250);
+const DRAFT_DEBOUNCE: Duration = Duration::from_secs(1);
+const HISTORY_DEBOUNCE: Duration = Duration::from_millis(500);
+const WRITE_ERROR_DURATION: Duration = Duration::from_secs(8);
+const UNDO_DELETE_DURATION: Duration = Duration::from_secs(10);
+const RELATIVE_TIME_REFRESH: Duration = Duration::from_secs(30);
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+// suggested to install a first model, when the server has none
+const PULL_EXAMPLE: &str = "ollama pull llama3.2";
+
 pub struct ThinkMate {
+    main_window: window::Id,
     config_dir: PathBuf,
     ollama_config: api::OllamaConfig,
     menubar: Menubar,
@@ -91,36 +252,208 @@ pub struct ThinkMate {
     worker: Option<mpsc::Sender<WorkerInput>>,
     settings: settings::Settings,
     show_settings: bool,
+    stats: Option<stats::Stats>,
+    write_error: Option<String>,
+    // bumped on every write error, so that only the last one dismisses the banner
+    write_error_generation: u64,
+    // the chat deleted last, put back in the history if undone before it expires
+    deleted: Option<SavedChat<String>>,
+    deleted_generation: u64,
+    // why the server cannot be reached, until dismissed or connected again
+    connection_error: Option<String>,
+    connection_error_dismissed: bool,
+    // the server is older than what is supported, until dismissed
+    version_warning: Option<String>,
+    version_warning_dismissed: bool,
+    // outcome of an action, shown until dismissed
+    notice: Option<String>,
+    // chat of the history waiting for the deletion to be confirmed
+    pending_delete: Option<Ulid>,
+    // tabs still generating a reply, waiting for their closing to be confirmed
+    pending_close: Vec<Ulid>,
+    // the whole history waiting for its deletion to be confirmed, armed after the first click
+    pending_clear: Option<bool>,
+    // the prompt of a chat sent to two models, shown over everything
+    comparison: Option<compare::Comparison>,
+    // streams of the comparison, to stop them once closed
+    comparison_streams: Vec<iced::task::Handle>,
+    // bumped on every run of the comparison, so that the messages of stale streams are dropped
+    comparison_generation: u64,
+    // the edge of the sidebar is being dragged
+    sidebar_resizing: bool,
+    // bumped on every change of the history, so that only the last of a burst of changes is written
+    history_generation: u64,
+    // hash of the last history content written, to skip rewriting an unchanged file
+    history_written: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shortcut {
+    NewChat,
+    CloseChat,
+    NextTab,
+    PreviousTab,
+    StopAll,
+    Find,
+    // position of the tab in the tab bar, from 0
+    SelectTab(usize),
+    // move the active tab right when true, left otherwise
+    MoveTab(bool),
+    Cancel,
+}
+
+impl Shortcut {
+    fn from_key_press(
+        key: iced::keyboard::Key,
+        modifiers: iced::keyboard::Modifiers,
+    ) -> Option<Self> {
+        if key == iced::keyboard::Key::Named(iced::keyboard::key::Named::Escape) {
+            // Escape: cancel the pending confirmation
+            return Some(Shortcut::Cancel);
+        }
+        if !modifiers.command() {
+            return None;
+        }
+        match key.as_ref() {
+            // Ctrl/Cmd+N: new chat with the model selected in the menubar
+            iced::keyboard::Key::Character("n") => Some(Shortcut::NewChat),
+            // Ctrl/Cmd+W: close the active tab
+            iced::keyboard::Key::Character("w") => Some(Shortcut::CloseChat),
+            // Ctrl/Cmd+Tab and Ctrl/Cmd+Shift+Tab: cycle through the tabs
+            iced::keyboard::Key::Named(iced::keyboard::key::Named::Tab) => {
+                Some(if modifiers.shift() {
+                    Shortcut::PreviousTab
+                } else {
+                    Shortcut::NextTab
+                })
+            }
+            // Ctrl/Cmd+Shift+PageDown and Ctrl/Cmd+Shift+PageUp: move the active tab
+            iced::keyboard::Key::Named(iced::keyboard::key::Named::PageDown)
+                if modifiers.shift() =>
+            {
+                Some(Shortcut::MoveTab(true))
+            }
+            iced::keyboard::Key::Named(iced::keyboard::key::Named::PageUp) if modifiers.shift() => {
+                Some(Shortcut::MoveTab(false))
+            }
+            // Ctrl/Cmd+.: stop every generation
+            iced::keyboard::Key::Character(".") => Some(Shortcut::StopAll),
+            // Ctrl/Cmd+F: search within the active chat
+            iced::keyboard::Key::Character("f") => Some(Shortcut::Find),
+            // Ctrl/Cmd+1..9: jump to a tab
+            iced::keyboard::Key::Character(c) => match c.parse::<usize>() {
+                Ok(n @ 1..=9) => Some(Shortcut::SelectTab(n - 1)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 pub enum WorkerInput {
-    Monitor(api::OllamaConfig),
+    Monitor(api::OllamaConfig, Duration),
+    Pause,
+    Resume,
+    /// check the server now instead of waiting for the next poll
+    PollNow,
 }
 
 impl ThinkMate {
-    fn new(config_dir: &Path) -> (Self, Task<Message>) {
+    fn new(config_dir: &Path, demo: bool) -> (Self, Task<Message>) {
         std::fs::create_dir_all(config_dir).unwrap();
         let history = read_history(config_dir);
 
-        let settings = settings::read_settings(config_dir).unwrap_or(settings::Settings::default());
+        let mut settings = settings::read_settings(config_dir).unwrap_or_default();
+        settings.demo = demo;
+        let session = session::read_session(config_dir);
+        let (main_window, open) = window::open(window::Settings {
+            size: settings.window.size(),
+            position: settings.window.position(),
+            ..window::Settings::default()
+        });
+        let mut main = Main::new(history);
+        main.restore(&session);
+        if settings.sidebar_collapsed {
+            main.sidebar_visibility = SidebarVisibility::Collapsed;
+        }
         let me = Self {
+            main_window,
+            ollama_config: settings.ollama_config(),
             settings,
             config_dir: config_dir.to_path_buf(),
-            ollama_config: api::OllamaConfig::localhost(api::DEFAULT_PORT),
             menubar: Menubar::new(),
-            main: Main::new(history),
+            main,
             worker: None,
             show_settings: false,
+            stats: None,
+            write_error: None,
+            write_error_generation: 0,
+            deleted: None,
+            deleted_generation: 0,
+            connection_error: None,
+            connection_error_dismissed: false,
+            version_warning: None,
+            version_warning_dismissed: false,
+            notice: None,
+            pending_delete: None,
+            pending_close: Vec::new(),
+            pending_clear: None,
+            comparison: None,
+            comparison_streams: Vec::new(),
+            comparison_generation: 0,
+            sidebar_resizing: false,
+            history_generation: 0,
+            history_written: None,
         };
-        (me, Task::none())
+        (me, open.then(|_| Task::none()))
     }
 
     fn set_models(&mut self, models: Vec<api::LocalModel>) {
-        self.menubar.set_models(models);
+        self.main.models = models.clone();
+        self.menubar.set_models(
+            models,
+            &self.settings.favorite_models,
+            self.settings.default_model.as_deref(),
+        );
+    }
+
+    /// Show the error in a banner, dismissed after a while
+    fn show_write_error(&mut self, error: String) -> Task<Message> {
+        self.write_error = Some(error);
+        self.write_error_generation += 1;
+        let generation = self.write_error_generation;
+        Task::perform(
+            async move {
+                tokio::time::sleep(WRITE_ERROR_DURATION).await;
+                generation
+            },
+            Message::WriteErrorExpired,
+        )
+    }
+
+    /// Schedule a write of the history once the changes pause
+    fn write_history(&mut self) -> Task<Message> {
+        self.history_generation += 1;
+        let generation = self.history_generation;
+        Task::perform(
+            async move {
+                tokio::time::sleep(HISTORY_DEBOUNCE).await;
+                generation
+            },
+            Message::HistoryWrite,
+        )
     }
 
-    fn write_history(&self) -> Task<Message> {
+    /// Write the history now, unless it didn't change since the last write
+    fn flush_history(&mut self) -> Task<Message> {
         let history = serialize_history(&self.main.sidebar.chats);
+        let mut hasher = std::hash::DefaultHasher::new();
+        history.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.history_written == Some(hash) {
+            return Task::none();
+        }
+        self.history_written = Some(hash);
         let config_dir = self.config_dir.clone();
         Task::perform(write_history(config_dir, history), |r| {
             Message::HistoryWritingResult(r.map_err(|e| format!("{}", e)))
@@ -135,161 +468,1354 @@ impl ThinkMate {
         })
     }
 
+    fn write_session(&self) -> Task<Message> {
+        let session = session::serialize_session(&self.main.session());
+        let config_dir = self.config_dir.clone();
+        Task::perform(session::write_session(config_dir, session), |r| {
+            Message::SessionWritingResult(r.map_err(|e| format!("{}", e)))
+        })
+    }
+
     fn add_history(&mut self, chat: SavedChat<String>) -> Task<Message> {
         self.main.sidebar.add_chat(chat);
         self.write_history()
     }
 
+    /// Commit the reply of a chat once its generation ended or was stopped
+    fn finish_chat(&mut self, ulid: Ulid) -> Task<Message> {
+        // a stop racing with the natural end of the stream must not commit the reply twice
+        let Some(chat) = self
+            .main
+            .find_chat_mut(ulid)
+            .filter(|chat| chat.is_generating())
+        else {
+            return Task::none();
+        };
+        chat.set_finish();
+        let to_save = chat.to_saved();
+        let exchange = chat.untitled_exchange();
+        // without a dedicated title model, the model of the chat names it
+        let title_model = if self.settings.title_model.is_empty() {
+            chat.model()
+        } else {
+            self.settings.title_model.clone()
+        };
+        let save = Task::batch([self.add_history(to_save), self.write_session()]);
+        match exchange {
+            Some((query, reply)) if self.settings.generate_titles => {
+                let api = self.ollama_config.instance();
+                let model = title_model;
+                let prompt = history::title_prompt(&query, &reply);
+                let title = Task::perform(api::chat_once(api, model, prompt), move |r| {
+                    Message::ChatTitle(ulid, r.as_deref().and_then(history::clean_title))
+                });
+                Task::batch([save, title])
+            }
+            _ => save,
+        }
+    }
+
+    /// Remove the tab of a chat, the reply it is generating being stopped and kept in the
+    /// history first
+    fn close_chat(&mut self, ulid: Ulid) -> Task<Message> {
+        let stop = self.update(Message::ChatStop(ulid));
+        if let Some(idx) = self.main.find_chat_position(ulid) {
+            self.main.tabs.remove(idx);
+            if self.main.chat_view > idx || self.main.chat_view == self.main.tabs.len() {
+                self.main.chat_view = self.main.chat_view.saturating_sub(1);
+            }
+        } else {
+            tracing::error!("cannot remove chat {} that doesn't exist", ulid)
+        }
+        Task::batch([stop, self.main.restore_scroll(), self.write_session()])
+    }
+
     fn set_connected(&mut self, connected: bool) {
         self.menubar.connected = connected;
+        self.main.connected = connected;
+    }
+
+    fn send_worker(&self, input: WorkerInput) -> Task<Message> {
+        let Some(mut sender) = self.worker.clone() else {
+            return Task::none();
+        };
+        let to_send = async move {
+            sender.send(input).await.unwrap_or(());
+        };
+        Task::future(to_send).then(|_| Task::none())
     }
 
     fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::ModelSelected(m) => {
+                if let Some(chat) = self.main.tabs.get_mut(self.main.chat_view) {
+                    if self.settings.apply_selected_model {
+                        chat.set_model(m.name().clone());
+                    } else {
+                        chat.suggest_model(m.name().clone());
+                    }
+                }
                 self.menubar.selected = Some(m);
                 Task::none()
             }
             Message::WorkerReady(sender) => {
                 let mut sender2 = sender.clone();
                 let config = self.ollama_config.clone();
+                let interval = self.settings.poll_interval();
                 let to_send = async move {
                     sender2
-                        .send(WorkerInput::Monitor(config))
+                        .send(WorkerInput::Monitor(config, interval))
                         .await
                         .unwrap_or(());
                 };
                 self.worker = Some(sender);
                 Task::future(to_send).then(|_| Task::none())
             }
+            Message::Polling => {
+                self.menubar.polling = true;
+                Task::none()
+            }
             Message::Connected => {
+                self.menubar.polling = false;
                 self.set_connected(true);
+                self.connection_error = None;
+                self.connection_error_dismissed = false;
                 Task::none()
             }
             Message::ModelsChanged(models) => {
                 self.set_models(models);
                 Task::none()
             }
-            Message::Disconnected => {
+            Message::Disconnected(e) => {
+                if !self.connection_error_dismissed {
+                    self.connection_error = Some(format!(
+                        "Cannot reach Ollama at {}, is it running? ({})",
+                        self.ollama_config.address(),
+                        e
+                    ));
+                }
+                self.menubar.polling = false;
+                self.menubar.version = None;
                 self.set_models(vec![]);
                 self.set_connected(false);
                 Task::none()
             }
+            Message::MonitorPauseToggle => {
+                self.menubar.paused = !self.menubar.paused;
+                if self.menubar.paused {
+                    self.menubar.polling = false;
+                    self.send_worker(WorkerInput::Pause)
+                } else {
+                    self.send_worker(WorkerInput::Resume)
+                }
+            }
+            Message::Reconnect => self.send_worker(WorkerInput::PollNow),
             Message::NewChat(local_model) => {
                 self.main.add_new(local_model);
                 Task::none()
             }
+            Message::NewChatFromTemplate(template) => {
+                let model = if template.model.is_empty() {
+                    self.menubar.selected.as_ref().map(|m| m.name().clone())
+                } else {
+                    Some(template.model.clone())
+                };
+                if let Some(model) = model {
+                    self.main.add_template(model, &template);
+                } else {
+                    tracing::warn!("no model for template {}", template.name)
+                }
+                Task::none()
+            }
             Message::ChatClosed(chat_closing) => {
-                if let Some(idx) = self.main.find_chat_position(chat_closing) {
-                    self.main.tabs.remove(idx);
+                let generating = self
+                    .main
+                    .find_chat(chat_closing)
+                    .is_some_and(|chat| chat.is_generating());
+                if generating && self.settings.confirm_close_generating {
+                    if !self.pending_close.contains(&chat_closing) {
+                        self.pending_close.push(chat_closing);
+                    }
+                    return Task::none();
+                }
+                self.close_chat(chat_closing)
+            }
+            Message::ChatCloseConfirm => {
+                let closing = std::mem::take(&mut self.pending_close);
+                let tasks = closing
+                    .into_iter()
+                    .map(|ulid| self.close_chat(ulid))
+                    .collect::<Vec<_>>();
+                Task::batch(tasks)
+            }
+            Message::ChatCloseCancel => {
+                self.pending_close.clear();
+                Task::none()
+            }
+            Message::ChatCloseOthers(kept) => {
+                let closing = self
+                    .main
+                    .docked_tabs()
+                    .into_iter()
+                    .map(|idx| self.main.tabs[idx].ulid())
+                    .filter(|ulid| *ulid != kept)
+                    .collect::<Vec<_>>();
+                let tasks = closing
+                    .into_iter()
+                    .map(|ulid| self.update(Message::ChatClosed(ulid)))
+                    .collect::<Vec<_>>();
+                Task::batch(tasks)
+            }
+            Message::ChatCloseAll => {
+                let closing = self
+                    .main
+                    .docked_tabs()
+                    .into_iter()
+                    .map(|idx| self.main.tabs[idx].ulid())
+                    .collect::<Vec<_>>();
+                let tasks = closing
+                    .into_iter()
+                    .map(|ulid| self.update(Message::ChatClosed(ulid)))
+                    .collect::<Vec<_>>();
+                Task::batch(tasks)
+            }
+            Message::ChatInsertPinned(ulid, text, None)
+                if text.contains(settings::CLIPBOARD_PLACEHOLDER) =>
+            {
+                iced::clipboard::read().map(move |clipboard| {
+                    Message::ChatInsertPinned(
+                        ulid,
+                        text.clone(),
+                        Some(clipboard.unwrap_or_default()),
+                    )
+                })
+            }
+            Message::ChatInsertPinned(ulid, text, clipboard) => {
+                let Some(chat) = self.main.find_chat(ulid) else {
+                    return Task::none();
+                };
+                // pasting replaces the selection, which the snippet may wrap
+                let selection = chat.prompt_selection().unwrap_or_default();
+                let text = settings::PinnedPrompt::expand(
+                    &text,
+                    &selection,
+                    clipboard.as_deref().unwrap_or_default(),
+                );
+                self.update(Message::ChatEditPrompt(
+                    ulid,
+                    iced::widget::text_editor::Action::Edit(
+                        iced::widget::text_editor::Edit::Paste(Arc::new(text)),
+                    ),
+                ))
+            }
+            Message::ChatMove(ulid, forward) => {
+                if self.main.move_tab(ulid, forward) {
+                    self.write_session()
+                } else {
+                    Task::none()
+                }
+            }
+            Message::ChatEditPrompt(ulid, text_action) => {
+                let is_edit = text_action.is_edit();
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.edit_prompt(text_action);
+                }
+                if !is_edit {
+                    return Task::none();
+                }
+                // the draft is saved with the session once the typing pauses
+                self.main.draft_generation += 1;
+                let generation = self.main.draft_generation;
+                Task::perform(
+                    async move {
+                        tokio::time::sleep(DRAFT_DEBOUNCE).await;
+                        generation
+                    },
+                    Message::DraftSave,
+                )
+            }
+            Message::DraftSave(generation) => {
+                if generation == self.main.draft_generation {
+                    self.write_session()
+                } else {
+                    Task::none()
+                }
+            }
+            Message::ChatDetach(ulid) => {
+                let (id, open) = window::open(window::Settings {
+                    size: Size {
+                        width: 900.0,
+                        height: 800.0,
+                    },
+                    ..window::Settings::default()
+                });
+                self.main.detach(id, ulid);
+                open.then(|_| Task::none())
+            }
+            Message::ModelFavoriteToggle(model) => {
+                let favorites = &mut self.settings.favorite_models;
+                if let Some(idx) = favorites.iter().position(|m| *m == model) {
+                    favorites.remove(idx);
+                } else {
+                    favorites.push(model);
+                }
+                self.set_models(self.main.models.clone());
+                self.write_config()
+            }
+            Message::ModelDefaultToggle(model) => {
+                self.settings.default_model =
+                    (self.settings.default_model.as_ref() != Some(&model)).then_some(model);
+                self.write_config()
+            }
+            Message::WindowResized(id, size) => {
+                if id == self.main_window {
+                    // the size is reported scaled by the interface scaling, which is not part
+                    // of the size given when opening the window
+                    let factor = self.settings.ui_scale.factor() as f32;
+                    self.settings.window.width = size.width * factor;
+                    self.settings.window.height = size.height * factor;
+                }
+                Task::none()
+            }
+            Message::WindowMoved(id, position) => {
+                if id == self.main_window {
+                    let factor = self.settings.ui_scale.factor() as f32;
+                    self.settings.window.position =
+                        Some((position.x * factor, position.y * factor));
+                }
+                Task::none()
+            }
+            Message::WindowClosed(id) => {
+                if id == self.main_window {
+                    // a change may still be waiting for its debounced write
+                    Task::batch([self.flush_history(), self.write_config()]).chain(iced::exit())
+                } else {
+                    self.main.attach(id);
+                    Task::none()
+                }
+            }
+            Message::ChatSelected(chat_selected) => {
+                if let Some(idx) = self.main.find_chat_position(chat_selected) {
+                    self.main.chat_view = idx;
+                } else {
+                    tracing::error!("cannot select chat {} that doesn't exist", chat_selected)
+                }
+                Task::batch([self.main.restore_scroll(), self.write_session()])
+            }
+            Message::ChatSend(ulid) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                let model = chat.model();
+                let params = chat.generation_params(self.settings.generation_params(&model));
+                let system = chat.system();
+                let history = chat.history();
+                let prompt = chat.set_generating();
+                // a new prompt brings the view back to the end of the chat
+                let follow = chat.follow_latest();
+                let config = &self.ollama_config.clone();
+                let api = config.instance();
+                Task::batch([
+                    follow,
+                    // the draft is gone once sent
+                    self.write_session(),
+                    Task::perform(
+                        api::chat_stream(api, model, system, history, prompt, params),
+                        move |r| match r {
+                            Ok(stream) => Message::ChatStreamStart(ulid, stream),
+                            Err(e) => Message::ChatFailed(ulid, e),
+                        },
+                    ),
+                ])
+            }
+            Message::ChatContinue(ulid) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                let model = chat.model();
+                let params = chat.generation_params(self.settings.generation_params(&model));
+                let system = chat.system();
+                let Some((prompt, partial)) = chat.set_continuing() else {
+                    return Task::none();
+                };
+                let history = chat.history();
+                let api = self.ollama_config.instance();
+                Task::perform(
+                    api::chat_continue(api, model, system, history, prompt, partial, params),
+                    move |r| match r {
+                        Ok(stream) => Message::ChatStreamStart(ulid, stream),
+                        Err(e) => Message::ChatFailed(ulid, e),
+                    },
+                )
+            }
+            Message::ChatToggleReasoning(ulid, index) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.toggle_reasoning(index);
+                }
+                Task::none()
+            }
+            Message::ChatToggleCode(ulid, index, chunk) => {
+                let collapse_default = self.settings.collapse_code;
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.toggle_code(index, chunk, collapse_default);
+                }
+                Task::none()
+            }
+            Message::ChatToggleSource(ulid, index) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.toggle_source(index);
+                }
+                Task::none()
+            }
+            Message::ChatSourceAction(ulid, index, action) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.source_action(index, action);
+                }
+                Task::none()
+            }
+            Message::ChatToggleSystem(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.toggle_show_system();
+                }
+                Task::none()
+            }
+            Message::ChatEditSystem(ulid, action) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.edit_system(action);
+                }
+                Task::none()
+            }
+            Message::ChatRegenerate(ulid) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                let model = chat.model();
+                let params = chat.generation_params(self.settings.generation_params(&model));
+                let system = chat.system();
+                let Some(prompt) = chat.set_regenerating() else {
+                    return Task::none();
+                };
+                let history = chat.history();
+                let api = self.ollama_config.instance();
+                Task::perform(
+                    api::chat_stream(api, model, system, history, prompt, params),
+                    move |r| match r {
+                        Ok(stream) => Message::ChatStreamStart(ulid, stream),
+                        Err(e) => Message::ChatFailed(ulid, e),
+                    },
+                )
+            }
+            Message::ChatToggleSentPrompt(ulid, index) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.toggle_show_sent(index);
+                }
+                Task::none()
+            }
+            Message::ChatModelSelected(ulid, model) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                chat.set_model(model.name().clone());
+                // the next turns go to the new model, with the whole conversation as context
+                if chat.previous.content.is_empty() {
+                    Task::none()
+                } else {
+                    let to_save = chat.to_saved();
+                    self.add_history(to_save)
+                }
+            }
+            Message::ChatEditLast(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.edit_last_query();
+                }
+                Task::none()
+            }
+            Message::ChatEditCancel(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.cancel_edit();
+                }
+                Task::none()
+            }
+            Message::ChatSuggestedModel(ulid, accept) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.resolve_suggested_model(accept);
+                }
+                Task::none()
+            }
+            Message::ChatStreamStart(ulid, chat_message_response_stream) => {
+                println!("chat stream start");
+                // stopped or closed while waiting for the server: the stream is dropped
+                let Some(chat) = self
+                    .main
+                    .find_chat_mut(ulid)
+                    .filter(|chat| chat.is_generating())
+                else {
+                    return Task::none();
+                };
+                chat.discard_replaced();
+                let (task, handle) =
+                    Task::run(chat_message_response_stream.batched(), move |x| match x {
+                        Ok(responses) => Message::ChatStream(ulid, responses),
+                        // the server gives no details, they are only logged by ollama-rs
+                        Err(()) => Message::ChatStreamError(
+                            ulid,
+                            "The reply was interrupted by an invalid or failed read".to_string(),
+                        ),
+                    })
+                    .chain(Task::done(Message::ChatStreamFinished(ulid)))
+                    .abortable();
+                self.main.generations.insert(ulid, handle);
+                task
+            }
+            Message::ChatStream(ulid, responses) => {
+                // only the chat of the stream is touched, the others keep their state
+                let trailing = self
+                    .main
+                    .find_chat_mut(ulid)
+                    .is_some_and(|chat| chat.add_content(responses));
+                if !trailing {
+                    return Task::none();
+                }
+                Task::perform(tokio::time::sleep(chat::PREVIEW_INTERVAL), move |()| {
+                    Message::ChatPreviewRefresh(ulid)
+                })
+            }
+            Message::ChatPreviewRefresh(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.refresh_preview();
+                }
+                Task::none()
+            }
+            Message::ChatStop(ulid) => {
+                if let Some(handle) = self.main.generations.remove(&ulid) {
+                    handle.abort();
+                }
+                self.finish_chat(ulid)
+            }
+            Message::ChatStreamError(ulid, error) => {
+                // keep what was received so far, without the final metrics
+                tracing::error!("chat {} stream error: {}", ulid, error);
+                if let Some(handle) = self.main.generations.remove(&ulid) {
+                    handle.abort();
+                }
+                let finish = self.finish_chat(ulid);
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.error = Some(error);
+                }
+                finish
+            }
+            Message::ChatStreamFinished(ulid) => {
+                self.main.generations.remove(&ulid);
+                self.finish_chat(ulid)
+            }
+            Message::ChatFailed(ulid, error) => {
+                tracing::error!("chat {} failed: {}", ulid, error);
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.set_failed(error);
+                }
+                Task::none()
+            }
+            Message::ChatErrorDismiss(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.error = None;
+                }
+                Task::none()
+            }
+            Message::ChatScrolled(ulid, viewport) => match self.main.find_chat_mut(ulid) {
+                Some(chat) => chat.scrolled(viewport),
+                None => Task::none(),
+            },
+            Message::ChatFollow(ulid) => match self.main.find_chat_mut(ulid) {
+                Some(chat) => chat.follow_latest(),
+                None => Task::none(),
+            },
+            Message::ChatFindOpen(ulid) => match self.main.find_chat_mut(ulid) {
+                Some(chat) => chat.open_find(),
+                None => Task::none(),
+            },
+            Message::ChatTemplateReapply(ulid) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                let Some(name) = chat.template_name().map(|name| name.to_string()) else {
+                    return Task::none();
+                };
+                match self.settings.templates.iter().find(|t| t.name == name) {
+                    Some(template) => chat.apply_template(template),
+                    None => {
+                        self.notice = Some(format!("Template \"{}\" doesn't exist anymore", name))
+                    }
+                }
+                Task::none()
+            }
+            Message::ChatFind(ulid, query) => match self.main.find_chat_mut(ulid) {
+                Some(chat) => chat.find(query),
+                None => Task::none(),
+            },
+            Message::ChatFindStep(ulid, forward) => match self.main.find_chat_mut(ulid) {
+                Some(chat) => chat.find_step(forward),
+                None => Task::none(),
+            },
+            Message::ChatFindClose(ulid) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.close_find();
+                }
+                Task::none()
+            }
+            Message::ChatAttach(ulid) => {
+                let Some(chat) = self.main.find_chat_mut(ulid) else {
+                    return Task::none();
+                };
+                chat.clear_attach_error();
+                let pick = rfd::AsyncFileDialog::new()
+                    .set_title("Attach an image")
+                    .add_filter("Images", chat::IMAGE_EXTENSIONS)
+                    .pick_file();
+                Task::future(pick).then(move |file| match file {
+                    // the dialog was cancelled
+                    None => Task::none(),
+                    Some(file) => {
+                        let path = file.path().to_path_buf();
+                        Task::perform(
+                            async move {
+                                // the filter of the dialog is only a hint on some platforms
+                                let supported = path
+                                    .extension()
+                                    .and_then(|ext| ext.to_str())
+                                    .is_some_and(|ext| {
+                                        chat::IMAGE_EXTENSIONS
+                                            .contains(&ext.to_lowercase().as_str())
+                                    });
+                                if !supported {
+                                    return Err(format!(
+                                        "unsupported image type, expecting one of: {}",
+                                        chat::IMAGE_EXTENSIONS.join(", ")
+                                    ));
+                                }
+                                tokio::fs::read(&path)
+                                    .await
+                                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))
+                            },
+                            move |r| Message::ChatAttachLoaded(ulid, r),
+                        )
+                    }
+                })
+            }
+            Message::ChatAttachLoaded(ulid, image) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.attach(image);
+                }
+                Task::none()
+            }
+            Message::ChatAttachRemove(ulid, index) => {
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.remove_attachment(index);
+                }
+                Task::none()
+            }
+            Message::ChatTitle(ulid, title) => {
+                // without a title, the chat keeps being described by its first query
+                let Some(title) = title else {
+                    return Task::none();
+                };
+                // a title given by the user is kept
+                let Some(chat) = self
+                    .main
+                    .find_chat_mut(ulid)
+                    .filter(|chat| !chat.has_title())
+                else {
+                    return Task::none();
+                };
+                chat.set_title(Some(title));
+                let to_save = chat.to_saved();
+                self.add_history(to_save)
+            }
+            Message::ChatRename(ulid, in_sidebar) => {
+                let (title, has_title) = match self.main.find_chat(ulid) {
+                    Some(chat) => (chat.name(), chat.has_title()),
+                    None => self
+                        .main
+                        .sidebar
+                        .chats
+                        .iter()
+                        .find(|c| c.ulid == ulid)
+                        .map(|c| (c.description(), c.title.is_some()))
+                        .unwrap_or_default(),
+                };
+                self.main.renaming = Some(Renaming {
+                    ulid,
+                    default: (!has_title).then(|| title.clone()),
+                    title,
+                    in_sidebar,
+                });
+                iced::widget::text_input::focus(Renaming::input_id())
+            }
+            Message::ChatRenameEdit(title) => {
+                if let Some(renaming) = &mut self.main.renaming {
+                    renaming.title = title;
+                }
+                Task::none()
+            }
+            Message::ChatRenameCancel => {
+                self.main.renaming = None;
+                Task::none()
+            }
+            Message::ChatRenameSubmit => {
+                let Some(renaming) = self.main.renaming.take() else {
+                    return Task::none();
+                };
+                let title = renaming.submitted_title();
+                if let Some(chat) = self.main.find_chat_mut(renaming.ulid) {
+                    chat.set_title(title.clone());
+                }
+                if self.main.sidebar.set_title(renaming.ulid, title) {
+                    self.write_history()
+                } else {
+                    Task::none()
+                }
+            }
+            Message::SidebarVisibilityToggle => {
+                self.main.sidebar_visibility = self.main.sidebar_visibility.toggle();
+                self.settings.sidebar_collapsed =
+                    matches!(self.main.sidebar_visibility, SidebarVisibility::Collapsed);
+                self.write_config()
+            }
+            Message::SidebarResizeStart => {
+                self.sidebar_resizing = true;
+                Task::none()
+            }
+            Message::SidebarResize(id, x) => {
+                if id == self.main_window {
+                    // the cursor moves in the scaled interface, as does the reported window size
+                    let width = self.settings.window.width / self.settings.ui_scale.factor() as f32;
+                    if width > 0.0 {
+                        self.settings.sidebar_width = (width - x) / width;
+                        self.settings.sidebar_width = self.settings.sidebar_width();
+                    }
+                }
+                Task::none()
+            }
+            Message::SidebarResizeEnd => {
+                self.sidebar_resizing = false;
+                self.write_config()
+            }
+            Message::CopyClipboard(s) => iced::clipboard::write(s.as_str().to_string()),
+            Message::CopyReply(s) => {
+                if self.settings.exclude_reasoning {
+                    iced::clipboard::write(history::strip_reasoning(&s))
+                } else {
+                    iced::clipboard::write(s.as_str().to_string())
+                }
+            }
+            Message::OpenConfigDir => {
+                if let Err(e) = open::that_detached(&self.config_dir) {
+                    tracing::error!("cannot open {}: {}", self.config_dir.display(), e);
+                    return self
+                        .show_write_error(format!("Opening the config folder failed: {}", e));
+                }
+                Task::none()
+            }
+            Message::LinkClicked(_) => Task::none(),
+            Message::Shortcut(Shortcut::NewChat) => match self.menubar.selected.clone() {
+                Some(model) => self.update(Message::NewChat(model)),
+                None => Task::none(),
+            },
+            Message::Shortcut(Shortcut::Cancel) => {
+                self.pending_delete = None;
+                self.pending_close.clear();
+                self.pending_clear = None;
+                self.main.sidebar.tagging = None;
+                self.main.renaming = None;
+                if let Some(chat) = self.main.tabs.get_mut(self.main.chat_view) {
+                    chat.close_find();
+                }
+                Task::none()
+            }
+            Message::Shortcut(Shortcut::Find) => match self.main.tabs.get(self.main.chat_view) {
+                Some(chat) if !self.main.is_detached(chat.ulid()) => {
+                    let ulid = chat.ulid();
+                    self.update(Message::ChatFindOpen(ulid))
+                }
+                _ => Task::none(),
+            },
+            Message::Shortcut(Shortcut::CloseChat) => {
+                match self.main.tabs.get(self.main.chat_view) {
+                    Some(chat) if !self.main.is_detached(chat.ulid()) => {
+                        let ulid = chat.ulid();
+                        self.update(Message::ChatClosed(ulid))
+                    }
+                    _ => Task::none(),
+                }
+            }
+            Message::Shortcut(Shortcut::StopAll) => self.update(Message::StopAll),
+            Message::StopAll => {
+                let generating = self
+                    .main
+                    .tabs
+                    .iter()
+                    .filter(|chat| chat.is_generating())
+                    .map(|chat| chat.ulid())
+                    .collect::<Vec<_>>();
+                // each partial reply is committed, as with the stop button of its chat
+                let tasks = generating
+                    .into_iter()
+                    .map(|ulid| self.update(Message::ChatStop(ulid)))
+                    .collect::<Vec<_>>();
+                Task::batch(tasks)
+            }
+            Message::Shortcut(Shortcut::NextTab) => {
+                if self.main.cycle_tab(true) {
+                    Task::batch([self.main.restore_scroll(), self.write_session()])
+                } else {
+                    Task::none()
+                }
+            }
+            Message::Shortcut(Shortcut::PreviousTab) => {
+                if self.main.cycle_tab(false) {
+                    Task::batch([self.main.restore_scroll(), self.write_session()])
+                } else {
+                    Task::none()
+                }
+            }
+            Message::Shortcut(Shortcut::MoveTab(forward)) => {
+                match self.main.tabs.get(self.main.chat_view) {
+                    Some(chat) if !self.main.is_detached(chat.ulid()) => {
+                        let ulid = chat.ulid();
+                        self.update(Message::ChatMove(ulid, forward))
+                    }
+                    _ => Task::none(),
+                }
+            }
+            Message::Shortcut(Shortcut::SelectTab(n)) => {
+                if self.main.select_tab(n) {
+                    Task::batch([self.main.restore_scroll(), self.write_session()])
+                } else {
+                    Task::none()
+                }
+            }
+            Message::AnimationTick => Task::none(),
+            // only there to redraw the relative timestamps of the sidebar
+            Message::ClockTick => Task::none(),
+            Message::ConfigWritingResult(r) => match r {
+                Ok(()) => Task::none(),
+                Err(e) => {
+                    tracing::error!("fail saving config {}", e);
+                    self.show_write_error(format!("Saving settings failed: {}", e))
+                }
+            },
+            Message::HistoryWrite(generation) => {
+                if generation == self.history_generation {
+                    self.flush_history()
+                } else {
+                    Task::none()
+                }
+            }
+            Message::HistoryWritingResult(r) => match r {
+                Ok(()) => Task::none(),
+                Err(e) => {
+                    tracing::error!("fail saving history {}", e);
+                    // the next change must write again, even to the same content
+                    self.history_written = None;
+                    self.show_write_error(format!("Saving history failed: {}", e))
+                }
+            },
+            Message::SessionWritingResult(r) => {
+                if let Err(e) = r {
+                    tracing::warn!("fail saving session {}", e);
+                }
+                Task::none()
+            }
+            Message::ServerVersion(version) => {
+                self.version_warning = version
+                    .as_deref()
+                    .filter(|v| api::is_outdated(v) && !self.version_warning_dismissed)
+                    .map(|v| {
+                        format!(
+                            "Ollama {} is older than {}, some features may not work. \
+                             Please update the server.",
+                            v,
+                            api::MIN_SERVER_VERSION
+                        )
+                    });
+                self.menubar.version = version;
+                Task::none()
+            }
+            Message::VersionWarningDismiss => {
+                self.version_warning = None;
+                self.version_warning_dismissed = true;
+                Task::none()
+            }
+            Message::ConnectionErrorDismiss => {
+                self.connection_error = None;
+                self.connection_error_dismissed = true;
+                Task::none()
+            }
+            Message::WriteErrorDismiss => {
+                self.write_error = None;
+                Task::none()
+            }
+            Message::WriteErrorExpired(generation) => {
+                if generation == self.write_error_generation {
+                    self.write_error = None;
+                }
+                Task::none()
+            }
+            Message::HistorySelected(ulid) => {
+                // check if the chat is already opened
+                if let Some(chat_idx) = self.main.find_chat_position(ulid) {
+                    self.main.chat_view = chat_idx;
+                    return self.write_session();
+                }
+                if let Some(saved_chat) = self
+                    .main
+                    .sidebar
+                    .chats
+                    .iter()
+                    .find(|c| c.ulid == ulid)
+                    .cloned()
+                {
+                    self.main.add_saved(saved_chat);
+                    self.write_session()
                 } else {
-                    tracing::error!("cannot remove chat {} that doesn't exist", chat_closing)
+                    Task::none()
+                }
+            }
+            Message::HistoryFork(ulid) => {
+                let Some(fork) = self.main.saved_chat(ulid).as_ref().map(SavedChat::fork) else {
+                    return Task::none();
+                };
+                let fork_id = fork.ulid;
+                self.main.add_saved(fork.clone());
+                if let Some(idx) = self.main.find_chat_position(fork_id) {
+                    self.main.chat_view = idx;
+                }
+                // a chat is only saved once it has an exchange
+                if fork.content.is_empty() {
+                    return self.write_session();
+                }
+                Task::batch([self.add_history(fork), self.write_session()])
+            }
+            Message::HistoryImport => {
+                self.main.sidebar.import_error = None;
+                let pick = rfd::AsyncFileDialog::new()
+                    .set_title("Import a chat")
+                    .add_filter("Exported chats", &["md", "markdown", "json"])
+                    .pick_file();
+                Task::future(pick).then(|file| match file {
+                    // the dialog was cancelled
+                    None => Task::none(),
+                    Some(file) => {
+                        let path = file.path().to_path_buf();
+                        Task::perform(
+                            async move {
+                                let content =
+                                    tokio::fs::read_to_string(&path).await.map_err(|e| {
+                                        format!("cannot read {}: {}", path.display(), e)
+                                    })?;
+                                history::parse_import(&content)
+                            },
+                            Message::HistoryImportLoaded,
+                        )
+                    }
+                })
+            }
+            Message::HistoryImportLoaded(r) => match r {
+                Ok(mut chat) => {
+                    // importing the same file twice must not replace the first import
+                    if self.main.sidebar.chats.iter().any(|c| c.ulid == chat.ulid) {
+                        chat.ulid = Ulid::new();
+                    }
+                    self.notice = Some(format!("Chat \"{}\" imported", chat.description()));
+                    self.add_history(chat)
+                }
+                Err(e) => {
+                    tracing::warn!("fail importing chat {}", e);
+                    self.main.sidebar.import_error = Some(e);
+                    Task::none()
+                }
+            },
+            Message::SidebarSearch(search) => {
+                let sidebar = &mut self.main.sidebar;
+                sidebar.search = search;
+                sidebar.search_generation += 1;
+                sidebar.search_results = None;
+                if sidebar.search.is_empty() {
+                    return Task::none();
+                }
+                let generation = sidebar.search_generation;
+                Task::perform(
+                    async move {
+                        tokio::time::sleep(SEARCH_DEBOUNCE).await;
+                        generation
+                    },
+                    Message::SidebarSearchRun,
+                )
+            }
+            Message::SidebarSearchRun(generation) => {
+                let sidebar = &self.main.sidebar;
+                if generation != sidebar.search_generation {
+                    return Task::none();
+                }
+                let chats = Arc::clone(&sidebar.chats);
+                let query = sidebar.search.clone();
+                Task::perform(
+                    tokio::task::spawn_blocking(move || history::search(&chats, &query)),
+                    move |hits| Message::SidebarSearchResults(generation, hits.unwrap_or_default()),
+                )
+            }
+            Message::SidebarSearchResults(generation, hits) => {
+                let sidebar = &mut self.main.sidebar;
+                if generation == sidebar.search_generation {
+                    sidebar.search_results = Some(hits);
+                }
+                Task::none()
+            }
+            Message::CopyChat(ulid) => match self.main.saved_chat(ulid) {
+                Some(chat) => iced::clipboard::write(history::to_markdown(
+                    &chat,
+                    self.settings.exclude_reasoning,
+                )),
+                None => Task::none(),
+            },
+            Message::ExportChat(ulid) => {
+                let Some(chat) = self.main.saved_chat(ulid) else {
+                    return Task::none();
+                };
+                let dir = directories::UserDirs::new()
+                    .and_then(|dirs| {
+                        dirs.download_dir()
+                            .or(dirs.document_dir())
+                            .map(Path::to_path_buf)
+                    })
+                    .unwrap_or_else(|| self.config_dir.clone());
+                let markdown = history::to_markdown(&chat, self.settings.exclude_reasoning);
+                // the dialog asks before replacing an existing file
+                let save = rfd::AsyncFileDialog::new()
+                    .set_title("Export the chat")
+                    .set_directory(dir)
+                    .set_file_name(history::export_file_name(&chat))
+                    .add_filter("Markdown", &["md"])
+                    .save_file();
+                Task::future(save).then(move |file| match file {
+                    // the dialog was cancelled
+                    None => Task::none(),
+                    Some(file) => {
+                        let path = file.path().to_path_buf();
+                        let markdown = markdown.clone();
+                        Task::perform(
+                            async move {
+                                tokio::fs::write(&path, markdown)
+                                    .await
+                                    .map(|()| path)
+                                    .map_err(|e| format!("{}", e))
+                            },
+                            Message::ExportResult,
+                        )
+                    }
+                })
+            }
+            Message::ExportResult(r) => match r {
+                Ok(path) => {
+                    self.notice = Some(format!("Chat exported to {}", path.display()));
+                    Task::none()
+                }
+                Err(e) => {
+                    tracing::error!("fail exporting chat {}", e);
+                    self.show_write_error(format!("Exporting chat failed: {}", e))
+                }
+            },
+            Message::NoticeDismiss => {
+                self.notice = None;
+                Task::none()
+            }
+            Message::HistoryPinToggle(ulid) => {
+                let Some(pinned) = self.main.sidebar.toggle_pinned(ulid) else {
+                    return Task::none();
+                };
+                // keep the opened chat in sync, as it replaces the saved one on the next turn
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.set_pinned(pinned);
+                }
+                self.write_history()
+            }
+            Message::HistoryTagEdit(ulid) => {
+                self.main.sidebar.tagging = Some(Tagging {
+                    ulid,
+                    tag: String::new(),
+                });
+                iced::widget::text_input::focus(Tagging::input_id())
+            }
+            Message::HistoryTagInput(tag) => {
+                if let Some(tagging) = &mut self.main.sidebar.tagging {
+                    tagging.tag = tag;
+                }
+                Task::none()
+            }
+            Message::HistoryTagSubmit => {
+                let Some(tagging) = self.main.sidebar.tagging.take() else {
+                    return Task::none();
+                };
+                let Some(tags) = self.main.sidebar.add_tag(tagging.ulid, &tagging.tag) else {
+                    return Task::none();
+                };
+                // keep the opened chat in sync, as it replaces the saved one on the next turn
+                if let Some(chat) = self.main.find_chat_mut(tagging.ulid) {
+                    chat.set_tags(tags);
+                }
+                self.write_history()
+            }
+            Message::HistoryTagRemove(ulid, tag) => {
+                let Some(tags) = self.main.sidebar.remove_tag(ulid, &tag) else {
+                    return Task::none();
+                };
+                if let Some(chat) = self.main.find_chat_mut(ulid) {
+                    chat.set_tags(tags);
+                }
+                self.write_history()
+            }
+            Message::HistoryTagFilter(tag) => {
+                self.main.sidebar.tag_filter = tag;
+                Task::none()
+            }
+            Message::HistoryDelete(ulid) => {
+                self.pending_delete = Some(ulid);
+                Task::none()
+            }
+            Message::HistoryDeleteConfirm => {
+                let Some(ulid) = self.pending_delete.take() else {
+                    return Task::none();
+                };
+                let Some(chat) = self.main.sidebar.remove_chat(ulid) else {
+                    return Task::none();
+                };
+                // a previous deletion cannot be undone anymore
+                self.deleted = Some(chat);
+                self.deleted_generation += 1;
+                let generation = self.deleted_generation;
+                Task::batch([
+                    self.write_history(),
+                    Task::perform(
+                        async move {
+                            tokio::time::sleep(UNDO_DELETE_DURATION).await;
+                            Some(generation)
+                        },
+                        Message::HistoryDeleteUndoExpired,
+                    ),
+                ])
+            }
+            Message::HistoryDeleteUndo => match self.deleted.take() {
+                // a chat still open saves itself again, and that copy is newer than the deleted one
+                Some(chat) if self.main.sidebar.chats.iter().any(|c| c.ulid == chat.ulid) => {
+                    Task::none()
+                }
+                Some(chat) => self.add_history(chat),
+                None => Task::none(),
+            },
+            Message::HistoryDeleteUndoExpired(generation) => {
+                if generation.map_or(true, |generation| generation == self.deleted_generation) {
+                    self.deleted = None;
+                }
+                Task::none()
+            }
+            Message::HistoryDeleteCancel => {
+                self.pending_delete = None;
+                Task::none()
+            }
+            Message::CompareOpen(ulid) => {
+                let Some(chat) = self.main.find_chat(ulid) else {
+                    return Task::none();
+                };
+                let Some(query) = chat.pending_query() else {
+                    return Task::none();
+                };
+                self.comparison = Some(compare::Comparison::new(
+                    chat.saved_before_prompt(),
+                    query,
+                    &self.main.models,
+                ));
+                Task::none()
+            }
+            Message::CompareClose => {
+                self.comparison = None;
+                self.comparison_generation += 1;
+                for handle in self.comparison_streams.drain(..) {
+                    handle.abort();
+                }
+                Task::none()
+            }
+            Message::CompareModelSelected(side, model) => {
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.select_model(side, model);
+                }
+                Task::none()
+            }
+            Message::CompareRun => {
+                let Some(comparison) = &mut self.comparison else {
+                    return Task::none();
+                };
+                for handle in self.comparison_streams.drain(..) {
+                    handle.abort();
+                }
+                self.comparison_generation += 1;
+                let generation = self.comparison_generation;
+                let tasks = (0..comparison.sides.len()).filter_map(|side| {
+                    let (model, system, history, prompt) = comparison.start(side)?;
+                    let params =
+                        comparison.generation_params(self.settings.generation_params(&model));
+                    let api = self.ollama_config.instance();
+                    Some(Task::perform(
+                        api::chat_stream(api, model, system, history, prompt, params),
+                        move |r| match r {
+                            Ok(stream) => Message::CompareStreamStart(generation, side, stream),
+                            Err(e) => Message::CompareFailed(generation, side, e),
+                        },
+                    ))
+                });
+                Task::batch(tasks.collect::<Vec<_>>())
+            }
+            Message::CompareStreamStart(generation, side, stream) => {
+                // closed, or run again, while waiting for the server
+                if generation != self.comparison_generation
+                    || self
+                        .comparison
+                        .as_mut()
+                        .and_then(|c| c.generating_side(side))
+                        .is_none()
+                {
+                    return Task::none();
+                }
+                let (task, handle) = Task::run(stream.batched(), move |x| match x {
+                    Ok(responses) => Message::CompareStream(generation, side, responses),
+                    Err(()) => Message::CompareFailed(
+                        generation,
+                        side,
+                        "The reply was interrupted by an invalid or failed read".to_string(),
+                    ),
+                })
+                .chain(Task::done(Message::CompareStreamFinished(generation, side)))
+                .abortable();
+                self.comparison_streams.push(handle);
+                task
+            }
+            Message::CompareStream(generation, side, responses) => {
+                if generation != self.comparison_generation {
+                    return Task::none();
+                }
+                let trailing = self
+                    .comparison
+                    .as_mut()
+                    .is_some_and(|comparison| comparison.add_content(side, responses));
+                if !trailing {
+                    return Task::none();
+                }
+                Task::perform(tokio::time::sleep(chat::PREVIEW_INTERVAL), move |()| {
+                    Message::ComparePreviewRefresh(generation, side)
+                })
+            }
+            Message::ComparePreviewRefresh(generation, side) => {
+                if generation != self.comparison_generation {
+                    return Task::none();
+                }
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.refresh_preview(side);
                 }
                 Task::none()
             }
-            Message::ChatEditPrompt(text_action) => {
-                let chat = &mut self.main.tabs[self.main.chat_view];
-                match &mut chat.state {
-                    ChatState::Prompting(content) => content.perform(text_action),
-                    ChatState::Generating(_) => {}
-                };
+            Message::CompareStreamFinished(generation, side) => {
+                if generation != self.comparison_generation {
+                    return Task::none();
+                }
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.finish(side);
+                }
                 Task::none()
             }
-            Message::ChatSelected(chat_selected) => {
-                if let Some(idx) = self.main.find_chat_position(chat_selected) {
-                    self.main.chat_view = idx;
-                } else {
-                    tracing::error!("cannot select chat {} that doesn't exist", chat_selected)
+            Message::CompareFailed(generation, side, error) => {
+                tracing::error!("comparison side {} failed: {}", side, error);
+                if generation != self.comparison_generation {
+                    return Task::none();
+                }
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.fail(side, error);
                 }
                 Task::none()
             }
-            Message::ChatSend => {
-                let chat = &mut self.main.tabs[self.main.chat_view];
-                let ulid = chat.ulid();
-                let model = chat.model();
-                let prompt = chat.set_generating().to_string();
-                let config = &self.ollama_config.clone();
-                let api = config.instance();
-                Task::perform(api::chat_stream(api, model, prompt), move |stream| {
-                    Message::ChatStreamStart(ulid, stream)
-                })
+            Message::CompareToggleReasoning(side) => {
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.toggle_reasoning(side);
+                }
+                Task::none()
             }
-            Message::ChatStreamStart(ulid, chat_message_response_stream) => {
-                println!("chat stream start");
-                let ulid = ulid.clone();
-                Task::run(chat_message_response_stream.0, move |x| {
-                    Message::ChatStream(ulid, x.unwrap())
-                })
-                .chain(Task::done(Message::ChatStreamFinished(ulid)))
+            Message::CompareToggleCode(side, chunk) => {
+                let collapse_default = self.settings.collapse_code;
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.toggle_code(side, chunk, collapse_default);
+                }
+                Task::none()
             }
-            Message::ChatStream(ulid, chat_message_response) => {
-                if let Some(chat) = self.main.find_chat_mut(ulid) {
-                    chat.add_content(chat_message_response);
-                    Task::none()
-                } else {
-                    Task::none()
+            Message::CompareSourceAction(side, action) => {
+                if let Some(comparison) = &mut self.comparison {
+                    comparison.source_action(side, action);
                 }
+                Task::none()
             }
-            Message::ChatStreamFinished(ulid) => {
-                let to_save = if let Some(chat) = self.main.find_chat_mut(ulid) {
-                    chat.set_finish();
-                    let saved = chat.to_saved();
-                    Some(saved.clone())
-                } else {
-                    None
+            Message::CompareSave(side) => {
+                let Some(chat) = self.comparison.as_mut().and_then(|c| c.keep(side)) else {
+                    return Task::none();
                 };
-                if let Some(to_save) = to_save {
-                    self.add_history(to_save)
-                } else {
-                    Task::none()
-                }
+                self.notice = Some(format!("Saved as a new chat with {}", chat.model));
+                self.main.add_saved(chat.clone());
+                Task::batch([self.add_history(chat), self.write_session()])
             }
-            Message::SidebarVisibilityToggle => {
-                self.main.sidebar_visibility = self.main.sidebar_visibility.toggle();
+            Message::HistoryClear => {
+                self.pending_clear = Some(false);
                 Task::none()
             }
-            Message::CopyClipboard(s) => iced::clipboard::write(s.as_str().to_string()),
-            Message::LinkClicked(_) => Task::none(),
-            Message::ConfigWritingResult(r) => match r {
-                Ok(()) => Task::none(),
-                Err(e) => {
-                    println!("fail saving config {}", e);
-                    Task::none()
-                }
-            },
-            Message::HistoryWritingResult(r) => match r {
-                Ok(()) => Task::none(),
-                Err(e) => {
-                    println!("fail saving history {}", e);
-                    Task::none()
+            Message::HistoryClearConfirm => {
+                match self.pending_clear {
+                    None => return Task::none(),
+                    // the first click only arms the deletion
+                    Some(false) => {
+                        self.pending_clear = Some(true);
+                        return Task::none();
+                    }
+                    Some(true) => self.pending_clear = None,
                 }
-            },
-            Message::HistorySelected(ulid) => {
-                // check if the chat is already opened
-                if let Some(chat_idx) = self.main.find_chat_position(ulid) {
-                    self.main.chat_view = chat_idx;
-                    return Task::none();
-                }
-                if let Some(saved_chat) = self
+                let saved = self
                     .main
                     .sidebar
                     .chats
                     .iter()
-                    .find(|c| c.ulid == ulid)
-                    .map(|c| c.clone())
-                {
-                    self.main.add_saved(saved_chat);
-                    Task::none()
-                } else {
-                    Task::none()
+                    .map(|c| c.ulid)
+                    .collect::<Vec<_>>();
+                let mut tasks = Vec::new();
+                for ulid in saved {
+                    if self.main.find_chat_position(ulid).is_none() {
+                        continue;
+                    }
+                    tasks.extend(
+                        self.main
+                            .detached
+                            .iter()
+                            .filter(|(_, detached)| **detached == ulid)
+                            .map(|(window, _)| window::close(*window)),
+                    );
+                    // closing directly, a generating chat would otherwise wait for a
+                    // confirmation and write itself back in the cleared history
+                    tasks.push(self.close_chat(ulid));
                 }
+                let sidebar = &mut self.main.sidebar;
+                sidebar.chats = Arc::default();
+                sidebar.tag_filter = None;
+                sidebar.tagging = None;
+                sidebar.search.clear();
+                sidebar.search_results = None;
+                tasks.push(self.write_history());
+                Task::batch(tasks)
             }
-            Message::HistoryDelete(ulid) => {
-                if self.main.sidebar.remove_chat(ulid) {
-                    self.write_history()
-                } else {
-                    Task::none()
-                }
+            Message::HistoryClearCancel => {
+                self.pending_clear = None;
+                Task::none()
             }
             Message::SettingsClicked => {
                 self.show_settings = true;
@@ -299,48 +1825,340 @@ impl ThinkMate {
                 self.show_settings = false;
                 Task::none()
             }
+            Message::StatsClicked => {
+                self.stats = Some(stats::Stats::compute(&self.main.sidebar.chats));
+                Task::none()
+            }
+            Message::StatsClosed => {
+                self.stats = None;
+                Task::none()
+            }
             Message::SettingsChanged(message_settings) => {
+                let previous_interval = self.settings.poll_interval();
+                let previous = settings::serialize_settings(&self.settings);
                 self.settings.update(message_settings);
-                self.write_config()
+                // e.g. a host or a number still being typed
+                if settings::serialize_settings(&self.settings) == previous {
+                    return Task::none();
+                }
+                let config = self.settings.ollama_config();
+                let interval = self.settings.poll_interval();
+                if config != self.ollama_config {
+                    self.ollama_config = config.clone();
+                    self.set_connected(false);
+                    self.connection_error = None;
+                    self.connection_error_dismissed = false;
+                } else if interval == previous_interval {
+                    return self.write_config();
+                }
+                Task::batch([
+                    self.write_config(),
+                    self.send_worker(WorkerInput::Monitor(config, interval)),
+                ])
             }
         }
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        Subscription::run(background_worker)
+        let animation = if self.main.is_animating() {
+            iced::window::frames().map(|_| Message::AnimationTick)
+        } else {
+            Subscription::none()
+        };
+        let clock = if self.settings.relative_timestamps {
+            iced::time::every(RELATIVE_TIME_REFRESH).map(|_| Message::ClockTick)
+        } else {
+            Subscription::none()
+        };
+        let sidebar_resize = if self.sidebar_resizing {
+            iced::event::listen_with(|event, _status, id| match event {
+                iced::Event::Mouse(iced::mouse::Event::CursorMoved { position }) => {
+                    Some(Message::SidebarResize(id, position.x))
+                }
+                iced::Event::Mouse(iced::mouse::Event::ButtonReleased(
+                    iced::mouse::Button::Left,
+                )) => Some(Message::SidebarResizeEnd),
+                _ => None,
+            })
+        } else {
+            Subscription::none()
+        };
+        Subscription::batch([
+            Subscription::run(background_worker),
+            window::close_events().map(Message::WindowClosed),
+            window::resize_events().map(|(id, size)| Message::WindowResized(id, size)),
+            iced::event::listen_with(|event, status, id| match event {
+                iced::Event::Window(window::Event::Moved(position)) => {
+                    Some(Message::WindowMoved(id, position))
+                }
+                // the title input captures escape, which never reaches the shortcuts then
+                iced::Event::Keyboard(iced::keyboard::Event::KeyPressed {
+                    key: iced::keyboard::Key::Named(iced::keyboard::key::Named::Escape),
+                    ..
+                }) if status == iced::event::Status::Captured => Some(Message::ChatRenameCancel),
+                _ => None,
+            }),
+            iced::keyboard::on_key_press(|key, modifiers| {
+                Shortcut::from_key_press(key, modifiers).map(Message::Shortcut)
+            }),
+            animation,
+            clock,
+            sidebar_resize,
+        ])
     }
 
-    fn title(&self) -> String {
-        "ThinkMate".to_string()
+    fn title(&self, window: window::Id) -> String {
+        match self.main.detached_chat(window) {
+            Some(chat) => format!("ThinkMate - {}", chat.name()),
+            None => "ThinkMate".to_string(),
+        }
     }
 
-    fn theme(&self) -> Theme {
-        match self.settings.theme {
-            settings::SettingsTheme::Light => Theme::CatppuccinLatte,
-            settings::SettingsTheme::Dark => Theme::CatppuccinFrappe,
-        }
+    fn scale_factor(&self, _window: window::Id) -> f64 {
+        self.settings.ui_scale.factor()
+    }
+
+    fn theme(&self, _window: window::Id) -> Theme {
+        self.settings.theme.theme()
     }
 
-    fn view(&self) -> Container<Message> {
-        let inside = if self.show_settings {
+    fn view(&self, window: window::Id) -> Container<'_, Message> {
+        if window != self.main_window {
+            let inside = match self.main.detached_chat(window) {
+                Some(chat) => chat.view(&self.settings, &self.main.models),
+                None => container(column![]),
+            };
+            return container(inside).center(Length::Fill).padding(3);
+        }
+        let inside = if let Some(ulid) = self.pending_delete {
+            let description = self
+                .main
+                .sidebar
+                .chats
+                .iter()
+                .find(|c| c.ulid == ulid)
+                .map(|c| c.description())
+                .unwrap_or_default();
+            Element::from(dialog(
+                "Delete chat",
+                column![]
+                    .push(text(format!(
+                        "Delete \"{}\" from the history?",
+                        description
+                    )))
+                    .push(
+                        row![]
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::Trash, "Delete")
+                                    .style(button::danger)
+                                    .on_press(Message::HistoryDeleteConfirm),
+                            )
+                            .push(
+                                button(text("Cancel"))
+                                    .style(button::secondary)
+                                    .on_press(Message::HistoryDeleteCancel),
+                            )
+                            .spacing(10),
+                    )
+                    .spacing(20)
+                    .align_x(Alignment::Center),
+                Message::HistoryDeleteCancel,
+            ))
+        } else if !self.pending_close.is_empty() {
+            let question = match self.pending_close.as_slice() {
+                [ulid] => format!(
+                    "\"{}\" is still generating a reply. Close it anyway?",
+                    self.main
+                        .find_chat(*ulid)
+                        .map(|chat| chat.name())
+                        .unwrap_or_default()
+                ),
+                closing => format!(
+                    "{} chats are still generating a reply. Close them anyway?",
+                    closing.len()
+                ),
+            };
+            Element::from(dialog(
+                "Close chat",
+                column![]
+                    .push(text(question))
+                    .push(
+                        text("The reply generated so far is kept in the history.")
+                            .style(text::secondary),
+                    )
+                    .push(
+                        row![]
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::XLg, "Close")
+                                    .style(button::danger)
+                                    .on_press(Message::ChatCloseConfirm),
+                            )
+                            .push(
+                                button(text("Cancel"))
+                                    .style(button::secondary)
+                                    .on_press(Message::ChatCloseCancel),
+                            )
+                            .spacing(10),
+                    )
+                    .spacing(20)
+                    .align_x(Alignment::Center),
+                Message::ChatCloseCancel,
+            ))
+        } else if let Some(comparison) = &self.comparison {
+            Element::from(dialog(
+                "Compare models",
+                comparison.view(&self.main.models, &self.settings),
+                Message::CompareClose,
+            ))
+        } else if let Some(armed) = self.pending_clear {
+            let count = self.main.sidebar.chats.len();
+            let confirm = if armed {
+                button_icon_text(
+                    iced_fonts::Bootstrap::Trash,
+                    "Click again to delete everything",
+                )
+            } else {
+                button_icon_text(iced_fonts::Bootstrap::Trash, "Delete all chats")
+            };
+            Element::from(dialog(
+                "Clear history",
+                column![]
+                    .push(text(format!(
+                        "Delete all the {} chats of the history? This cannot be undone.",
+                        count
+                    )))
+                    .push(
+                        row![]
+                            .push(
+                                confirm
+                                    .style(button::danger)
+                                    .on_press(Message::HistoryClearConfirm),
+                            )
+                            .push(
+                                button(text("Cancel"))
+                                    .style(button::secondary)
+                                    .on_press(Message::HistoryClearCancel),
+                            )
+                            .spacing(10),
+                    )
+                    .spacing(20)
+                    .align_x(Alignment::Center),
+                Message::HistoryClearCancel,
+            ))
+        } else if let Some(stats) = &self.stats {
+            Element::from(dialog("Statistics", stats.view(), Message::StatsClosed))
+        } else if self.show_settings {
             Element::from(dialog(
                 "Settings",
-                self.settings.view().map(Message::SettingsChanged),
+                column![]
+                    .push(
+                        self.settings
+                            .view(&self.main.models)
+                            .map(Message::SettingsChanged),
+                    )
+                    .push(
+                        row![]
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::BarChart, "Statistics")
+                                    .on_press(Message::StatsClicked),
+                            )
+                            .push(
+                                button_icon_text(
+                                    iced_fonts::Bootstrap::FolderSymlink,
+                                    "Open config folder",
+                                )
+                                .on_press(Message::OpenConfigDir),
+                            )
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::Trash, "Clear history")
+                                    .style(button::danger)
+                                    .on_press_maybe(
+                                        (!self.main.sidebar.chats.is_empty())
+                                            .then_some(Message::HistoryClear),
+                                    ),
+                            )
+                            // read-only, but the path can still be selected and copied
+                            .push(iced::widget::text_input(
+                                "",
+                                &self.config_dir.display().to_string(),
+                            ))
+                            .spacing(10)
+                            .align_y(Alignment::Center),
+                    )
+                    .spacing(20),
                 Message::SettingsClosed,
             ))
         } else {
             column![]
-                .push(self.menubar.view().height(Length::Fixed(40.0)))
+                .push(
+                    self.menubar
+                        .view(
+                            &self.settings.templates,
+                            &self.settings.favorite_models,
+                            self.settings.default_model.as_deref(),
+                            self.main.tabs.iter().any(|chat| chat.is_generating()),
+                        )
+                        .height(Length::Fixed(40.0)),
+                )
+                .push_maybe(
+                    self.connection_error
+                        .as_ref()
+                        .map(|e| banner(e, Message::ConnectionErrorDismiss)),
+                )
+                .push_maybe(
+                    self.version_warning
+                        .as_ref()
+                        .map(|w| banner(w, Message::VersionWarningDismiss)),
+                )
+                .push_maybe(
+                    self.notice
+                        .as_ref()
+                        .map(|n| notice(n, Message::NoticeDismiss)),
+                )
                 .push(
                     row![]
-                        .push(self.main.view().width(Length::Fill))
+                        .push(self.main.view(&self.settings).width(Length::Fill))
                         .height(Length::Fill)
                         .width(Length::Fill)
                         .padding(Padding::default().top(5.0).top(5.0)),
                 )
                 .into()
         };
-        container(inside).center(Length::Fill).padding(3)
+        // a toast over everything, the failed write may come from a dialog
+        let undo = self.deleted.as_ref().map(|chat| {
+            container(
+                row![]
+                    .push(text(format!("Deleted \"{}\"", chat.description())).width(Length::Fill))
+                    .push(
+                        button(text("Undo"))
+                            .style(button::secondary)
+                            .on_press(Message::HistoryDeleteUndo),
+                    )
+                    .push(
+                        button_icon_small(iced_fonts::Bootstrap::X)
+                            .style(button::text)
+                            .on_press(Message::HistoryDeleteUndoExpired(None)),
+                    )
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+            )
+            .padding(5)
+            .max_width(600.0)
+            .style(container::rounded_box)
+        });
+        let error = self
+            .write_error
+            .as_ref()
+            .map(|e| banner(e, Message::WriteErrorDismiss).max_width(600.0));
+        let toast = (undo.is_some() || error.is_some()).then(|| {
+            container(column![].push_maybe(undo).push_maybe(error).spacing(10))
+                .align_bottom(Length::Fill)
+                .center_x(Length::Fill)
+                .padding(20)
+        });
+        container(stack![inside].push_maybe(toast))
+            .center(Length::Fill)
+            .padding(3)
     }
 }
 
@@ -350,82 +2168,265 @@ fn background_worker() -> impl Stream<Item = Message> {
 
         output.send(Message::WorkerReady(sender)).await.unwrap();
 
+        // the monitor task currently running (if any) and the config it was started
+        // with, so that it can be stopped on pause and restarted on resume
+        let mut monitoring: Option<tokio::task::JoinHandle<()>> = None;
+        let mut monitor_config: Option<(api::OllamaConfig, Duration)> = None;
+        let mut paused = false;
+
         loop {
             let input = receiver.select_next_some().await;
-            match input {
-                WorkerInput::Monitor(config) => {
+            let restart = match input {
+                WorkerInput::Monitor(config, interval) => {
+                    monitor_config = Some((config, interval));
+                    true
+                }
+                WorkerInput::Pause => {
+                    paused = true;
+                    false
+                }
+                WorkerInput::Resume => {
+                    paused = false;
+                    monitoring.is_none()
+                }
+                // restarting the monitor polls right away
+                WorkerInput::PollNow => true,
+            };
+            if paused || restart {
+                if let Some(handle) = monitoring.take() {
+                    handle.abort();
+                }
+            }
+            match &monitor_config {
+                Some((config, interval)) if restart && !paused => {
                     let output = output.clone();
-                    tokio::spawn(async move { monitor(output, config).await });
+                    let config = config.clone();
+                    let interval = *interval;
+                    monitoring = Some(tokio::spawn(async move {
+                        monitor(output, config, interval).await
+                    }));
                 }
+                _ => {}
             }
         }
     })
 }
 
-async fn monitor(mut output: mpsc::Sender<Message>, config: api::OllamaConfig) {
+async fn monitor(mut output: mpsc::Sender<Message>, config: api::OllamaConfig, interval: Duration) {
     let mut previous_models = Vec::new();
+    // asked again on every reconnection, the server may have been updated meanwhile
+    let mut version_checked = false;
     let api = config.instance();
     loop {
+        output.send(Message::Polling).await.unwrap();
         match api::get_model_lists(&api).await {
-            Err(_) => {
-                output.send(Message::Disconnected).await.unwrap();
+            Err(api::ConnectionFailed(e)) => {
+                version_checked = false;
+                output.send(Message::Disconnected(e)).await.unwrap();
             }
             Ok(models) => {
                 output.send(Message::Connected).await.unwrap();
                 if previous_models != models {
                     previous_models = models.clone();
                     output.send(Message::ModelsChanged(models)).await.unwrap();
-                } else {
+                }
+                // the models are usable even when a slow server does not tell its version
+                if !version_checked {
+                    version_checked = true;
+                    let version = tokio::time::timeout(VERSION_TIMEOUT, api::get_version(&api))
+                        .await
+                        .ok()
+                        .flatten();
+                    output.send(Message::ServerVersion(version)).await.unwrap();
                 }
             }
         }
-        tokio::time::sleep(Duration::new(10, 0)).await
+        tokio::time::sleep(interval).await
+    }
+}
+
+/// A model offered by the menubar, with its size and date to tell apart its quantizations
+#[derive(Clone)]
+struct ModelOption(api::LocalModel);
+
+impl std::fmt::Display for ModelOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}  ·  {}  ·  {}",
+            self.0.name(),
+            self.0.size(),
+            self.0.modified()
+        )
     }
 }
 
 pub struct Menubar {
     connected: bool,
-    model: combo_box::State<api::LocalModel>,
+    paused: bool,
+    // a check of the server is in flight
+    polling: bool,
+    // version of the server, when connected and known
+    version: Option<String>,
+    model: combo_box::State<ModelOption>,
     selected: Option<api::LocalModel>,
 }
 
+impl Default for Menubar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Menubar {
     pub fn new() -> Self {
         Self {
             connected: false,
+            paused: false,
+            polling: false,
+            version: None,
             model: combo_box::State::new(vec![]),
             selected: None,
         }
     }
 
-    pub fn view(&self) -> Container<Message> {
-        let indicator_color = if self.connected {
+    pub fn view<'a>(
+        &'a self,
+        templates: &'a [settings::ChatTemplate],
+        favorites: &[String],
+        default_model: Option<&str>,
+        generating: bool,
+    ) -> Container<'a, Message> {
+        let favorite = self
+            .selected
+            .as_ref()
+            .is_some_and(|model| favorites.contains(model.name()));
+        let default = self
+            .selected
+            .as_ref()
+            .is_some_and(|model| Some(model.name().as_str()) == default_model);
+        let indicator_color = if self.paused {
+            Color::from_rgb8(0x9f, 0x9f, 0x9f)
+        } else if self.connected {
             Color::from_rgb8(0, 0x9f, 0)
         } else {
             Color::from_rgb8(0x9f, 0, 0)
         };
+        let status = match (self.paused, self.connected, &self.version) {
+            (true, _, _) => "Paused".to_string(),
+            (false, true, Some(version)) => format!("Connected to Ollama {}", version),
+            (false, true, None) => "Connected to Ollama".to_string(),
+            (false, false, _) => "Disconnected".to_string(),
+        };
+        let pause_icon = if self.paused {
+            iced_fonts::Bootstrap::PlayFill
+        } else {
+            iced_fonts::Bootstrap::PauseFill
+        };
         let mut title_font = iced::Font::DEFAULT;
         title_font.weight = Weight::ExtraBold;
+        let no_model = self.connected && self.model.options().is_empty();
+        let models = combo_box(
+            &self.model,
+            if no_model {
+                "No model installed"
+            } else {
+                "Select Model"
+            },
+            self.selected.clone().map(ModelOption).as_ref(),
+            |option| Message::ModelSelected(option.0),
+        )
+        .width(Length::Fixed(320.0));
+        let models: Element<'a, Message> = if no_model {
+            tooltip(
+                models,
+                container(text(format!("Install one with `{}`", PULL_EXAMPLE)).size(12.0))
+                    .padding(5)
+                    .style(container::rounded_box),
+                tooltip::Position::Bottom,
+            )
+            .into()
+        } else {
+            models.into()
+        };
         container(
             row![]
                 .push(button_icon(iced_fonts::Bootstrap::Gear).on_press(Message::SettingsClicked))
                 .push(text("ThinkMate").font(title_font).size(20.0))
                 .push(horizontal_space())
+                .push(models)
                 .push(
-                    combo_box(
-                        &self.model,
-                        "Select Model",
-                        self.selected.as_ref(),
-                        Message::ModelSelected,
-                    )
-                    .width(Length::Fixed(180.0)),
+                    button_icon(if favorite {
+                        iced_fonts::Bootstrap::StarFill
+                    } else {
+                        iced_fonts::Bootstrap::Star
+                    })
+                    .style(button::secondary)
+                    .on_press_maybe(
+                        self.selected
+                            .as_ref()
+                            .map(|s| Message::ModelFavoriteToggle(s.name().clone())),
+                    ),
                 )
+                .push(tooltip(
+                    button_icon(if default {
+                        iced_fonts::Bootstrap::BookmarkCheckFill
+                    } else {
+                        iced_fonts::Bootstrap::Bookmark
+                    })
+                    .style(button::secondary)
+                    .on_press_maybe(
+                        self.selected
+                            .as_ref()
+                            .map(|s| Message::ModelDefaultToggle(s.name().clone())),
+                    ),
+                    container(
+                        text(if default {
+                            "Selected on start"
+                        } else {
+                            "Select this model on start"
+                        })
+                        .size(12.0),
+                    )
+                    .padding(5)
+                    .style(container::rounded_box),
+                    tooltip::Position::Bottom,
+                ))
                 .push(
                     button_icon_text(iced_fonts::Bootstrap::Plus, "New Chat").on_press_maybe(
                         self.selected.as_ref().map(|s| Message::NewChat(s.clone())),
                     ),
                 )
-                .push(Indicator::new().circle_radius(8.0).color(indicator_color))
+                .push(
+                    pick_list(
+                        templates,
+                        None::<settings::ChatTemplate>,
+                        Message::NewChatFromTemplate,
+                    )
+                    .placeholder("From template...")
+                    .width(Length::Fixed(160.0)),
+                )
+                .push_maybe(generating.then(|| {
+                    button_icon_text(iced_fonts::Bootstrap::StopFill, "Stop all")
+                        .style(button::danger)
+                        .on_press(Message::StopAll)
+                }))
+                .push_maybe((!self.connected && !self.paused).then(|| {
+                    button_icon_text(iced_fonts::Bootstrap::ArrowRepeat, "Reconnect")
+                        .style(button::secondary)
+                        .on_press(Message::Reconnect)
+                }))
+                .push(button_icon(pause_icon).on_press(Message::MonitorPauseToggle))
+                .push(tooltip(
+                    Indicator::new()
+                        .circle_radius(8.0)
+                        .color(indicator_color)
+                        .pulsing(self.polling),
+                    container(text(status).size(12.0))
+                        .padding(5)
+                        .style(container::rounded_box),
+                    tooltip::Position::Left,
+                ))
                 .spacing(10.0)
                 .align_y(Alignment::Center),
         )
@@ -434,11 +2435,33 @@ impl Menubar {
         .style(container::bordered_box)
     }
 
-    pub fn set_models(&mut self, models: Vec<api::LocalModel>) {
-        if models.is_empty() {
-            self.selected = None;
-        }
-        self.model = combo_box::State::with_selection(models, self.selected.as_ref());
+    /// Offer the models, the favorite ones first. The default model is selected when nothing
+    /// else is
+    pub fn set_models(
+        &mut self,
+        mut models: Vec<api::LocalModel>,
+        favorites: &[String],
+        default_model: Option<&str>,
+    ) {
+        // matched by name, as a model pulled again keeps its name but changes of size and date;
+        // a model which is gone is no longer selected
+        self.selected = self
+            .selected
+            .take()
+            .and_then(|selected| models.iter().find(|m| m.name() == selected.name()).cloned())
+            .or_else(|| {
+                models
+                    .iter()
+                    .find(|m| Some(m.name().as_str()) == default_model)
+                    .cloned()
+            });
+        // stable, so that the order of the server is kept within each group
+        models.sort_by_key(|model| !favorites.contains(model.name()));
+        let selection = self.selected.clone().map(ModelOption);
+        self.model = combo_box::State::with_selection(
+            models.into_iter().map(ModelOption).collect(),
+            selection.as_ref(),
+        );
     }
 }
 
@@ -446,8 +2469,19 @@ pub struct Main {
     home: EmptyChats,
     chat_view: usize,
     tabs: Vec<Chat>,
+    // chats shown in their own window instead of the tab bar
+    detached: HashMap<window::Id, Ulid>,
     sidebar: Sidebar,
     sidebar_visibility: SidebarVisibility,
+    // running generations, to stop them on request
+    generations: HashMap<Ulid, iced::task::Handle>,
+    renaming: Option<Renaming>,
+    // bumped on every edit of a prompt, so that only the last one saves the drafts
+    draft_generation: u64,
+    // models available on the server, to start a chat or switch the model of one
+    models: Vec<api::LocalModel>,
+    // the server answered its last check
+    connected: bool,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -472,30 +2506,62 @@ impl Main {
             home: EmptyChats::new(),
             chat_view: 0,
             tabs: vec![],
+            detached: HashMap::new(),
             sidebar: Sidebar::new(chats),
             sidebar_visibility: SidebarVisibility::default(),
+            generations: HashMap::new(),
+            renaming: None,
+            draft_generation: 0,
+            models: vec![],
+            connected: false,
         }
     }
 
-    pub fn view(&self) -> Container<Message> {
-        let main = if self.tabs.is_empty() {
-            container(self.home.view())
+    pub fn view<'a>(&'a self, settings: &'a settings::Settings) -> Container<'a, Message> {
+        let main = if self.tabs.iter().all(|chat| self.is_detached(chat.ulid())) {
+            container(self.home.view(self.models.first(), self.connected))
         } else {
             let view = self.chat_view;
             let tab_bar_elements = self
                 .tabs
                 .iter()
                 .enumerate()
+                .filter(|(_, chat)| !self.is_detached(chat.ulid()))
                 .map(|(i, chat)| {
                     let selected = i == view;
-                    let label = text(chat.name());
+                    let ulid = chat.ulid();
+                    let label: Element<'a, Message> = match &self.renaming {
+                        Some(renaming) if renaming.ulid == chat.ulid() && !renaming.in_sidebar => {
+                            renaming.view().width(200.0).into()
+                        }
+                        _ => text(chat.name()).into(),
+                    };
+                    let rename = button_icon_small(iced_fonts::Bootstrap::Pencil)
+                        .padding(1.0)
+                        .style(button::secondary)
+                        .on_press(Message::ChatRename(chat.ulid(), false));
+                    let detach = button_icon_small(iced_fonts::Bootstrap::BoxArrowUpRight)
+                        .padding(1.0)
+                        .style(button::secondary)
+                        .on_press(Message::ChatDetach(chat.ulid()));
+                    let copy = button_icon_small(iced_fonts::Bootstrap::Clipboard)
+                        .padding(1.0)
+                        .style(button::secondary)
+                        .on_press(Message::CopyChat(chat.ulid()));
+                    let generating = chat
+                        .is_generating()
+                        .then(|| iced_aw::Spinner::new().width(12.0).height(12.0));
                     let close = button_icon_small(iced_fonts::Bootstrap::X)
                         .padding(1.0)
-                        .style(|theme, status| button::danger(theme, status))
+                        .style(button::danger)
                         .on_press(Message::ChatClosed(chat.ulid()));
-                    button(
+                    let tab = button(
                         row![]
+                            .push_maybe(generating)
                             .push(label)
+                            .push(rename)
+                            .push(detach)
+                            .push(copy)
                             .push(close)
                             .spacing(10.0)
                             .align_y(Alignment::Center),
@@ -507,44 +2573,276 @@ impl Main {
                         } else {
                             button::secondary(theme, status)
                         }
-                    })
+                    });
+                    iced_aw::ContextMenu::new(tab, move || Self::view_tab_menu(ulid))
                 })
-                .map(|b| Element::from(b));
+                .map(Element::from);
             let tab_bar = row(tab_bar_elements).width(Length::Fill).spacing(5.0);
-            if let Some(chat) = self.tabs.get(view) {
+            if let Some(chat) = self
+                .tabs
+                .get(view)
+                .filter(|chat| !self.is_detached(chat.ulid()))
+            {
                 container(
                     column![]
                         .push(tab_bar)
                         .push(horizontal_rule(1.0))
                         .push(vertical_space().height(5.0))
-                        .push(chat.view()),
+                        .push(chat.view(settings, &self.models)),
                 )
             } else {
                 container(column![].push(tab_bar))
             }
         };
 
-        let sidebar = match self.sidebar_visibility {
-            SidebarVisibility::Expanded => self.sidebar.view().width(Length::FillPortion(9)),
-            SidebarVisibility::Collapsed => {
-                self.sidebar.view_collapse().width(Length::FillPortion(1))
+        match self.sidebar_visibility {
+            SidebarVisibility::Expanded => {
+                // the sidebar takes its share of the window in thousandths
+                let portion = (settings.sidebar_width() * 1000.0) as u16;
+                let sidebar = self
+                    .sidebar
+                    .view(self.renaming.as_ref(), settings.relative_timestamps)
+                    .width(Length::FillPortion(portion));
+                let edge = mouse_area(container(vertical_rule(1.0)).padding([0, 3]))
+                    .on_press(Message::SidebarResizeStart)
+                    .interaction(iced::mouse::Interaction::ResizingHorizontally);
+                container(
+                    row![]
+                        .push(main.width(Length::FillPortion(1000 - portion)))
+                        .push(edge)
+                        .push(sidebar),
+                )
             }
+            SidebarVisibility::Collapsed => container(
+                row![]
+                    .push(main.width(Length::FillPortion(32)))
+                    .push(self.sidebar.view_collapse().width(Length::FillPortion(1))),
+            ),
+        }
+    }
+
+    // opened with a right click on a tab
+    fn view_tab_menu<'a>(ulid: Ulid) -> Element<'a, Message> {
+        let item = |icon, label, message| {
+            button_icon_text(icon, label)
+                .style(button::text)
+                .width(Length::Fill)
+                .on_press(message)
         };
         container(
-            row![]
-                .push(main.width(Length::FillPortion(32)))
-                .push(sidebar),
+            column![]
+                .push(item(
+                    iced_fonts::Bootstrap::X,
+                    "Close",
+                    Message::ChatClosed(ulid),
+                ))
+                .push(item(
+                    iced_fonts::Bootstrap::XSquare,
+                    "Close others",
+                    Message::ChatCloseOthers(ulid),
+                ))
+                .push(item(
+                    iced_fonts::Bootstrap::XCircle,
+                    "Close all",
+                    Message::ChatCloseAll,
+                ))
+                .push(horizontal_rule(1.0))
+                .push(item(
+                    iced_fonts::Bootstrap::ArrowLeft,
+                    "Move left",
+                    Message::ChatMove(ulid, false),
+                ))
+                .push(item(
+                    iced_fonts::Bootstrap::ArrowRight,
+                    "Move right",
+                    Message::ChatMove(ulid, true),
+                ))
+                .push(horizontal_rule(1.0))
+                .push(item(
+                    iced_fonts::Bootstrap::Files,
+                    "Duplicate",
+                    Message::HistoryFork(ulid),
+                ))
+                .push(item(
+                    iced_fonts::Bootstrap::Pencil,
+                    "Rename",
+                    Message::ChatRename(ulid, false),
+                ))
+                .push(item(
+                    iced_fonts::Bootstrap::Download,
+                    "Export",
+                    Message::ExportChat(ulid),
+                )),
         )
+        .width(180.0)
+        .padding(5.0)
+        .style(container::rounded_box)
+        .into()
     }
 
     pub fn add_new(&mut self, model: api::LocalModel) {
         self.tabs.push(Chat::new(model))
     }
 
+    pub fn add_template(&mut self, model: String, template: &settings::ChatTemplate) {
+        self.tabs.push(Chat::from_template(model, template))
+    }
+
     pub fn add_saved(&mut self, saved_chat: SavedChat<String>) {
         self.tabs.push(Chat::from_saved(saved_chat))
     }
 
+    /// The tabs to reopen on the next start, only those saved in the history can be
+    pub fn session(&self) -> session::Session {
+        session::Session {
+            tabs: self.tabs.iter().map(|chat| chat.ulid()).collect(),
+            active: self.tabs.get(self.chat_view).map(|chat| chat.ulid()),
+            drafts: self
+                .tabs
+                .iter()
+                .filter_map(|chat| Some((chat.ulid(), chat.draft()?)))
+                .collect(),
+        }
+    }
+
+    /// Reopen the tabs of a previous session, skipping the chats deleted since
+    pub fn restore(&mut self, session: &session::Session) {
+        for ulid in &session.tabs {
+            if let Some(chat) = self.sidebar.chats.iter().find(|c| c.ulid == *ulid) {
+                let mut chat = Chat::from_saved(chat.clone());
+                if let Some(draft) = session.drafts.get(ulid) {
+                    chat.set_draft(draft);
+                }
+                self.tabs.push(chat);
+            }
+        }
+        if let Some(idx) = session
+            .active
+            .and_then(|ulid| self.find_chat_position(ulid))
+        {
+            self.chat_view = idx;
+        }
+    }
+
+    pub fn is_animating(&self) -> bool {
+        self.tabs
+            .get(self.chat_view)
+            .is_some_and(|c| c.is_animating())
+            || self
+                .detached
+                .values()
+                .any(|ulid| self.find_chat(*ulid).is_some_and(|c| c.is_animating()))
+    }
+
+    pub fn is_detached(&self, ulid: Ulid) -> bool {
+        self.detached.values().any(|u| *u == ulid)
+    }
+
+    pub fn detached_chat(&self, window: window::Id) -> Option<&Chat> {
+        self.detached
+            .get(&window)
+            .and_then(|ulid| self.find_chat(*ulid))
+    }
+
+    /// Move a chat out of the tab bar into the given window
+    pub fn detach(&mut self, window: window::Id, ulid: Ulid) {
+        self.detached.insert(window, ulid);
+        if self.find_chat_position(ulid) == Some(self.chat_view) {
+            if let Some(idx) = self
+                .tabs
+                .iter()
+                .position(|chat| !self.is_detached(chat.ulid()))
+            {
+                self.chat_view = idx;
+            }
+        }
+    }
+
+    /// Scroll the shown tab back to where it was left
+    pub fn restore_scroll(&self) -> Task<Message> {
+        match self.tabs.get(self.chat_view) {
+            Some(chat) if !self.is_detached(chat.ulid()) => chat.restore_scroll(),
+            _ => Task::none(),
+        }
+    }
+
+    // index of the tabs shown in the tab bar
+    fn docked_tabs(&self) -> Vec<usize> {
+        (0..self.tabs.len())
+            .filter(|idx| !self.is_detached(self.tabs[*idx].ulid()))
+            .collect()
+    }
+
+    /// Show the next (or previous) tab of the tab bar, wrapping around at the ends. Returns
+    /// whether the shown tab changed
+    pub fn cycle_tab(&mut self, forward: bool) -> bool {
+        let docked = self.docked_tabs();
+        if docked.is_empty() {
+            return false;
+        }
+        let next = match docked.iter().position(|idx| *idx == self.chat_view) {
+            None => 0,
+            Some(pos) if forward => (pos + 1) % docked.len(),
+            Some(pos) => (pos + docked.len() - 1) % docked.len(),
+        };
+        let changed = docked[next] != self.chat_view;
+        self.chat_view = docked[next];
+        changed
+    }
+
+    /// Swap a tab with the next (or previous) one of the tab bar, the shown tab staying the
+    /// same. Returns whether the tab moved
+    pub fn move_tab(&mut self, ulid: Ulid, forward: bool) -> bool {
+        let docked = self.docked_tabs();
+        let Some(pos) = docked.iter().position(|idx| self.tabs[*idx].ulid() == ulid) else {
+            return false;
+        };
+        let other = if forward {
+            docked.get(pos + 1)
+        } else {
+            pos.checked_sub(1).and_then(|pos| docked.get(pos))
+        };
+        let (from, Some(&to)) = (docked[pos], other) else {
+            return false;
+        };
+        self.tabs.swap(from, to);
+        if self.chat_view == from {
+            self.chat_view = to;
+        } else if self.chat_view == to {
+            self.chat_view = from;
+        }
+        true
+    }
+
+    /// Show the n-th tab of the tab bar, or the last one if there are fewer. Returns whether
+    /// the shown tab changed
+    pub fn select_tab(&mut self, n: usize) -> bool {
+        let docked = self.docked_tabs();
+        let Some(idx) = docked.get(n).or(docked.last()).copied() else {
+            return false;
+        };
+        let changed = idx != self.chat_view;
+        self.chat_view = idx;
+        changed
+    }
+
+    /// Put the chat of a closed window back in the tab bar
+    pub fn attach(&mut self, window: window::Id) {
+        if let Some(ulid) = self.detached.remove(&window) {
+            if let Some(idx) = self.find_chat_position(ulid) {
+                self.chat_view = idx;
+            }
+        }
+    }
+
+    /// The chat as saved, from its tab if opened or else from the history
+    pub fn saved_chat(&self, ulid: Ulid) -> Option<SavedChat<String>> {
+        match self.find_chat(ulid) {
+            Some(chat) => Some(chat.to_saved()),
+            None => self.sidebar.chats.iter().find(|c| c.ulid == ulid).cloned(),
+        }
+    }
+
     pub fn find_chat_position(&self, ulid: Ulid) -> Option<usize> {
         self.tabs.iter().position(|chat| chat.ulid() == ulid)
     }
@@ -561,22 +2859,171 @@ impl Main {
 #[derive(Clone)]
 pub struct EmptyChats {}
 
+impl Default for EmptyChats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EmptyChats {
     pub fn new() -> Self {
         EmptyChats {}
     }
 
-    pub fn view(&self) -> Container<Message> {
+    pub fn view<'a>(
+        &self,
+        first_model: Option<&'a api::LocalModel>,
+        connected: bool,
+    ) -> Container<'a, Message> {
+        let hint: Element<'a, Message> = match first_model {
+            Some(model) => text(format!(
+                "New chats use {} unless another model is selected",
+                model
+            ))
+            .size(12.0)
+            .style(text::secondary)
+            .into(),
+            None if connected => column![]
+                .push(
+                    text("The server has no model installed, download one from a terminal:")
+                        .size(12.0)
+                        .style(text::secondary),
+                )
+                .push(
+                    row![]
+                        .push(
+                            container(text(PULL_EXAMPLE).font(Font::MONOSPACE))
+                                .padding(5)
+                                .style(container::bordered_box),
+                        )
+                        .push(
+                            button_icon_small(iced_fonts::Bootstrap::Clipboard)
+                                .style(button::secondary)
+                                .on_press(Message::CopyClipboard(Arc::new(
+                                    PULL_EXAMPLE.to_string(),
+                                ))),
+                        )
+                        .spacing(5)
+                        .align_y(Alignment::Center),
+                )
+                .push(
+                    text("Models appear here once downloaded")
+                        .size(12.0)
+                        .style(text::secondary),
+                )
+                .spacing(5)
+                .align_x(Alignment::Center)
+                .into(),
+            None => text("Waiting for the server to list its models")
+                .size(12.0)
+                .style(text::secondary)
+                .into(),
+        };
         container(
             column![]
                 .push(
                     text(
                         "To get started create a new chat or open a previous chat from the sidebar",
                     )
-                    .style(|theme| text::secondary(theme)),
+                    .style(text::secondary),
                 )
-                .spacing(10.0),
+                .push(
+                    button_icon_text(iced_fonts::Bootstrap::Plus, "New Chat")
+                        .on_press_maybe(first_model.map(|model| Message::NewChat(model.clone()))),
+                )
+                .push(hint)
+                .spacing(10.0)
+                .align_x(Alignment::Center),
         )
         .center(Length::Fill)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(menubar: &Menubar) -> Vec<String> {
+        menubar
+            .model
+            .options()
+            .iter()
+            .map(|m| m.0.name().clone())
+            .collect()
+    }
+
+    fn selected(menubar: &Menubar) -> Option<&str> {
+        menubar.selected.as_ref().map(|m| m.name().as_str())
+    }
+
+    #[test]
+    fn models_added() {
+        let mut menubar = Menubar::new();
+        menubar.set_models(vec![api::LocalModel::with_size("a", 1)], &[], None);
+        menubar.selected = Some(api::LocalModel::with_size("a", 1));
+        let models = ["c", "a", "b"].map(|name| api::LocalModel::with_size(name, 1));
+        menubar.set_models(models.to_vec(), &[], None);
+        assert_eq!(names(&menubar), ["c", "a", "b"]);
+        assert_eq!(selected(&menubar), Some("a"));
+    }
+
+    #[test]
+    fn models_removed() {
+        let mut menubar = Menubar::new();
+        menubar.selected = Some(api::LocalModel::with_size("a", 1));
+        menubar.set_models(vec![api::LocalModel::with_size("b", 1)], &[], None);
+        assert_eq!(names(&menubar), ["b"]);
+        assert_eq!(selected(&menubar), None);
+        menubar.set_models(vec![], &[], None);
+        assert!(names(&menubar).is_empty());
+    }
+
+    #[test]
+    fn models_reordered() {
+        let mut menubar = Menubar::new();
+        menubar.selected = Some(api::LocalModel::with_size("a", 1));
+        // a pulled again keeps its selection, with its new size
+        let models = ["a", "b", "c"].map(|name| api::LocalModel::with_size(name, 2));
+        menubar.set_models(models.to_vec(), &["c".to_string()], None);
+        assert_eq!(names(&menubar), ["c", "a", "b"]);
+        assert_eq!(menubar.selected, Some(api::LocalModel::with_size("a", 2)));
+    }
+
+    #[test]
+    fn default_model_selected() {
+        let mut menubar = Menubar::new();
+        let models = ["a", "b"].map(|name| api::LocalModel::with_size(name, 1));
+        menubar.set_models(models.to_vec(), &[], Some("b"));
+        assert_eq!(selected(&menubar), Some("b"));
+        menubar.selected = Some(api::LocalModel::with_size("a", 1));
+        menubar.set_models(models.to_vec(), &[], Some("b"));
+        assert_eq!(selected(&menubar), Some("a"));
+    }
+
+    #[test]
+    fn rename_keeps_default_title() {
+        let renaming = |title: &str, default: Option<&str>| Renaming {
+            ulid: Ulid::new(),
+            title: title.to_string(),
+            default: default.map(str::to_string),
+            in_sidebar: false,
+        };
+        let default = Some("Chat 2025-01-02 10:00:00");
+        assert_eq!(
+            renaming("Chat 2025-01-02 10:00:00", default).submitted_title(),
+            None
+        );
+        assert_eq!(renaming("  ", default).submitted_title(), None);
+        assert_eq!(
+            renaming(" Trip plans ", default)
+                .submitted_title()
+                .as_deref(),
+            Some("Trip plans")
+        );
+        // a title of its own is kept when submitted unchanged
+        assert_eq!(
+            renaming("Trip plans", None).submitted_title().as_deref(),
+            Some("Trip plans")
+        );
+    }
+}
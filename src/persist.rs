@@ -0,0 +1,109 @@
+// durable file writing shared by the history and the settings
+use std::{
+    io::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::io::AsyncWriteExt;
+
+const RENAME_ATTEMPTS: u32 = 3;
+
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn with_context(e: Error, what: &str, path: &Path) -> Error {
+    Error::new(e.kind(), format!("{} {}: {}", what, path.display(), e))
+}
+
+/// Write the content to a temporary file next to `path`, flush it to disk, and then
+/// move it over `path`.
+///
+/// The rename is retried a few times, and if it still fails (e.g. on some network shares)
+/// the temporary file is copied over instead. If everything fails, the temporary file is
+/// left in place so that [`recover`] can pick it up on the next start.
+pub async fn write_atomic(path: PathBuf, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = tmp_path(&path);
+
+    let mut file = tokio::fs::File::create(&tmp_path)
+        .await
+        .map_err(|e| with_context(e, "cannot create", &tmp_path))?;
+    file.write_all(content)
+        .await
+        .map_err(|e| with_context(e, "cannot write", &tmp_path))?;
+    file.sync_all()
+        .await
+        .map_err(|e| with_context(e, "cannot sync", &tmp_path))?;
+    drop(file);
+
+    let mut attempt = 0;
+    let rename_err = loop {
+        match tokio::fs::rename(&tmp_path, &path).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                attempt += 1;
+                if attempt == RENAME_ATTEMPTS {
+                    break e;
+                }
+                tokio::time::sleep(Duration::from_millis(50 * attempt as u64)).await;
+            }
+        }
+    };
+
+    tracing::warn!(
+        "rename of {} failed ({}), falling back to copy",
+        tmp_path.display(),
+        rename_err
+    );
+    match tokio::fs::copy(&tmp_path, &path).await {
+        Ok(_) => {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            Ok(())
+        }
+        Err(e) => Err(Error::other(format!(
+            "cannot replace {} (rename: {}, copy: {}), data kept in {}",
+            path.display(),
+            rename_err,
+            e,
+            tmp_path.display()
+        ))),
+    }
+}
+
+/// Finish an interrupted [`write_atomic`], if a leftover temporary file is newer than `path`
+/// and is accepted by `is_valid`.
+pub fn recover(path: &Path, is_valid: impl FnOnce(&[u8]) -> bool) {
+    let tmp_path = tmp_path(path);
+    let Ok(tmp_meta) = std::fs::metadata(&tmp_path) else {
+        return;
+    };
+    let newer = match (
+        tmp_meta.modified(),
+        std::fs::metadata(path).and_then(|m| m.modified()),
+    ) {
+        (Ok(tmp), Ok(current)) => tmp > current,
+        (_, Err(_)) => true,
+        (Err(_), _) => false,
+    };
+    if !newer {
+        return;
+    }
+    let Ok(content) = std::fs::read(&tmp_path) else {
+        return;
+    };
+    if !is_valid(&content) {
+        tracing::warn!("ignoring invalid leftover {}", tmp_path.display());
+        return;
+    }
+    let result = std::fs::rename(&tmp_path, path).or_else(|_| {
+        std::fs::write(path, &content)?;
+        std::fs::remove_file(&tmp_path)
+    });
+    match result {
+        Ok(()) => tracing::info!("recovered {} from {}", path.display(), tmp_path.display()),
+        Err(e) => tracing::error!("cannot recover {}: {}", tmp_path.display(), e),
+    }
+}
@@ -0,0 +1,36 @@
+// the chats opened in tabs, restored on the next start
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub tabs: Vec<Ulid>,
+    pub active: Option<Ulid>,
+    /// prompts being written in the tabs, not sent yet
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub drafts: HashMap<Ulid, String>,
+}
+
+const SESSION_FILE_NAME: &str = "session.json";
+
+pub fn read_session(path: &Path) -> Session {
+    let path = path.join(SESSION_FILE_NAME);
+    let Ok(file) = std::fs::File::open(&path) else {
+        return Session::default();
+    };
+    serde_json::from_reader(&file).unwrap_or_default()
+}
+
+pub fn serialize_session(session: &Session) -> String {
+    serde_json::to_string_pretty(session).unwrap()
+}
+
+pub async fn write_session(path: PathBuf, session: String) -> std::io::Result<()> {
+    crate::persist::write_atomic(path.join(SESSION_FILE_NAME), session.as_bytes()).await
+}
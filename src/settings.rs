@@ -1,47 +1,576 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
 
 use iced::{
-    widget::{column, container, pick_list, row, text},
-    Alignment, Element,
+    highlighter,
+    widget::{
+        button, checkbox, column, container, pick_list, row, scrollable, text, text_input, tooltip,
+    },
+    window, Alignment, Element, Length, Point, Size,
 };
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum SettingsTheme {
-    Light,
-    Dark,
-}
+use crate::{
+    api,
+    helper::{button_icon, button_icon_text},
+};
+
+/// One of the built-in themes of iced, stored by name
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "String", into = "String")]
+pub struct SettingsTheme(iced::Theme);
 
 impl std::fmt::Display for SettingsTheme {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "{}", self.0)
     }
 }
 
+// names stored by older versions, which only offered two Catppuccin variants
+const LEGACY_LIGHT: &str = "Light";
+const LEGACY_DARK: &str = "Dark";
+
 impl SettingsTheme {
-    pub const ALL: [Self; 2] = [SettingsTheme::Light, SettingsTheme::Dark];
+    /// Every built-in theme, except the plain light and dark ones whose names are taken by
+    /// older configurations
+    pub fn all() -> Vec<Self> {
+        iced::Theme::ALL
+            .iter()
+            .filter(|theme| **theme != iced::Theme::Light && **theme != iced::Theme::Dark)
+            .cloned()
+            .map(SettingsTheme)
+            .collect()
+    }
+
+    pub fn theme(&self) -> iced::Theme {
+        self.0.clone()
+    }
+
+    pub fn is_dark(&self) -> bool {
+        self.0.extended_palette().is_dark
+    }
+}
+
+impl From<String> for SettingsTheme {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            LEGACY_LIGHT => SettingsTheme(iced::Theme::CatppuccinLatte),
+            LEGACY_DARK => SettingsTheme(iced::Theme::CatppuccinFrappe),
+            _ => Self::all()
+                .into_iter()
+                .find(|theme| theme.to_string() == name)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<SettingsTheme> for String {
+    fn from(theme: SettingsTheme) -> Self {
+        theme.to_string()
+    }
 }
 
 impl Default for SettingsTheme {
     fn default() -> Self {
         let system_use_dark = iced::Theme::default() == iced::Theme::Dark;
         if system_use_dark {
-            SettingsTheme::Dark
+            SettingsTheme(iced::Theme::CatppuccinFrappe)
         } else {
-            SettingsTheme::Light
+            SettingsTheme(iced::Theme::CatppuccinLatte)
         }
     }
 }
 
-#[derive(Default, Serialize, Deserialize)]
+/// Scaling of the whole interface, in percent
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct UiScale(u16);
+
+impl std::fmt::Display for UiScale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.0)
+    }
+}
+
+impl Default for UiScale {
+    fn default() -> Self {
+        UiScale(100)
+    }
+}
+
+impl UiScale {
+    pub const ALL: [Self; 8] = [
+        UiScale(75),
+        UiScale(90),
+        UiScale(100),
+        UiScale(110),
+        UiScale(125),
+        UiScale(150),
+        UiScale(175),
+        UiScale(200),
+    ];
+
+    /// The factor applied to every window, a hand-edited value being kept within the offered range
+    pub fn factor(self) -> f64 {
+        self.0
+            .clamp(Self::ALL[0].0, Self::ALL[Self::ALL.len() - 1].0) as f64
+            / 100.0
+    }
+}
+
+/// Size and position of the main window, in logical pixels of the system scaling
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WindowGeometry {
+    pub width: f32,
+    pub height: f32,
+    pub position: Option<(f32, f32)>,
+}
+
+// the position to restore, only readable by a plain function given to `window::Position`
+static RESTORED_POSITION: OnceLock<(f32, f32)> = OnceLock::new();
+
+// part of the window which must be on the screen for the saved position to be used
+const MIN_VISIBLE: f32 = 100.0;
+
+impl Default for WindowGeometry {
+    fn default() -> Self {
+        Self {
+            width: 1280.0,
+            height: 1024.0,
+            position: None,
+        }
+    }
+}
+
+impl WindowGeometry {
+    pub fn size(&self) -> Size {
+        Size::new(self.width, self.height)
+    }
+
+    /// The saved position, or centered if there is none or it would be off the screen
+    /// (e.g. the monitor it was on has been unplugged)
+    pub fn position(&self) -> window::Position {
+        match self.position {
+            Some(position) => {
+                let _ = RESTORED_POSITION.set(position);
+                window::Position::SpecificWith(Self::restore_position)
+            }
+            None => window::Position::Centered,
+        }
+    }
+
+    fn restore_position(size: Size, monitor: Size) -> Point {
+        let centered = Point::new(
+            (monitor.width - size.width) / 2.0,
+            (monitor.height - size.height) / 2.0,
+        );
+        let Some(&(x, y)) = RESTORED_POSITION.get() else {
+            return centered;
+        };
+        let visible = x > MIN_VISIBLE - size.width
+            && x < monitor.width - MIN_VISIBLE
+            && y >= 0.0
+            && y < monitor.height - MIN_VISIBLE;
+        if visible {
+            Point::new(x, y)
+        } else {
+            centered
+        }
+    }
+}
+
+/// Whose generation parameters are being edited
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ParamsTarget {
+    #[default]
+    AllModels,
+    Model(String),
+    /// the template of this name, its parameters going with the chats created from it
+    Template(String),
+}
+
+impl std::fmt::Display for ParamsTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsTarget::AllModels => write!(f, "All models"),
+            ParamsTarget::Model(model) => write!(f, "{}", model),
+            ParamsTarget::Template(name) => write!(f, "Template {}", name),
+        }
+    }
+}
+
+const MAX_TEMPERATURE: f32 = 2.0;
+const MAX_TOP_P: f32 = 1.0;
+const MIN_NUM_CTX: u64 = 256;
+const MAX_NUM_CTX: u64 = 1 << 20;
+
+// empty for the default of the server, `None` when invalid
+fn parse_param(s: &str, max: f32) -> Option<Option<f32>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Some(None);
+    }
+    let value = s.parse::<f32>().ok()?;
+    (0.0..=max).contains(&value).then_some(Some(value))
+}
+
+// one stop sequence per row, with `\n` and `\t` standing for a new line and a tab
+fn parse_stop(rows: &[String]) -> Option<Vec<String>> {
+    let stop = rows
+        .iter()
+        .map(|seq| seq.replace("\\n", "\n").replace("\\t", "\t"))
+        .filter(|seq| !seq.is_empty())
+        .collect::<Vec<_>>();
+    (!stop.is_empty()).then_some(stop)
+}
+
+fn show_stop(seq: &str) -> String {
+    seq.replace('\n', "\\n").replace('\t', "\\t")
+}
+
+// like `parse_param`, for how long the models stay loaded
+fn parse_keep_alive(s: &str) -> Option<Option<String>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Some(None);
+    }
+    api::parse_keep_alive(s).map(|_| Some(s.to_string()))
+}
+
+// like `parse_param`, for the context size
+fn parse_num_ctx(s: &str) -> Option<Option<u64>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Some(None);
+    }
+    let value = s.parse::<u64>().ok()?;
+    (MIN_NUM_CTX..=MAX_NUM_CTX)
+        .contains(&value)
+        .then_some(Some(value))
+}
+
+/// The generation parameters as typed, only applied when valid
+#[derive(Clone, Default)]
+struct ParamsInput {
+    temperature: String,
+    top_p: String,
+    num_ctx: String,
+    stop: Vec<String>,
+    keep_alive: String,
+}
+
+const DEFAULT_SIDEBAR_WIDTH: f32 = 0.22;
+const MIN_SIDEBAR_WIDTH: f32 = 0.1;
+const MAX_SIDEBAR_WIDTH: f32 = 0.6;
+
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 10;
+const MIN_POLL_INTERVAL_SECS: u64 = 1;
+
+/// Highlighting of code blocks, `Auto` following the application theme
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CodeTheme {
+    #[default]
+    Auto,
+    SolarizedDark,
+    Base16Mocha,
+    Base16Ocean,
+    Base16Eighties,
+    InspiredGitHub,
+}
+
+impl std::fmt::Display for CodeTheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.highlighter() {
+            None => write!(f, "Auto"),
+            Some(theme) => write!(f, "{}", theme),
+        }
+    }
+}
+
+impl CodeTheme {
+    pub const ALL: [Self; 6] = [
+        CodeTheme::Auto,
+        CodeTheme::SolarizedDark,
+        CodeTheme::Base16Mocha,
+        CodeTheme::Base16Ocean,
+        CodeTheme::Base16Eighties,
+        CodeTheme::InspiredGitHub,
+    ];
+
+    fn highlighter(self) -> Option<highlighter::Theme> {
+        match self {
+            CodeTheme::Auto => None,
+            CodeTheme::SolarizedDark => Some(highlighter::Theme::SolarizedDark),
+            CodeTheme::Base16Mocha => Some(highlighter::Theme::Base16Mocha),
+            CodeTheme::Base16Ocean => Some(highlighter::Theme::Base16Ocean),
+            CodeTheme::Base16Eighties => Some(highlighter::Theme::Base16Eighties),
+            CodeTheme::InspiredGitHub => Some(highlighter::Theme::InspiredGitHub),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub theme: SettingsTheme,
+    pub code_theme: CodeTheme,
+    pub ui_scale: UiScale,
+    /// geometry of the main window when it was last closed
+    pub window: WindowGeometry,
+    /// the history sidebar was collapsed when last toggled
+    pub sidebar_collapsed: bool,
+    /// share of the main window taken by the expanded sidebar, as last dragged
+    pub sidebar_width: f32,
+    pub templates: Vec<ChatTemplate>,
+    /// show the code blocks of the replies collapsed until expanded
+    pub collapse_code: bool,
+    /// render the replies as markdown, instead of showing them as the model wrote them
+    pub render_markdown: bool,
+    /// ask before closing a tab whose reply is still being generated
+    pub confirm_close_generating: bool,
+    /// remove `<think>` blocks when copying or exporting replies
+    pub exclude_reasoning: bool,
+    /// show the creation time of the chats in the sidebar as "5 min ago" instead of a date
+    pub relative_timestamps: bool,
+    /// models listed first in the menubar, by name
+    pub favorite_models: Vec<String>,
+    /// model selected in the menubar as soon as the server lists it
+    pub default_model: Option<String>,
+    /// switch the active chat to the model selected in the menubar without asking
+    pub apply_selected_model: bool,
+    pub pinned_prompts: Vec<PinnedPrompt>,
+    /// name new chats by asking `title_model` (or the model of the chat when empty) after
+    /// their first exchange
+    pub generate_titles: bool,
+    pub title_model: String,
+    pub ollama_host: String,
+    pub ollama_port: u16,
+    /// bearer token sent to the server, when it sits behind an authenticating proxy
+    pub ollama_api_key: Option<String>,
+    /// canned models and replies instead of the server, set by `--demo`
+    #[serde(skip)]
+    pub demo: bool,
+    // host and port as typed, only applied when valid
+    #[serde(skip)]
+    server_input: Option<(String, String)>,
+    /// seconds between two checks of the server and its models
+    pub poll_interval_secs: u64,
+    #[serde(skip)]
+    poll_input: Option<String>,
+    /// sampling parameters of every model, unless overridden in `model_generation`
+    pub generation: api::GenerationParams,
+    /// sampling parameters of some models, by model name
+    pub model_generation: HashMap<String, api::GenerationParams>,
+    #[serde(skip)]
+    params_target: ParamsTarget,
+    #[serde(skip)]
+    params_input: Option<ParamsInput>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let default_server = api::OllamaConfig::localhost(api::DEFAULT_PORT);
+        Self {
+            theme: SettingsTheme::default(),
+            code_theme: CodeTheme::default(),
+            ui_scale: UiScale::default(),
+            window: WindowGeometry::default(),
+            sidebar_collapsed: false,
+            sidebar_width: DEFAULT_SIDEBAR_WIDTH,
+            templates: ChatTemplate::defaults(),
+            collapse_code: false,
+            render_markdown: true,
+            confirm_close_generating: true,
+            exclude_reasoning: true,
+            relative_timestamps: true,
+            favorite_models: Vec::new(),
+            default_model: None,
+            apply_selected_model: false,
+            pinned_prompts: PinnedPrompt::defaults(),
+            generate_titles: false,
+            title_model: String::new(),
+            ollama_host: default_server.host,
+            ollama_port: default_server.port,
+            ollama_api_key: None,
+            demo: false,
+            server_input: None,
+            poll_interval_secs: DEFAULT_POLL_INTERVAL_SECS,
+            poll_input: None,
+            generation: api::GenerationParams::default(),
+            model_generation: HashMap::new(),
+            params_target: ParamsTarget::default(),
+            params_input: None,
+        }
+    }
+}
+
+/// A starting point for a new chat
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ChatTemplate {
+    pub name: String,
+    /// model to use, or the one selected in the menubar when empty
+    pub model: String,
+    pub system: String,
+    /// initial text loaded in the prompt editor
+    pub prompt: String,
+    /// text wrapping every prompt of the chat, `{prompt}` standing for what is typed;
+    /// prompts are sent as typed when empty
+    pub wrap: String,
+    /// generation parameters of the chats, over the ones of the settings
+    #[serde(skip_serializing_if = "api::GenerationParams::is_unset")]
+    pub params: api::GenerationParams,
+}
+
+impl std::fmt::Display for ChatTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl ChatTemplate {
+    fn new(name: &str, system: &str, prompt: &str, wrap: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            model: String::new(),
+            system: system.to_string(),
+            prompt: prompt.to_string(),
+            wrap: wrap.to_string(),
+            params: api::GenerationParams::default(),
+        }
+    }
+
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::new(
+                "Code review",
+                "You are an experienced software engineer doing a careful code review. \
+                 Point out bugs, unclear code and possible improvements.",
+                "Please review the following code:\n\n",
+                "",
+            ),
+            Self::new(
+                "Summarize",
+                "You summarize texts concisely, keeping only the important points.",
+                "",
+                "Summarize the following text:\n\n{prompt}",
+            ),
+            Self::new(
+                "Brainstorm",
+                "You are a creative partner helping to explore many different ideas.",
+                "Let's brainstorm ideas about ",
+                "",
+            ),
+        ]
+    }
+}
+
+/// A snippet of text inserted in the prompt editor with a single click. `{selection}` and
+/// `{clipboard}` are replaced by the text selected in the editor and the clipboard content
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PinnedPrompt {
+    pub label: String,
+    pub text: String,
+}
+
+pub const SELECTION_PLACEHOLDER: &str = "{selection}";
+pub const CLIPBOARD_PLACEHOLDER: &str = "{clipboard}";
+
+impl PinnedPrompt {
+    /// The text to insert, with its placeholders replaced. The replacements are not expanded
+    /// again, so a selection containing `{clipboard}` is inserted as is
+    pub fn expand(text: &str, selection: &str, clipboard: &str) -> String {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        loop {
+            let next = [
+                (
+                    rest.find(SELECTION_PLACEHOLDER),
+                    SELECTION_PLACEHOLDER,
+                    selection,
+                ),
+                (
+                    rest.find(CLIPBOARD_PLACEHOLDER),
+                    CLIPBOARD_PLACEHOLDER,
+                    clipboard,
+                ),
+            ]
+            .into_iter()
+            .filter_map(|(pos, placeholder, value)| Some((pos?, placeholder, value)))
+            .min_by_key(|(pos, _, _)| *pos);
+            let Some((pos, placeholder, value)) = next else {
+                expanded.push_str(rest);
+                return expanded;
+            };
+            expanded.push_str(&rest[..pos]);
+            expanded.push_str(value);
+            rest = &rest[pos + placeholder.len()..];
+        }
+    }
+
+    pub fn defaults() -> Vec<Self> {
+        [
+            ("Explain", "Explain the following: "),
+            ("Refactor", "Refactor this code to make it clearer: "),
+            ("Add tests", "Write tests for this code: "),
+        ]
+        .into_iter()
+        .map(|(label, text)| Self {
+            label: label.to_string(),
+            text: text.to_string(),
+        })
+        .collect()
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum MessageSettings {
     ThemeSelected(SettingsTheme),
+    CodeThemeSelected(CodeTheme),
+    UiScaleSelected(UiScale),
+    ExcludeReasoning(bool),
+    CollapseCode(bool),
+    RenderMarkdown(bool),
+    ConfirmCloseGenerating(bool),
+    RelativeTimestamps(bool),
+    ApplySelectedModel(bool),
+    OllamaHost(String),
+    OllamaPort(String),
+    ServerApply,
+    OllamaApiKey(String),
+    PollInterval(String),
+    ParamsTargetSelected(ParamsTarget),
+    ParamsTemperature(String),
+    ParamsTopP(String),
+    ParamsNumCtx(String),
+    ParamsStopAdd,
+    ParamsStopRemove(usize),
+    ParamsStopEdit(usize, String),
+    ParamsKeepAlive(String),
+    ParamsReset,
+    GenerateTitles(bool),
+    TitleModel(String),
+    TemplateAdd,
+    TemplateRemove(usize),
+    TemplateEdit(usize, TemplateField, String),
+    PinnedAdd,
+    PinnedRemove(usize),
+    PinnedLabel(usize, String),
+    PinnedText(usize, String),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TemplateField {
+    Name,
+    Model,
+    System,
+    Prompt,
+    Wrap,
 }
 
 const CONFIG_FILE_NAME: &str = "config.json";
@@ -65,33 +594,818 @@ pub fn serialize_settings(settings: &Settings) -> String {
 }
 
 pub async fn write_config(path: PathBuf, settings: String) -> std::io::Result<()> {
-    let path = path.join(CONFIG_FILE_NAME);
-    let tmp_path = path.clone().with_extension(".json.tmp");
+    crate::persist::write_atomic(path.join(CONFIG_FILE_NAME), settings.as_bytes()).await
+}
+
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && url::Url::parse(&format!("http://{}", api::url_host(host))).is_ok_and(|url| {
+            url.host_str().is_some()
+                && url.port().is_none()
+                && url.path() == "/"
+                && url.username().is_empty()
+        })
+}
 
-    let mut file = tokio::fs::File::create(&tmp_path).await?;
-    file.write_all(settings.as_bytes()).await?;
-    std::fs::rename(tmp_path, path)?;
-    Ok(())
+fn parse_port(port: &str) -> Option<u16> {
+    port.parse().ok().filter(|p| *p != 0)
 }
 
 impl Settings {
+    pub fn ollama_config(&self) -> api::OllamaConfig {
+        api::OllamaConfig {
+            host: self.ollama_host.clone(),
+            port: self.ollama_port,
+            api_key: self.ollama_api_key.clone(),
+            demo: self.demo,
+        }
+    }
+
+    pub fn code_highlighter(&self) -> highlighter::Theme {
+        self.code_theme
+            .highlighter()
+            .unwrap_or(match self.theme.theme() {
+                iced::Theme::SolarizedDark => highlighter::Theme::SolarizedDark,
+                iced::Theme::CatppuccinMocha => highlighter::Theme::Base16Mocha,
+                _ if self.theme.is_dark() => highlighter::Theme::Base16Ocean,
+                _ => highlighter::Theme::InspiredGitHub,
+            })
+    }
+
+    /// Share of the main window taken by the expanded sidebar, kept within bounds leaving room
+    /// to both the sidebar and the chats
+    pub fn sidebar_width(&self) -> f32 {
+        if self.sidebar_width.is_finite() {
+            self.sidebar_width
+                .clamp(MIN_SIDEBAR_WIDTH, MAX_SIDEBAR_WIDTH)
+        } else {
+            DEFAULT_SIDEBAR_WIDTH
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs.max(MIN_POLL_INTERVAL_SECS))
+    }
+
+    /// The parameters to generate with the model, falling back to the ones of all models
+    pub fn generation_params(&self, model: &str) -> api::GenerationParams {
+        self.model_generation
+            .get(model)
+            .cloned()
+            .unwrap_or_default()
+            .or(self.generation.clone())
+    }
+
+    fn target_params(&self) -> api::GenerationParams {
+        match &self.params_target {
+            ParamsTarget::AllModels => self.generation.clone(),
+            ParamsTarget::Model(model) => self
+                .model_generation
+                .get(model)
+                .cloned()
+                .unwrap_or_default(),
+            ParamsTarget::Template(name) => self
+                .templates
+                .iter()
+                .find(|t| &t.name == name)
+                .map(|t| t.params.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn set_target_params(&mut self, params: api::GenerationParams) {
+        match &self.params_target {
+            ParamsTarget::AllModels => self.generation = params,
+            ParamsTarget::Model(model) if params.is_unset() => {
+                self.model_generation.remove(model);
+            }
+            ParamsTarget::Model(model) => {
+                self.model_generation.insert(model.clone(), params);
+            }
+            ParamsTarget::Template(name) => {
+                if let Some(template) = self.templates.iter_mut().find(|t| &t.name == name) {
+                    template.params = params;
+                }
+            }
+        }
+    }
+
+    fn params_input(&self) -> ParamsInput {
+        match &self.params_input {
+            Some(input) => input.clone(),
+            None => {
+                let params = self.target_params();
+                let show = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+                ParamsInput {
+                    temperature: show(params.temperature),
+                    top_p: show(params.top_p),
+                    num_ctx: params.num_ctx.map(|v| v.to_string()).unwrap_or_default(),
+                    stop: params
+                        .stop
+                        .iter()
+                        .flatten()
+                        .map(|seq| show_stop(seq))
+                        .collect(),
+                    keep_alive: params.keep_alive.unwrap_or_default(),
+                }
+            }
+        }
+    }
+
+    fn update_params(&mut self, input: ParamsInput) {
+        let mut params = self.target_params();
+        if let Some(temperature) = parse_param(&input.temperature, MAX_TEMPERATURE) {
+            params.temperature = temperature;
+        }
+        if let Some(top_p) = parse_param(&input.top_p, MAX_TOP_P) {
+            params.top_p = top_p;
+        }
+        if let Some(num_ctx) = parse_num_ctx(&input.num_ctx) {
+            params.num_ctx = num_ctx;
+        }
+        params.stop = parse_stop(&input.stop);
+        if let Some(keep_alive) = parse_keep_alive(&input.keep_alive) {
+            params.keep_alive = keep_alive;
+        }
+        self.set_target_params(params);
+        self.params_input = Some(input);
+    }
+
+    fn server_input(&self) -> (&str, String) {
+        match &self.server_input {
+            Some((host, port)) => (host, port.clone()),
+            None => (&self.ollama_host, self.ollama_port.to_string()),
+        }
+    }
+
+    // the server changes once the host and port are submitted, not on every keystroke
+    fn apply_server(&mut self) {
+        let Some((host, port)) = &self.server_input else {
+            return;
+        };
+        let host = host.trim();
+        if let (true, Some(port)) = (valid_host(host), parse_port(port)) {
+            self.ollama_host = host.to_string();
+            self.ollama_port = port;
+            self.server_input = None;
+        }
+    }
+
     pub fn update(&mut self, message: MessageSettings) {
         match message {
+            MessageSettings::OllamaHost(host) => {
+                let (_, port) = self.server_input();
+                self.server_input = Some((host, port));
+            }
+            MessageSettings::OllamaPort(port) => {
+                let host = self.server_input().0.to_string();
+                self.server_input = Some((host, port));
+            }
+            MessageSettings::ServerApply => self.apply_server(),
+            MessageSettings::OllamaApiKey(key) => {
+                self.ollama_api_key = (!key.is_empty()).then_some(key);
+            }
+            MessageSettings::PollInterval(secs) => {
+                if let Ok(secs) = secs.parse::<u64>() {
+                    self.poll_interval_secs = secs.max(MIN_POLL_INTERVAL_SECS);
+                }
+                self.poll_input = Some(secs);
+            }
+            MessageSettings::ParamsTargetSelected(target) => {
+                self.params_target = target;
+                self.params_input = None;
+            }
+            MessageSettings::ParamsTemperature(temperature) => {
+                let input = ParamsInput {
+                    temperature,
+                    ..self.params_input()
+                };
+                self.update_params(input);
+            }
+            MessageSettings::ParamsTopP(top_p) => {
+                let input = ParamsInput {
+                    top_p,
+                    ..self.params_input()
+                };
+                self.update_params(input);
+            }
+            MessageSettings::ParamsNumCtx(num_ctx) => {
+                let input = ParamsInput {
+                    num_ctx,
+                    ..self.params_input()
+                };
+                self.update_params(input);
+            }
+            MessageSettings::ParamsStopAdd => {
+                let mut input = self.params_input();
+                input.stop.push(String::new());
+                self.update_params(input);
+            }
+            MessageSettings::ParamsStopRemove(idx) => {
+                let mut input = self.params_input();
+                if idx < input.stop.len() {
+                    input.stop.remove(idx);
+                }
+                self.update_params(input);
+            }
+            MessageSettings::ParamsStopEdit(idx, seq) => {
+                let mut input = self.params_input();
+                if let Some(row) = input.stop.get_mut(idx) {
+                    *row = seq;
+                }
+                self.update_params(input);
+            }
+            MessageSettings::ParamsKeepAlive(keep_alive) => {
+                let input = ParamsInput {
+                    keep_alive,
+                    ..self.params_input()
+                };
+                self.update_params(input);
+            }
+            MessageSettings::ParamsReset => {
+                self.set_target_params(api::GenerationParams::default());
+                self.params_input = None;
+            }
             MessageSettings::ThemeSelected(settings_theme) => {
                 self.theme = settings_theme;
             }
+            MessageSettings::CodeThemeSelected(code_theme) => {
+                self.code_theme = code_theme;
+            }
+            MessageSettings::UiScaleSelected(ui_scale) => {
+                self.ui_scale = ui_scale;
+            }
+            MessageSettings::ExcludeReasoning(exclude) => {
+                self.exclude_reasoning = exclude;
+            }
+            MessageSettings::CollapseCode(collapse) => {
+                self.collapse_code = collapse;
+            }
+            MessageSettings::RenderMarkdown(render) => {
+                self.render_markdown = render;
+            }
+            MessageSettings::ConfirmCloseGenerating(confirm) => {
+                self.confirm_close_generating = confirm;
+            }
+            MessageSettings::RelativeTimestamps(relative) => {
+                self.relative_timestamps = relative;
+            }
+            MessageSettings::ApplySelectedModel(apply) => {
+                self.apply_selected_model = apply;
+            }
+            MessageSettings::GenerateTitles(generate) => {
+                self.generate_titles = generate;
+            }
+            MessageSettings::TitleModel(model) => {
+                self.title_model = model;
+            }
+            MessageSettings::PinnedAdd => {
+                self.pinned_prompts.push(PinnedPrompt::default());
+            }
+            MessageSettings::PinnedRemove(idx) => {
+                if idx < self.pinned_prompts.len() {
+                    self.pinned_prompts.remove(idx);
+                }
+            }
+            MessageSettings::PinnedLabel(idx, label) => {
+                if let Some(pinned) = self.pinned_prompts.get_mut(idx) {
+                    pinned.label = label;
+                }
+            }
+            MessageSettings::PinnedText(idx, text) => {
+                if let Some(pinned) = self.pinned_prompts.get_mut(idx) {
+                    pinned.text = text;
+                }
+            }
+            MessageSettings::TemplateAdd => {
+                self.templates.push(ChatTemplate {
+                    name: "New template".to_string(),
+                    ..ChatTemplate::default()
+                });
+            }
+            MessageSettings::TemplateRemove(idx) => {
+                if idx < self.templates.len() {
+                    self.templates.remove(idx);
+                }
+            }
+            MessageSettings::TemplateEdit(idx, field, value) => {
+                if let Some(template) = self.templates.get_mut(idx) {
+                    match field {
+                        TemplateField::Name => template.name = value,
+                        TemplateField::Model => template.model = value,
+                        TemplateField::System => template.system = value,
+                        TemplateField::Prompt => template.prompt = value,
+                        TemplateField::Wrap => template.wrap = value,
+                    }
+                }
+            }
         }
     }
 
-    pub fn view<'a>(&self) -> Element<'a, MessageSettings> {
-        let labelled_row = |s| row![].push(container(text(s)).width(120.0));
+    fn view_template(idx: usize, template: &ChatTemplate) -> Element<'_, MessageSettings> {
+        let field = |placeholder, value, field| {
+            text_input(placeholder, value)
+                .on_input(move |s| MessageSettings::TemplateEdit(idx, field, s))
+        };
+        row![]
+            .push(
+                column![]
+                    .push(
+                        row![]
+                            .push(field("Name", &template.name, TemplateField::Name))
+                            .push(field(
+                                "Model (default: selected)",
+                                &template.model,
+                                TemplateField::Model,
+                            ))
+                            .spacing(5),
+                    )
+                    .push(field(
+                        "System prompt",
+                        &template.system,
+                        TemplateField::System,
+                    ))
+                    .push(field(
+                        "Initial prompt",
+                        &template.prompt,
+                        TemplateField::Prompt,
+                    ))
+                    .push(field(
+                        "Wrap every prompt, {prompt} standing for the typed text",
+                        &template.wrap,
+                        TemplateField::Wrap,
+                    ))
+                    .spacing(5)
+                    .width(Length::Fill),
+            )
+            .push(
+                button_icon(iced_fonts::Bootstrap::Trash)
+                    .style(button::danger)
+                    .on_press(MessageSettings::TemplateRemove(idx)),
+            )
+            .spacing(10)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_pinned(idx: usize, pinned: &PinnedPrompt) -> Element<'_, MessageSettings> {
+        row![]
+            .push(
+                text_input("Label", &pinned.label)
+                    .on_input(move |s| MessageSettings::PinnedLabel(idx, s))
+                    .width(Length::FillPortion(1)),
+            )
+            .push(
+                text_input(
+                    "Text to insert, {selection} and {clipboard} are replaced",
+                    &pinned.text,
+                )
+                .on_input(move |s| MessageSettings::PinnedText(idx, s))
+                .width(Length::FillPortion(3)),
+            )
+            .push(
+                button_icon(iced_fonts::Bootstrap::Trash)
+                    .style(button::danger)
+                    .on_press(MessageSettings::PinnedRemove(idx)),
+            )
+            .spacing(5)
+            .align_y(Alignment::Center)
+            .into()
+    }
+
+    fn view_params(&self, models: &[api::LocalModel]) -> Element<'_, MessageSettings> {
+        let mut targets = vec![ParamsTarget::AllModels];
+        // models tuned earlier stay editable even if not installed anymore
+        let mut names = models
+            .iter()
+            .map(|model| model.name().clone())
+            .chain(self.model_generation.keys().cloned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        targets.extend(names.into_iter().map(ParamsTarget::Model));
+        targets.extend(
+            self.templates
+                .iter()
+                .map(|template| ParamsTarget::Template(template.name.clone())),
+        );
+
+        let input = self.params_input();
+        let error = if parse_param(&input.temperature, MAX_TEMPERATURE).is_none() {
+            Some(format!(
+                "Invalid temperature, expecting a number between 0 and {}",
+                MAX_TEMPERATURE
+            ))
+        } else if parse_param(&input.top_p, MAX_TOP_P).is_none() {
+            Some(format!(
+                "Invalid top p, expecting a number between 0 and {}",
+                MAX_TOP_P
+            ))
+        } else if parse_num_ctx(&input.num_ctx).is_none() {
+            Some(format!(
+                "Invalid context size, expecting a number of tokens between {} and {}",
+                MIN_NUM_CTX, MAX_NUM_CTX
+            ))
+        } else if parse_keep_alive(&input.keep_alive).is_none() {
+            Some("Invalid keep alive, expecting a duration like 30s, 10m or 2h, or -1".to_string())
+        } else {
+            None
+        };
+        // a model or template without its own value uses the one of all models
+        let placeholder = |v: Option<String>| match (&self.params_target, v) {
+            (ParamsTarget::AllModels, _) | (_, None) => "default".to_string(),
+            (_, Some(v)) => v,
+        };
+        let inherited_stop = match (&self.params_target, self.generation.stop.as_deref()) {
+            (ParamsTarget::AllModels, _) | (_, None) => None,
+            (_, Some(stop)) => Some(
+                stop.iter()
+                    .map(|seq| show_stop(seq))
+                    .collect::<Vec<_>>()
+                    .join("  "),
+            ),
+        };
+        let stop_rows = input
+            .stop
+            .iter()
+            .enumerate()
+            .map(|(idx, seq)| {
+                row![]
+                    .push(
+                        text_input("Sequence like </answer>, or \\n\\n", seq)
+                            .on_input(move |s| MessageSettings::ParamsStopEdit(idx, s))
+                            .width(300.0),
+                    )
+                    .push(
+                        button_icon(iced_fonts::Bootstrap::Trash)
+                            .style(button::danger)
+                            .on_press(MessageSettings::ParamsStopRemove(idx)),
+                    )
+                    .spacing(5)
+                    .align_y(Alignment::Center)
+                    .into()
+            })
+            .collect::<Vec<Element<_>>>();
         column![]
-            .push(labelled_row("Theme").push(pick_list(
-                SettingsTheme::ALL,
-                Some(self.theme),
-                MessageSettings::ThemeSelected,
-            )))
-            .align_x(Alignment::Start)
+            .push(
+                row![]
+                    .push(pick_list(
+                        targets,
+                        Some(self.params_target.clone()),
+                        MessageSettings::ParamsTargetSelected,
+                    ))
+                    .push(text("Temperature"))
+                    .push(
+                        text_input(
+                            &placeholder(self.generation.temperature.map(|v| v.to_string())),
+                            &input.temperature,
+                        )
+                        .on_input(MessageSettings::ParamsTemperature)
+                        .width(70.0),
+                    )
+                    .push(text("Top p"))
+                    .push(
+                        text_input(
+                            &placeholder(self.generation.top_p.map(|v| v.to_string())),
+                            &input.top_p,
+                        )
+                        .on_input(MessageSettings::ParamsTopP)
+                        .width(70.0),
+                    )
+                    .push(text("Context"))
+                    .push(
+                        text_input(
+                            &placeholder(self.generation.num_ctx.map(|v| v.to_string())),
+                            &input.num_ctx,
+                        )
+                        .on_input(MessageSettings::ParamsNumCtx)
+                        .width(80.0),
+                    )
+                    .push(
+                        button(text("Reset"))
+                            .style(button::secondary)
+                            .on_press(MessageSettings::ParamsReset),
+                    )
+                    .spacing(5)
+                    .align_y(Alignment::Center),
+            )
+            .push(
+                row![]
+                    .push(tooltip(
+                        text("Keep loaded"),
+                        container(
+                            text(
+                                "How long the model stays in memory after a reply, \
+                                 -1 to keep it loaded",
+                            )
+                            .size(12.0),
+                        )
+                        .padding(5)
+                        .style(container::rounded_box),
+                        tooltip::Position::Bottom,
+                    ))
+                    .push(
+                        text_input(
+                            &placeholder(self.generation.keep_alive.clone()),
+                            &input.keep_alive,
+                        )
+                        .on_input(MessageSettings::ParamsKeepAlive)
+                        .width(70.0),
+                    )
+                    .spacing(5)
+                    .align_y(Alignment::Center),
+            )
+            .push(
+                row![]
+                    .push(text("Stop at"))
+                    .push(
+                        column(stop_rows)
+                            .push_maybe(
+                                input
+                                    .stop
+                                    .is_empty()
+                                    .then_some(inherited_stop)
+                                    .flatten()
+                                    .map(|stop| {
+                                        text(format!("Default of all models: {}", stop))
+                                            .style(text::secondary)
+                                    }),
+                            )
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::Plus, "Add stop sequence")
+                                    .on_press(MessageSettings::ParamsStopAdd),
+                            )
+                            .spacing(5),
+                    )
+                    .spacing(5),
+            )
+            .push_maybe(error.map(|e| text(e).style(text::danger)))
+            .spacing(5)
             .into()
     }
+
+    fn view_server(&self) -> Element<'_, MessageSettings> {
+        let (host, port) = self.server_input();
+        let poll = self
+            .poll_input
+            .clone()
+            .unwrap_or_else(|| self.poll_interval_secs.to_string());
+        let error = if !valid_host(host.trim()) {
+            Some("Invalid host, expecting a name or an IP address")
+        } else if parse_port(&port).is_none() {
+            Some("Invalid port, expecting a number between 1 and 65535")
+        } else if poll.parse::<u64>().is_err() {
+            Some("Invalid interval, expecting a number of seconds")
+        } else {
+            None
+        };
+        column![]
+            .push(
+                row![]
+                    .push(
+                        text_input("Host", host)
+                            .on_input(MessageSettings::OllamaHost)
+                            .on_submit(MessageSettings::ServerApply)
+                            .width(250.0),
+                    )
+                    .push(
+                        text_input("Port", &port)
+                            .on_input(MessageSettings::OllamaPort)
+                            .on_submit(MessageSettings::ServerApply)
+                            .width(80.0),
+                    )
+                    .push(
+                        button(text("Apply")).on_press_maybe(
+                            (self.server_input.is_some()
+                                && valid_host(host.trim())
+                                && parse_port(&port).is_some())
+                            .then_some(MessageSettings::ServerApply),
+                        ),
+                    )
+                    .spacing(5),
+            )
+            .push(
+                text_input(
+                    "API key (optional)",
+                    self.ollama_api_key.as_deref().unwrap_or(""),
+                )
+                .on_input(MessageSettings::OllamaApiKey)
+                .secure(true)
+                .width(335.0),
+            )
+            .push(
+                row![]
+                    .push(text("Check every"))
+                    .push(
+                        text_input("10", &poll)
+                            .on_input(MessageSettings::PollInterval)
+                            .width(60.0),
+                    )
+                    .push(text(format!(
+                        "seconds (at least {})",
+                        MIN_POLL_INTERVAL_SECS
+                    )))
+                    .spacing(5)
+                    .align_y(Alignment::Center),
+            )
+            .push_maybe(error.map(|e| text(e).style(text::danger)))
+            .spacing(5)
+            .into()
+    }
+
+    pub fn view<'a>(&'a self, models: &[api::LocalModel]) -> Element<'a, MessageSettings> {
+        let labelled_row = |s| row![].push(container(text(s)).width(120.0));
+        let templates = self
+            .templates
+            .iter()
+            .enumerate()
+            .map(|(i, t)| Self::view_template(i, t));
+        let pinned = self
+            .pinned_prompts
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Self::view_pinned(i, p));
+        scrollable(
+            column![]
+                .push(labelled_row("Theme").push(pick_list(
+                    SettingsTheme::all(),
+                    Some(self.theme.clone()),
+                    MessageSettings::ThemeSelected,
+                )))
+                .push(labelled_row("Code theme").push(pick_list(
+                    CodeTheme::ALL,
+                    Some(self.code_theme),
+                    MessageSettings::CodeThemeSelected,
+                )))
+                .push(labelled_row("Interface size").push(pick_list(
+                    UiScale::ALL,
+                    Some(self.ui_scale),
+                    MessageSettings::UiScaleSelected,
+                )))
+                .push(labelled_row("Ollama server").push(self.view_server()))
+                .push(labelled_row("Parameters").push(self.view_params(models)))
+                .push(
+                    labelled_row("Model").push(
+                        checkbox(
+                            "Apply the selected model to the active chat",
+                            self.apply_selected_model,
+                        )
+                        .on_toggle(MessageSettings::ApplySelectedModel),
+                    ),
+                )
+                .push(
+                    labelled_row("Titles").push(
+                        row![]
+                            .push(
+                                checkbox("Generate chat titles", self.generate_titles)
+                                    .on_toggle(MessageSettings::GenerateTitles),
+                            )
+                            .push(
+                                text_input(
+                                    "Title model (default: the chat model)",
+                                    &self.title_model,
+                                )
+                                .on_input(MessageSettings::TitleModel)
+                                .width(250.0),
+                            )
+                            .spacing(10)
+                            .align_y(Alignment::Center),
+                    ),
+                )
+                .push(
+                    labelled_row("Replies").push(
+                        checkbox("Render markdown", self.render_markdown)
+                            .on_toggle(MessageSettings::RenderMarkdown),
+                    ),
+                )
+                .push(
+                    labelled_row("Tabs").push(
+                        checkbox(
+                            "Ask before closing a chat that is generating",
+                            self.confirm_close_generating,
+                        )
+                        .on_toggle(MessageSettings::ConfirmCloseGenerating),
+                    ),
+                )
+                .push(
+                    labelled_row("Code blocks").push(
+                        checkbox("Collapse code blocks by default", self.collapse_code)
+                            .on_toggle(MessageSettings::CollapseCode),
+                    ),
+                )
+                .push(
+                    labelled_row("History").push(
+                        checkbox("Show relative timestamps", self.relative_timestamps)
+                            .on_toggle(MessageSettings::RelativeTimestamps),
+                    ),
+                )
+                .push(
+                    labelled_row("Copy/Export").push(
+                        checkbox("Exclude reasoning", self.exclude_reasoning)
+                            .on_toggle(MessageSettings::ExcludeReasoning),
+                    ),
+                )
+                .push(
+                    labelled_row("Pinned prompts").push(
+                        column(pinned)
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::Plus, "Add pinned prompt")
+                                    .on_press(MessageSettings::PinnedAdd),
+                            )
+                            .spacing(5),
+                    ),
+                )
+                .push(
+                    labelled_row("Templates").push(
+                        column(templates)
+                            .push(
+                                button_icon_text(iced_fonts::Bootstrap::Plus, "Add template")
+                                    .on_press(MessageSettings::TemplateAdd),
+                            )
+                            .spacing(15),
+                    ),
+                )
+                .spacing(20)
+                .align_x(Alignment::Start),
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_applied_on_submit() {
+        let mut settings = Settings::default();
+        settings.update(MessageSettings::OllamaHost(String::new()));
+        for c in ["g", "p", "u", "-", "b", "o", "x"] {
+            let host = format!("{}{}", settings.server_input().0, c);
+            settings.update(MessageSettings::OllamaHost(host));
+        }
+        assert_eq!(settings.ollama_host, "localhost");
+        settings.update(MessageSettings::ServerApply);
+        assert_eq!(settings.ollama_host, "gpu-box");
+
+        // an invalid host is kept as typed, and not applied
+        settings.update(MessageSettings::OllamaHost("http://".to_string()));
+        settings.update(MessageSettings::ServerApply);
+        assert_eq!(settings.ollama_host, "gpu-box");
+        assert_eq!(settings.server_input().0, "http://");
+    }
+
+    #[test]
+    fn ipv6_hosts() {
+        assert!(valid_host("::1"));
+        assert!(valid_host("[::1]"));
+        assert!(valid_host("fe80::1"));
+        assert!(valid_host("192.168.1.10"));
+        assert!(!valid_host("::1:11434:"));
+        assert_eq!(api::url_host("::1"), "[::1]");
+        assert_eq!(api::url_host("[::1]"), "[::1]");
+        assert_eq!(api::url_host("localhost"), "localhost");
+        let config = api::OllamaConfig {
+            host: "::1".to_string(),
+            ..api::OllamaConfig::localhost(api::DEFAULT_PORT)
+        };
+        assert_eq!(config.address(), "http://[::1]:11434");
+    }
+
+    #[test]
+    fn pinned_prompt_expanded_once() {
+        assert_eq!(
+            PinnedPrompt::expand("Compare {selection} with {clipboard}.", "a", "b"),
+            "Compare a with b."
+        );
+        // placeholders coming from the selection or the clipboard stay as they are
+        assert_eq!(
+            PinnedPrompt::expand("{clipboard} / {selection}", "{clipboard}", "{selection}"),
+            "{selection} / {clipboard}"
+        );
+        assert_eq!(
+            PinnedPrompt::expand("{selection}{selection} {other}", "x", ""),
+            "xx {other}"
+        );
+    }
+
+    #[test]
+    fn stop_sequences_per_row() {
+        let mut settings = Settings::default();
+        settings.update(MessageSettings::ParamsStopAdd);
+        settings.update(MessageSettings::ParamsStopAdd);
+        settings.update(MessageSettings::ParamsStopEdit(0, "a, b".to_string()));
+        assert_eq!(settings.generation.stop, Some(vec!["a, b".to_string()]));
+        // the empty row stays editable without being sent
+        assert_eq!(settings.params_input().stop.len(), 2);
+        settings.update(MessageSettings::ParamsStopEdit(1, "\\n\\n".to_string()));
+        assert_eq!(
+            settings.generation.stop,
+            Some(vec!["a, b".to_string(), "\n\n".to_string()])
+        );
+        settings.update(MessageSettings::ParamsStopRemove(0));
+        assert_eq!(settings.generation.stop, Some(vec!["\n\n".to_string()]));
+        settings.update(MessageSettings::ParamsStopRemove(0));
+        assert_eq!(settings.generation.stop, None);
+    }
 }
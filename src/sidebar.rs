@@ -1,78 +1,456 @@
+use std::sync::Arc;
+
 use chrono::{DateTime, Local};
 use iced::{
-    widget::{button, column, container, row, scrollable, text, Container},
+    widget::{
+        button, column, container, horizontal_rule, row, scrollable, text, text_input, tooltip,
+        Container,
+    },
     Alignment, Background, Element, Length, Theme,
 };
 use ulid::Ulid;
 
 use crate::{
-    helper::{button_icon, button_icon_text},
-    history::SavedChat,
+    helper::{button_icon, button_icon_small, button_icon_text},
+    history::{SavedChat, SearchHit},
     Message,
 };
 
+/// A chat title being edited, in the tab bar or in the sidebar
+pub struct Renaming {
+    pub ulid: Ulid,
+    pub title: String,
+    // the title shown by default, when the chat has none of its own
+    pub default: Option<String>,
+    pub in_sidebar: bool,
+}
+
+impl Renaming {
+    pub fn input_id() -> text_input::Id {
+        text_input::Id::new("chat-rename")
+    }
+
+    /// The new title of the chat; an empty title, or the default one left as is, keeps the
+    /// chat without a title
+    pub fn submitted_title(&self) -> Option<String> {
+        let title = self.title.trim();
+        let unchanged = self.default.as_deref().map(str::trim) == Some(title);
+        (!title.is_empty() && !unchanged).then(|| title.to_string())
+    }
+
+    pub fn view(&self) -> text_input::TextInput<'_, Message> {
+        text_input("Title", &self.title)
+            .id(Self::input_id())
+            .on_input(Message::ChatRenameEdit)
+            .on_submit(Message::ChatRenameSubmit)
+            .padding(2)
+    }
+}
+
+/// Humanized time elapsed between `then` and `now`, e.g. "5 min ago" or "yesterday"
+fn relative_time(then: DateTime<Local>, now: DateTime<Local>) -> String {
+    let elapsed = now.signed_duration_since(then);
+    if elapsed.num_minutes() < 1 {
+        return "just now".to_string();
+    }
+    if elapsed.num_hours() < 1 {
+        return format!("{} min ago", elapsed.num_minutes());
+    }
+    if elapsed.num_days() < 1 {
+        let hours = elapsed.num_hours();
+        return format!("{} hour{} ago", hours, if hours == 1 { "" } else { "s" });
+    }
+    // by calendar day, so that last evening is "yesterday" in the morning
+    let days = now
+        .date_naive()
+        .signed_duration_since(then.date_naive())
+        .num_days();
+    match days {
+        ..=1 => "yesterday".to_string(),
+        2..=6 => format!("{} days ago", days),
+        _ => then.format("%Y-%m-%d").to_string(),
+    }
+}
+
+/// A tag being added to a chat of the sidebar
+pub struct Tagging {
+    pub ulid: Ulid,
+    pub tag: String,
+}
+
+impl Tagging {
+    pub fn input_id() -> text_input::Id {
+        text_input::Id::new("chat-tag")
+    }
+}
+
 pub struct Sidebar {
-    pub chats: Vec<SavedChat<String>>,
+    // shared with the searches running in the background, copied on write only while one runs
+    pub chats: Arc<Vec<SavedChat<String>>>,
+    pub tagging: Option<Tagging>,
+    // only the chats with this tag are listed
+    pub tag_filter: Option<String>,
+    pub search: String,
+    // bumped on every edit of the search, so that stale (debounced) searches are dropped
+    pub search_generation: u64,
+    pub search_results: Option<Vec<SearchHit>>,
+    pub import_error: Option<String>,
 }
 
 impl Sidebar {
-    pub fn new(chats: Vec<SavedChat<String>>) -> Self {
-        Self { chats }
+    pub fn new(mut chats: Vec<SavedChat<String>>) -> Self {
+        chats.sort_by_key(Self::sort_key);
+        Self {
+            chats: Arc::new(chats),
+            tagging: None,
+            tag_filter: None,
+            search: String::new(),
+            search_generation: 0,
+            search_results: None,
+            import_error: None,
+        }
+    }
+
+    // pinned chats first, then by creation time
+    fn sort_key(chat: &SavedChat<String>) -> (bool, Ulid) {
+        (!chat.pinned, chat.ulid)
     }
 
+    /// Add a chat, or replace the previously saved version of it
     pub fn add_chat(&mut self, chat: SavedChat<String>) {
-        self.chats.push(chat);
-        self.chats.sort_by(|a, b| a.ulid.cmp(&b.ulid))
+        let chats = Arc::make_mut(&mut self.chats);
+        if let Some(existing) = chats.iter_mut().find(|c| c.ulid == chat.ulid) {
+            *existing = chat;
+        } else {
+            chats.push(chat);
+        }
+        chats.sort_by_key(Self::sort_key)
     }
 
-    pub fn remove_chat(&mut self, chat_id: Ulid) -> bool {
-        if let Some(idx) = self.chats.iter().position(|c| c.ulid == chat_id) {
-            self.chats.remove(idx);
+    /// Pin or unpin a chat, returning its new state
+    pub fn toggle_pinned(&mut self, chat_id: Ulid) -> Option<bool> {
+        let chats = Arc::make_mut(&mut self.chats);
+        let chat = chats.iter_mut().find(|c| c.ulid == chat_id)?;
+        chat.pinned = !chat.pinned;
+        let pinned = chat.pinned;
+        chats.sort_by_key(Self::sort_key);
+        Some(pinned)
+    }
+
+    /// Add a tag to a chat, returning its new tags
+    pub fn add_tag(&mut self, chat_id: Ulid, tag: &str) -> Option<Vec<String>> {
+        let chat = Arc::make_mut(&mut self.chats)
+            .iter_mut()
+            .find(|c| c.ulid == chat_id)?;
+        let tag = tag.trim();
+        if !tag.is_empty() && !chat.tags.iter().any(|t| t == tag) {
+            chat.tags.push(tag.to_string());
+        }
+        Some(chat.tags.clone())
+    }
+
+    /// Remove a tag from a chat, returning its new tags
+    pub fn remove_tag(&mut self, chat_id: Ulid, tag: &str) -> Option<Vec<String>> {
+        let chat = Arc::make_mut(&mut self.chats)
+            .iter_mut()
+            .find(|c| c.ulid == chat_id)?;
+        chat.tags.retain(|t| t != tag);
+        let tags = chat.tags.clone();
+        // the filter would otherwise hide every chat once its last use is gone
+        if self.tag_filter.as_deref() == Some(tag) && !self.all_tags().contains(&tag) {
+            self.tag_filter = None;
+        }
+        Some(tags)
+    }
+
+    /// Every tag used by a chat, sorted
+    pub fn all_tags(&self) -> Vec<&str> {
+        let mut tags = self
+            .chats
+            .iter()
+            .flat_map(|chat| chat.tags.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    }
+
+    pub fn set_title(&mut self, chat_id: Ulid, title: Option<String>) -> bool {
+        if let Some(chat) = Arc::make_mut(&mut self.chats)
+            .iter_mut()
+            .find(|c| c.ulid == chat_id)
+        {
+            chat.title = title;
             true
         } else {
             false
         }
     }
 
-    fn view_element<'a>(chat: &'a SavedChat<String>) -> Element<'a, Message> {
+    pub fn remove_chat(&mut self, chat_id: Ulid) -> Option<SavedChat<String>> {
+        let idx = self.chats.iter().position(|c| c.ulid == chat_id)?;
+        Some(Arc::make_mut(&mut self.chats).remove(idx))
+    }
+
+    fn view_tag(tag: &str, selected: bool) -> button::Button<'_, Message> {
+        button(text(tag).size(11.0))
+            .padding([1.0, 6.0])
+            .style(move |theme, status| {
+                if selected {
+                    button::primary(theme, status)
+                } else {
+                    button::secondary(theme, status)
+                }
+            })
+    }
+
+    fn view_tags<'a>(&'a self, chat: &'a SavedChat<String>) -> Option<Element<'a, Message>> {
+        let tagging = self.tagging.as_ref().filter(|t| t.ulid == chat.ulid);
+        if chat.tags.is_empty() && tagging.is_none() {
+            return None;
+        }
+        let chips = chat.tags.iter().map(|tag| {
+            row![]
+                .push(
+                    Self::view_tag(tag, self.tag_filter.as_ref() == Some(tag))
+                        .on_press(Message::HistoryTagFilter(Some(tag.clone()))),
+                )
+                .push(
+                    button_icon_small(iced_fonts::Bootstrap::X)
+                        .on_press(Message::HistoryTagRemove(chat.ulid, tag.clone()))
+                        .style(button::text)
+                        .padding(1.0),
+                )
+                .align_y(Alignment::Center)
+                .into()
+        });
+        Some(
+            row(chips)
+                .push_maybe(tagging.map(|tagging| {
+                    text_input("New tag", &tagging.tag)
+                        .id(Tagging::input_id())
+                        .on_input(Message::HistoryTagInput)
+                        .on_submit(Message::HistoryTagSubmit)
+                        .size(11.0)
+                        .padding(2)
+                        .width(100.0)
+                }))
+                .spacing(5.0)
+                .align_y(Alignment::Center)
+                .wrap()
+                .into(),
+        )
+    }
+
+    fn view_element<'a>(
+        &'a self,
+        chat: &'a SavedChat<String>,
+        renaming: Option<&'a Renaming>,
+        now: Option<DateTime<Local>>,
+    ) -> Element<'a, Message> {
         let datetime = chat.ulid.datetime();
         let date: DateTime<Local> = datetime.into();
+        let absolute = date.format("%Y-%m-%d %H:%M:%S").to_string();
+        let timestamp: Element<'a, Message> = match now {
+            Some(now) => tooltip(
+                text(relative_time(date, now)),
+                container(text(absolute).size(12.0))
+                    .padding(5)
+                    .style(container::rounded_box),
+                tooltip::Position::Bottom,
+            )
+            .into(),
+            None => text(absolute).into(),
+        };
 
+        let description: Element<'a, Message> = match renaming {
+            Some(renaming) if renaming.ulid == chat.ulid && renaming.in_sidebar => {
+                renaming.view().size(12.0).into()
+            }
+            _ => text(chat.description()).size(12.0).into(),
+        };
         button(
             row![]
                 .push(
                     column![]
-                        .push(text(format!("{}", date.format("%Y-%m-%d %H:%M:%S"))))
-                        .push(text(format!("{}", chat.description())).size(12.0))
+                        .push(timestamp)
+                        .push(description)
+                        .push_maybe(self.view_tags(chat))
                         .spacing(5.0)
                         .width(Length::Fill),
                 )
+                .push(
+                    button_icon(iced_fonts::Bootstrap::Pencil)
+                        .on_press(Message::ChatRename(chat.ulid, true))
+                        .style(iced::widget::button::secondary)
+                        .padding(1.0),
+                )
+                .push(
+                    button_icon(iced_fonts::Bootstrap::Files)
+                        .on_press(Message::HistoryFork(chat.ulid))
+                        .style(iced::widget::button::secondary)
+                        .padding(1.0),
+                )
+                .push(
+                    button_icon(iced_fonts::Bootstrap::Tag)
+                        .on_press(Message::HistoryTagEdit(chat.ulid))
+                        .style(iced::widget::button::secondary)
+                        .padding(1.0),
+                )
+                .push(
+                    button_icon(if chat.pinned {
+                        iced_fonts::Bootstrap::PinFill
+                    } else {
+                        iced_fonts::Bootstrap::Pin
+                    })
+                    .on_press(Message::HistoryPinToggle(chat.ulid))
+                    .style(iced::widget::button::secondary)
+                    .padding(1.0),
+                )
+                .push(
+                    button_icon(iced_fonts::Bootstrap::Download)
+                        .on_press(Message::ExportChat(chat.ulid))
+                        .style(iced::widget::button::secondary)
+                        .padding(1.0),
+                )
                 .push(
                     button_icon(iced_fonts::Bootstrap::Trash)
-                        .on_press(Message::HistoryDelete(chat.ulid.clone()))
+                        .on_press(Message::HistoryDelete(chat.ulid))
                         .padding(1.0),
                 )
                 .spacing(5.0)
                 .align_y(Alignment::Center),
         )
-        .on_press(Message::HistorySelected(chat.ulid.clone()))
-        .style(|theme, status| iced::widget::button::text(theme, status))
+        .on_press(Message::HistorySelected(chat.ulid))
+        .style(iced::widget::button::text)
+        .into()
+    }
+
+    fn view_hit(hit: &SearchHit) -> Element<'_, Message> {
+        button(
+            column![]
+                .push(text(&hit.description))
+                .push(text(&hit.snippet).size(12.0).style(text::secondary))
+                .spacing(5.0),
+        )
+        .on_press(Message::HistorySelected(hit.ulid))
+        .style(iced::widget::button::text)
+        .width(Length::Fill)
         .into()
     }
 
-    pub fn view<'a>(&'a self) -> Container<'a, Message> {
-        let elements = self.chats.iter().map(Self::view_element);
+    fn view_tag_filter(&self) -> Option<Element<'_, Message>> {
+        let tags = self.all_tags();
+        if tags.is_empty() {
+            return None;
+        }
+        let chips = tags.into_iter().map(|tag| {
+            let selected = self.tag_filter.as_deref() == Some(tag);
+            Self::view_tag(tag, selected)
+                .on_press(Message::HistoryTagFilter(
+                    (!selected).then(|| tag.to_string()),
+                ))
+                .into()
+        });
+        Some(
+            row![]
+                .push(text("Tags").size(12.0).style(text::secondary))
+                .extend(chips)
+                .spacing(5.0)
+                .align_y(Alignment::Center)
+                .wrap()
+                .into(),
+        )
+    }
+
+    /// The list of chats, with their creation time relative to now when `relative_time` is set
+    pub fn view<'a>(
+        &'a self,
+        renaming: Option<&'a Renaming>,
+        relative_time: bool,
+    ) -> Container<'a, Message> {
+        let now = relative_time.then(Local::now);
+        // the titles are filtered as the search is typed, the messages once the search ran
+        let query = self.search.to_lowercase();
+        let listed = |chat: &&SavedChat<String>| {
+            self.tag_filter
+                .as_ref()
+                .map_or(true, |tag| chat.tags.contains(tag))
+                && (query.is_empty() || chat.description().to_lowercase().contains(&query))
+        };
+        let (pinned, others): (Vec<_>, Vec<_>) = self
+            .chats
+            .iter()
+            .filter(listed)
+            .partition(|chat| chat.pinned);
+        let hits: Option<Element<'a, Message>> = if self.search.is_empty() {
+            None
+        } else {
+            match &self.search_results {
+                None => Some(text("Searching...").style(text::secondary).into()),
+                Some(hits) => {
+                    let hits = hits
+                        .iter()
+                        .filter(|hit| {
+                            !pinned
+                                .iter()
+                                .chain(&others)
+                                .any(|chat| chat.ulid == hit.ulid)
+                        })
+                        .map(Self::view_hit)
+                        .collect::<Vec<_>>();
+                    match (hits.is_empty(), pinned.is_empty() && others.is_empty()) {
+                        (true, true) => Some(text("No results").style(text::secondary).into()),
+                        (true, false) => None,
+                        (false, _) => Some(column(hits).into()),
+                    }
+                }
+            }
+        };
+        let list = column(
+            pinned
+                .iter()
+                .map(|chat| self.view_element(chat, renaming, now)),
+        )
+        .push_maybe((!pinned.is_empty() && !others.is_empty()).then(|| horizontal_rule(1.0)))
+        .extend(
+            others
+                .iter()
+                .map(|chat| self.view_element(chat, renaming, now)),
+        )
+        .push_maybe(hits);
         container(
             column![]
                 .push(
-                    button_icon_text(
-                        iced_fonts::Bootstrap::ArrowsCollapseVertical,
-                        "Close Sidebar",
-                    )
-                    .on_press(Message::SidebarVisibilityToggle)
-                    .width(Length::Fill),
+                    row![]
+                        .push(
+                            button_icon_text(
+                                iced_fonts::Bootstrap::ArrowsCollapseVertical,
+                                "Close Sidebar",
+                            )
+                            .on_press(Message::SidebarVisibilityToggle)
+                            .width(Length::Fill),
+                        )
+                        .push(
+                            button_icon_text(iced_fonts::Bootstrap::Upload, "Import")
+                                .style(button::secondary)
+                                .on_press(Message::HistoryImport),
+                        )
+                        .spacing(5),
+                )
+                .push_maybe(
+                    self.import_error
+                        .as_ref()
+                        .map(|e| text(e).size(12.0).style(text::danger)),
+                )
+                .push(
+                    text_input("Search in chats...", &self.search)
+                        .on_input(Message::SidebarSearch)
+                        .padding(5),
                 )
-                .push(scrollable(column(elements))),
+                .push_maybe(self.view_tag_filter())
+                .push(scrollable(list))
+                .spacing(5),
         )
         .style(|theme: &Theme| {
             let base = theme.extended_palette().background.base.color;
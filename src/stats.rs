@@ -0,0 +1,97 @@
+use std::{collections::HashMap, time::Duration};
+
+use iced::{
+    widget::{column, container, row, text},
+    Element,
+};
+
+use crate::{
+    history::{Party, SavedChat},
+    Message,
+};
+
+/// Rough token count: one per punctuation sign and one per 4 characters of each word
+pub fn estimate_tokens(s: &str) -> usize {
+    s.split_whitespace()
+        .map(|word| {
+            let punctuation = word.chars().filter(|c| c.is_ascii_punctuation()).count();
+            let letters = word.chars().count() - punctuation;
+            letters.div_ceil(4) + punctuation
+        })
+        .sum()
+}
+
+/// Usage summary over all the saved chats
+pub struct Stats {
+    chats: usize,
+    messages: usize,
+    tokens: usize,
+    most_used_model: Option<(String, usize)>,
+    average_response: Option<Duration>,
+}
+
+impl Stats {
+    pub fn compute(chats: &[SavedChat<String>]) -> Self {
+        let mut messages = 0;
+        let mut tokens = 0;
+        let mut replies_per_model = HashMap::<&str, usize>::new();
+        let mut durations = Vec::new();
+
+        for chat in chats {
+            for party in &chat.content {
+                messages += 1;
+                if let Party::Reply(reply) = party {
+                    tokens += estimate_tokens(&reply.content);
+                    *replies_per_model.entry(chat.model.as_str()).or_default() += 1;
+                    durations.extend(reply.info.duration_ms);
+                }
+            }
+        }
+
+        let most_used_model = replies_per_model
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+            .map(|(model, count)| (model.to_string(), count));
+        let average_response = (!durations.is_empty())
+            .then(|| Duration::from_millis(durations.iter().sum::<u64>() / durations.len() as u64));
+
+        Self {
+            chats: chats.len(),
+            messages,
+            tokens,
+            most_used_model,
+            average_response,
+        }
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        let line = |label, value: String| {
+            row![]
+                .push(container(text(label)).width(220.0))
+                .push(text(value))
+        };
+        column![]
+            .push(line("Chats", self.chats.to_string()))
+            .push(line("Messages", self.messages.to_string()))
+            .push(line(
+                "Tokens generated (estimated)",
+                self.tokens.to_string(),
+            ))
+            .push(line(
+                "Most used model",
+                match &self.most_used_model {
+                    None => "-".to_string(),
+                    Some((model, count)) => format!("{} ({} replies)", model, count),
+                },
+            ))
+            .push(line(
+                "Average response time",
+                match self.average_response {
+                    None => "-".to_string(),
+                    Some(d) => format!("{:.1} seconds", d.as_secs_f32()),
+                },
+            ))
+            .spacing(10)
+            .into()
+    }
+}